@@ -0,0 +1,159 @@
+// Records labeled gesture sessions from the sample stream of a device:
+// `cargo run --bin label_capture -- <output> --subject s01 --mount wrist_left
+//  [--labels vertical,horizontal,diagonal] [--host localhost] [--port 1883]
+//  [--id imu1] [--decimation 1]`
+//
+// Keys 1-9 mark the start of the gesture with that label, space marks its end,
+// and q stops the session. Everything in between is recorded unlabeled. Labels
+// take effect from the next stream frame on.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
+use motion_host::capture::{CaptureWriter, SessionMetadata};
+use motion_host::commands;
+use rumqttc::{Client, Event as MqttEvent, MqttOptions, Packet, QoS};
+
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+const KEY_POLL_PERIOD: Duration = Duration::from_millis(10);
+
+enum Message {
+    Frame(Vec<u8>),
+    Ack(String),
+}
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let output = args.next().ok_or_else(|| anyhow!("Missing the output file"))?;
+    let (mut host, mut port, mut id, mut decimation) = (String::from("localhost"), 1883, String::from("imu1"), 1u16);
+    let (mut subject, mut mount) = (None, None);
+    let mut labels = vec![String::from("vertical"), String::from("horizontal"), String::from("diagonal")];
+    while let Some(flag) = args.next() {
+        let value = args.next().ok_or_else(|| anyhow!("Missing the value of {}", flag))?;
+        match flag.as_str() {
+            "--subject" => subject = Some(value),
+            "--mount" => mount = Some(value),
+            "--labels" => labels = value.split(',').map(String::from).collect(),
+            "--host" => host = value,
+            "--port" => port = value.parse()?,
+            "--id" => id = value,
+            "--decimation" => decimation = value.parse()?,
+            _ => return Err(anyhow!("Unknown option {}", flag)),
+        }
+    }
+    // Labels are single words in the marker lines
+    if labels.is_empty() || labels.len() > 9 || labels.iter().any(|label| label.is_empty() || label.contains(char::is_whitespace)) {
+        return Err(anyhow!("Expected 1 to 9 labels without spaces, got {:?}", labels));
+    }
+    let metadata = SessionMetadata {
+        subject: subject.ok_or_else(|| anyhow!("Missing --subject"))?,
+        device: id.clone(),
+        mount: mount.ok_or_else(|| anyhow!("Missing --mount"))?,
+    };
+
+    let stream_topic = format!("{}/stream", id);
+    let ack_topic = format!("{}/ack", id);
+    let (client, mut connection) = Client::new(MqttOptions::new("label-capture", host, port), 64);
+    client.subscribe(&stream_topic, QoS::AtMostOnce)?;
+    client.subscribe(&ack_topic, QoS::AtMostOnce)?;
+    client.publish(commands::TOPIC, QoS::AtLeastOnce, false, format!("stream fused {}", decimation))?;
+    let (tx, messages) = channel();
+    std::thread::spawn(move || -> Result<()> {
+        for event in connection.iter() {
+            if let MqttEvent::Incoming(Packet::Publish(publish)) = event? {
+                let message = if publish.topic == stream_topic {
+                    Message::Frame(publish.payload.to_vec())
+                } else {
+                    Message::Ack(String::from_utf8_lossy(&publish.payload).into_owned())
+                };
+                if tx.send(message).is_err() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    });
+
+    let mut capture = CaptureWriter::new(BufWriter::new(File::create(&output)?), &format!("{}/stream", id));
+    capture.write_metadata(&metadata)?;
+    for (key, label) in labels.iter().enumerate() {
+        println!("{}: {}", key + 1, label);
+    }
+    println!("space: end of the gesture, q: stop");
+
+    enable_raw_mode()?;
+    let result = record(&mut capture, &labels, &messages);
+    // The terminal is restored whatever happened
+    disable_raw_mode()?;
+    result?;
+
+    client.publish(commands::TOPIC, QoS::AtLeastOnce, false, "stream off")?;
+    let deadline = Instant::now() + ACK_TIMEOUT;
+    loop {
+        match messages.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Message::Ack(ack)) if ack.starts_with("stream off") => break,
+            Ok(_) => (),
+            Err(_) => {
+                eprintln!("No acknowledgement of stream off");
+                break;
+            },
+        }
+    }
+    capture.into_inner().flush()?;
+    Ok(())
+}
+
+// Writes frames under the label chosen by the keys, until q
+fn record(capture: &mut CaptureWriter<BufWriter<File>>, labels: &[String],
+          messages: &Receiver<Message>) -> Result<()> {
+    // Raw mode needs explicit carriage returns
+    let status = |capture: &CaptureWriter<_>, label: Option<&str>| {
+        print!("\r\n{} samples, {} segments, {} frames lost, recording {}",
+               capture.samples, capture.segments, capture.losses.lost, label.unwrap_or("no gesture"));
+        let _ = std::io::stdout().flush();
+    };
+    let mut label: Option<&str> = None;
+    loop {
+        if event::poll(KEY_POLL_PERIOD)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => break,
+                    KeyCode::Char(' ') => label = None,
+                    KeyCode::Char(digit @ '1'..='9') => {
+                        match labels.get(digit as usize - '1' as usize) {
+                            Some(selected) => label = Some(selected),
+                            None => continue,
+                        }
+                    },
+                    _ => continue,
+                }
+                capture.set_label(label);
+                status(capture, label);
+            }
+        }
+        loop {
+            match messages.try_recv() {
+                // A bad frame is no reason to lose the session
+                Ok(Message::Frame(payload)) => {
+                    if let Err(err) = capture.write_frame(&payload) {
+                        print!("\r\n{}", err);
+                    }
+                },
+                Ok(Message::Ack(ack)) => print!("\r\n{}", ack),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Err(anyhow!("Lost the broker connection")),
+            }
+        }
+    }
+    status(capture, label);
+    print!("\r\n");
+    Ok(())
+}
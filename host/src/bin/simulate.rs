@@ -0,0 +1,21 @@
+// Simulated device against a real broker, e.g. a local mosquitto:
+// `cargo run --bin simulate -- [host] [port] [mqtt_id]`
+
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::channel;
+use std::time::Duration;
+
+use anyhow::Result;
+use motion_host::{mqtt, simulator};
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let host = args.next().unwrap_or_else(|| String::from("localhost"));
+    let port = args.next().map_or(Ok(1883), |port| port.parse())?;
+    let id = args.next().unwrap_or_else(|| String::from("imu-sim"));
+
+    let (command_tx, commands) = channel();
+    let sink = mqtt::connect(&host, port, &id, command_tx);
+    println!("Publishing on {}/# at {}:{}, send commands on \"commands\"", id, host, port);
+    simulator::run(Box::new(sink), commands, Duration::from_millis(5), &AtomicBool::new(false))
+}
@@ -0,0 +1,74 @@
+// Records the sample stream of a device into a capture file:
+// `cargo run --bin stream_capture -- <output> [--host localhost] [--port 1883]
+//  [--id imu1] [--content raw|fused] [--decimation 1] [--seconds 10]`
+//
+// Streaming is started with the `stream` command and stopped when done.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use motion_host::capture::CaptureWriter;
+use motion_host::commands;
+use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
+
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let output = args.next().ok_or_else(|| anyhow!("Missing the output file"))?;
+    let (mut host, mut port, mut id) = (String::from("localhost"), 1883, String::from("imu1"));
+    let (mut content, mut decimation, mut seconds) = (String::from("fused"), 1u16, 10);
+    while let Some(flag) = args.next() {
+        let value = args.next().ok_or_else(|| anyhow!("Missing the value of {}", flag))?;
+        match flag.as_str() {
+            "--host" => host = value,
+            "--port" => port = value.parse()?,
+            "--id" => id = value,
+            "--content" => content = value,
+            "--decimation" => decimation = value.parse()?,
+            "--seconds" => seconds = value.parse()?,
+            _ => return Err(anyhow!("Unknown option {}", flag)),
+        }
+    }
+
+    let stream_topic = format!("{}/stream", id);
+    let ack_topic = format!("{}/ack", id);
+    let (client, mut connection) = Client::new(MqttOptions::new("stream-capture", host, port), 64);
+    client.subscribe(&stream_topic, QoS::AtMostOnce)?;
+    client.subscribe(&ack_topic, QoS::AtMostOnce)?;
+    client.publish(commands::TOPIC, QoS::AtLeastOnce, false, format!("stream {} {}", content, decimation))?;
+
+    let mut capture = CaptureWriter::new(BufWriter::new(File::create(&output)?), &stream_topic);
+    let deadline = Instant::now() + Duration::from_secs(seconds);
+    let mut stopping = false;
+    for event in connection.iter() {
+        if let Event::Incoming(Packet::Publish(publish)) = event? {
+            if publish.topic == stream_topic && !stopping {
+                let lost = capture.write_frame(&publish.payload)?;
+                if lost > 0 {
+                    eprintln!("Lost {} frames", lost);
+                }
+            } else if publish.topic == ack_topic {
+                let ack = String::from_utf8_lossy(&publish.payload);
+                println!("{}", ack);
+                if stopping && ack.starts_with("stream off") {
+                    break;
+                }
+            }
+        }
+        // Gives up on the acknowledgement after a while
+        if stopping && Instant::now() >= deadline + ACK_TIMEOUT {
+            break;
+        }
+        if !stopping && Instant::now() >= deadline {
+            client.publish(commands::TOPIC, QoS::AtLeastOnce, false, "stream off")?;
+            stopping = true;
+        }
+    }
+
+    println!("{} samples in {} frames, {} frames lost, saved to {}",
+             capture.samples, capture.losses.received, capture.losses.lost, output);
+    Ok(())
+}
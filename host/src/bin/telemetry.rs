@@ -0,0 +1,68 @@
+// Decodes the telemetry frames of a device running with `event_sink = "telemetry"`
+// and prints one line per record, for the live notebooks and plotting tools:
+// `cargo run --bin telemetry -- [--input /dev/ttyACM0] [--format csv|json]
+//  [--udp 127.0.0.1:9870]`
+//
+// The input is a serial port (set up beforehand, e.g. `stty -F /dev/ttyACM0 raw
+// 115200`) or `-` for stdin. Frames are found wherever the reading starts, so
+// there is no need to reset the board. With `--udp` every line is also sent as
+// a datagram to the given address.
+
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::net::UdpSocket;
+
+use anyhow::{anyhow, Result};
+use motion_host::dataview::{format_line, LineFormat};
+use motion_host::telemetry::FrameDecoder;
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let (mut input, mut format, mut udp) = (String::from("-"), LineFormat::Csv, None);
+    while let Some(flag) = args.next() {
+        let value = args.next().ok_or_else(|| anyhow!("Missing the value of {}", flag))?;
+        match flag.as_str() {
+            "--input" => input = value,
+            "--format" => format = LineFormat::parse(&value).ok_or_else(|| anyhow!("Unknown format {}", value))?,
+            "--udp" => udp = Some(value),
+            _ => return Err(anyhow!("Unknown option {}", flag)),
+        }
+    }
+
+    let reader: Box<dyn Read> = match input.as_str() {
+        "-" => Box::new(std::io::stdin()),
+        path => Box::new(File::open(path)?),
+    };
+    let socket = match udp {
+        Some(address) => {
+            let socket = UdpSocket::bind("0.0.0.0:0")?;
+            socket.connect(address)?;
+            Some(socket)
+        },
+        None => None,
+    };
+
+    let mut output = std::io::stdout().lock();
+    let mut decoder = FrameDecoder::default();
+    let (mut records, mut skipped) = (0u32, 0u32);
+    for byte in BufReader::new(reader).bytes() {
+        match decoder.push(byte?) {
+            Some(Ok(record)) => {
+                let line = format_line(format, &record);
+                if let Some(socket) = &socket {
+                    // Nobody listening is fine, the lines still go to stdout
+                    let _ = socket.send(line.as_bytes());
+                }
+                // Flushed right away, plotting tools read it live
+                writeln!(output, "{}", line)?;
+                output.flush()?;
+                records += 1;
+            },
+            // Log output of the firmware, or a damaged frame
+            Some(Err(_)) => skipped += 1,
+            None => (),
+        }
+    }
+    eprintln!("{} records, {} skipped frames", records, skipped);
+    Ok(())
+}
@@ -0,0 +1,53 @@
+// Trains the gesture classifier on labeled sessions from `label_capture` and
+// writes the model for the firmware:
+// `cargo run --bin train_classifier -- <session>... [--output ../src/gesture_model.rs]
+//  [--window 100] [--stride 25] [--epochs 500] [--rate 0.5]`
+//
+// Captures have to be fused and sampled without decimation, as on the device.
+
+use anyhow::{anyhow, Result};
+use motion_host::capture::read_segments;
+use motion_host::training::{accuracy, rust_source, train, TrainingSet};
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let mut sessions = Vec::new();
+    let mut output = String::from("../src/gesture_model.rs");
+    let (mut window, mut stride, mut epochs, mut rate) = (100u16, 25u16, 500, 0.5);
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            sessions.push(arg);
+            continue;
+        }
+        let value = args.next().ok_or_else(|| anyhow!("Missing the value of {}", arg))?;
+        match arg.as_str() {
+            "--output" => output = value,
+            "--window" => window = value.parse()?,
+            "--stride" => stride = value.parse()?,
+            "--epochs" => epochs = value.parse()?,
+            "--rate" => rate = value.parse()?,
+            _ => return Err(anyhow!("Unknown option {}", arg)),
+        }
+    }
+    if sessions.is_empty() {
+        return Err(anyhow!("Missing the labeled sessions"));
+    }
+
+    let mut segments = Vec::new();
+    for session in &sessions {
+        segments.extend(read_segments(&std::fs::read_to_string(session)?));
+    }
+    let set = TrainingSet::from_segments(&segments, window as usize, stride as usize)?;
+    for (label, count) in set.labels.iter().zip(set.counts()) {
+        println!("{}: {} windows", label, count);
+    }
+
+    let model = train(&set, epochs, rate);
+    let classifier = model.quantize(window, stride);
+    println!("Accuracy on the training windows: {:.3}, {:.3} in fixed point",
+             accuracy(&set, |features| model.predict(features)),
+             accuracy(&set, |features| classifier.classify(features).0));
+    std::fs::write(&output, rust_source(&classifier))?;
+    println!("Model of {} bytes written to {}", classifier.to_blob().len(), output);
+    Ok(())
+}
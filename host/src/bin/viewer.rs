@@ -0,0 +1,101 @@
+// Terminal view of a device running with `event_sink = "telemetry"`:
+// `cargo run --bin viewer -- [--input /dev/ttyACM0] [--replay dump.bin]
+//  [--threshold 1.5] [--history 1000]`
+//
+// The input is a serial port (set up as for the `telemetry` tool) or `-` for
+// stdin. `--replay` plays back a saved dump of the serial output, e.g. from
+// `cat /dev/ttyACM0 > dump.bin`, at the rate it was sampled. The threshold
+// drawn against the movement detection defaults to the firmware's. q or Esc
+// quits.
+
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::sync::mpsc::{channel, Sender, TryRecvError};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::ExecutableCommand;
+use motion_host::analysis::Analysis;
+use motion_host::telemetry::{FrameDecoder, Record, TelemetryError};
+use motion_host::viewer::ViewerState;
+use ratatui::backend::CrosstermBackend;
+use ratatui::Terminal;
+
+const REFRESH_PERIOD: Duration = Duration::from_millis(50);
+
+// Decodes the input into `tx`, pacing samples by their delta when replaying
+fn read_input(reader: Box<dyn Read + Send>, replay: bool, tx: Sender<Result<Record, TelemetryError>>) -> Result<()> {
+    let mut decoder = FrameDecoder::default();
+    for byte in BufReader::new(reader).bytes() {
+        if let Some(result) = decoder.push(byte?) {
+            if let (true, Ok(Record::Sample { delta, .. })) = (replay, &result) {
+                std::thread::sleep(Duration::from_secs_f32(delta.clamp(0.0, 1.0)));
+            }
+            if tx.send(result).is_err() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, state: &mut ViewerState,
+       records: &std::sync::mpsc::Receiver<Result<Record, TelemetryError>>) -> Result<()> {
+    loop {
+        loop {
+            match records.try_recv() {
+                Ok(Ok(record)) => state.apply(&record),
+                Ok(Err(_)) => state.skipped += 1,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    state.ended = true;
+                    break;
+                },
+            }
+        }
+        terminal.draw(|frame| state.render(frame))?;
+        if event::poll(REFRESH_PERIOD)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let (mut input, mut replay) = (String::from("-"), false);
+    let (mut threshold, mut history) = (Analysis::default().acceleration_threshold(), 1000);
+    while let Some(flag) = args.next() {
+        let value = args.next().ok_or_else(|| anyhow!("Missing the value of {}", flag))?;
+        match flag.as_str() {
+            "--input" => input = value,
+            "--replay" => (input, replay) = (value, true),
+            "--threshold" => threshold = value.parse()?,
+            "--history" => history = value.parse()?,
+            _ => return Err(anyhow!("Unknown option {}", flag)),
+        }
+    }
+
+    let reader: Box<dyn Read + Send> = match input.as_str() {
+        "-" => Box::new(std::io::stdin()),
+        path => Box::new(File::open(path)?),
+    };
+    let (tx, records) = channel();
+    std::thread::spawn(move || read_input(reader, replay, tx));
+
+    let mut state = ViewerState::new(history, threshold);
+    enable_raw_mode()?;
+    std::io::stdout().execute(EnterAlternateScreen)?;
+    let result = Terminal::new(CrosstermBackend::new(std::io::stdout()))
+        .map_err(anyhow::Error::from)
+        .and_then(|mut terminal| run(&mut terminal, &mut state, &records));
+    // The terminal is restored whatever happened
+    disable_raw_mode()?;
+    std::io::stdout().execute(LeaveAlternateScreen)?;
+    result
+}
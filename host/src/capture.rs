@@ -0,0 +1,206 @@
+// Writes streamed samples in the capture format the notebooks read: console
+// lines until the one with the `WHO_AM_I: 0x` marker, then one comma-separated
+// sample per line. Labeled sessions are a run of such segments, each marker
+// line naming the gesture of the samples after it, and the session metadata
+// as `# key: value` lines before the first marker.
+
+use std::io::Write;
+
+use anyhow::{anyhow, Result};
+
+use crate::stream::{decode, LossTracker, StreamContent};
+
+const RAW_COLUMNS: &str = "a_x,a_y,a_z,g_x,g_y,g_z";
+const FUSED_COLUMNS: &str = "a_x,a_y,a_z,g_x,g_y,g_z,roll,pitch,yaw,lin_x,lin_y,lin_z";
+
+const MARKER: &str = ", WHO_AM_I: 0x";
+
+// Who and what a labeled session was recorded with
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionMetadata {
+    pub subject: String,
+    pub device: String,
+    // Where and how the device was worn, e.g. `wrist_left`
+    pub mount: String,
+}
+
+impl SessionMetadata {
+    // Reads the metadata lines before the first segment
+    pub fn read(text: &str) -> Self {
+        let mut metadata = SessionMetadata::default();
+        for line in text.lines().take_while(|line| !line.contains(MARKER)) {
+            match line.strip_prefix("# ").and_then(|line| line.split_once(": ")) {
+                Some(("subject", value)) => metadata.subject = String::from(value),
+                Some(("device", value)) => metadata.device = String::from(value),
+                Some(("mount", value)) => metadata.mount = String::from(value),
+                _ => (),
+            }
+        }
+        metadata
+    }
+}
+
+pub struct CaptureWriter<W: Write> {
+    writer: W,
+    source: String,
+    header: bool,
+    label: Option<String>,
+    pub losses: LossTracker,
+    pub samples: u32,
+    pub segments: u32,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    pub fn new(writer: W, source: &str) -> Self {
+        Self {
+            writer,
+            source: String::from(source),
+            header: false,
+            label: None,
+            losses: LossTracker::default(),
+            samples: 0,
+            segments: 0,
+        }
+    }
+
+    // Goes before any frame
+    pub fn write_metadata(&mut self, metadata: &SessionMetadata) -> Result<()> {
+        writeln!(self.writer, "# subject: {}", metadata.subject)?;
+        writeln!(self.writer, "# device: {}", metadata.device)?;
+        writeln!(self.writer, "# mount: {}", metadata.mount)?;
+        Ok(())
+    }
+
+    // The next frame starts a new segment if the label changes, `None` for
+    // samples that are no gesture in particular
+    pub fn set_label(&mut self, label: Option<&str>) {
+        if self.label.as_deref() != label {
+            self.label = label.map(String::from);
+            self.header = false;
+        }
+    }
+
+    // Appends the samples of a stream frame, returning how many frames went
+    // missing before it
+    pub fn write_frame(&mut self, payload: &[u8]) -> Result<u16> {
+        let frame = decode(payload).map_err(|err| anyhow!("Invalid frame: {:?}", err))?;
+        if !self.header {
+            // The samples carry no chip ID, the marker is all the notebooks need
+            let columns = match frame.content {
+                StreamContent::Raw => RAW_COLUMNS,
+                StreamContent::Fused => FUSED_COLUMNS,
+            };
+            match &self.label {
+                Some(label) => write!(self.writer, "# {} label {} from sample {}", self.source, label, self.samples)?,
+                None => write!(self.writer, "# {}", self.source)?,
+            }
+            writeln!(self.writer, " decimation {}: {}{}", frame.decimation, columns, MARKER)?;
+            self.header = true;
+            self.segments += 1;
+        }
+        let lost = self.losses.track(frame.sequence);
+
+        for sample in frame.samples.iter() {
+            let mut fields = vec![sample.accel, sample.gyro];
+            if frame.content == StreamContent::Fused {
+                fields.extend([sample.euler, sample.linear_accel]);
+            }
+            let line: Vec<String> = fields.iter().flatten().map(|value| value.to_string()).collect();
+            writeln!(self.writer, "{}", line.join(","))?;
+        }
+        self.samples += frame.samples.len() as u32;
+        Ok(lost)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+// Samples after a marker line, until the next line that is not a sample
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub label: Option<String>,
+    pub columns: Vec<String>,
+    pub samples: Vec<Vec<f32>>,
+}
+
+// Reads back the segments of a capture, the way the notebooks parse it
+pub fn read_segments(text: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut current: Option<Segment> = None;
+    for line in text.lines() {
+        if let Some(segment) = current.as_mut() {
+            let sample: Result<Vec<f32>, _> = line.split(',').map(str::parse).collect();
+            match sample {
+                Ok(sample) if sample.len() == segment.columns.len() => {
+                    segment.samples.push(sample);
+                    continue;
+                },
+                _ => segments.extend(current.take()),
+            }
+        }
+        if let Some(header) = line.strip_suffix(MARKER) {
+            let (description, columns) = header.rsplit_once(": ").unwrap_or((header, ""));
+            let label = description.split_whitespace().skip_while(|word| *word != "label").nth(1).map(String::from);
+            let columns = columns.split(',').map(String::from).collect();
+            current = Some(Segment { label, columns, samples: Vec::new() });
+        }
+    }
+    segments.extend(current);
+    segments
+}
+
+#[test]
+fn test_capture_from_frames() {
+    use crate::stream::{StreamConfig, StreamEncoder, StreamSample, SAMPLES_PER_FRAME};
+
+    let mut encoder = StreamEncoder::new(StreamConfig { content: StreamContent::Raw, decimation: 1 });
+    let sample = StreamSample { accel: [0.0, 0.5, -1.0], gyro: [1.5, 0.0, -250.0], ..Default::default() };
+    let frames: Vec<Vec<u8>> = (0..3 * SAMPLES_PER_FRAME).filter_map(|_| encoder.push(&sample)).collect();
+
+    let mut capture = CaptureWriter::new(Vec::new(), "imu1");
+    assert_eq!(capture.write_frame(&frames[0]).unwrap(), 0);
+    // The second frame was lost on the way
+    assert_eq!(capture.write_frame(&frames[2]).unwrap(), 1);
+    assert!(capture.write_frame(&frames[1][1..]).is_err());
+    assert_eq!((capture.samples, capture.losses.lost), (2 * SAMPLES_PER_FRAME as u32, 1));
+
+    let text = String::from_utf8(capture.into_inner()).unwrap();
+    let mut lines = text.lines();
+    assert_eq!(lines.next().unwrap(), "# imu1 decimation 1: a_x,a_y,a_z,g_x,g_y,g_z, WHO_AM_I: 0x");
+    assert_eq!(lines.next().unwrap(), "0,0.5,-1,1.5,0,-250");
+    assert_eq!(lines.count(), 2 * SAMPLES_PER_FRAME - 1);
+}
+
+#[test]
+fn test_labeled_session() {
+    use crate::stream::{StreamConfig, StreamEncoder, StreamSample, SAMPLES_PER_FRAME};
+
+    let mut encoder = StreamEncoder::new(StreamConfig { content: StreamContent::Fused, decimation: 1 });
+    let sample = StreamSample { accel: [0.0, 0.0, 1.0], euler: [0.0, 0.0, 90.0], ..Default::default() };
+    let frames: Vec<Vec<u8>> = (0..4 * SAMPLES_PER_FRAME).filter_map(|_| encoder.push(&sample)).collect();
+
+    let mut capture = CaptureWriter::new(Vec::new(), "imu1/stream");
+    let metadata = SessionMetadata { subject: String::from("s01"), device: String::from("imu1"), mount: String::from("wrist_left") };
+    capture.write_metadata(&metadata).unwrap();
+    capture.write_frame(&frames[0]).unwrap();
+    capture.set_label(Some("vertical"));
+    capture.write_frame(&frames[1]).unwrap();
+    capture.set_label(Some("vertical"));
+    capture.write_frame(&frames[2]).unwrap();
+    capture.set_label(None);
+    capture.write_frame(&frames[3]).unwrap();
+    assert_eq!(capture.segments, 3);
+
+    let text = String::from_utf8(capture.into_inner()).unwrap();
+    assert!(text.contains(&format!("# imu1/stream label vertical from sample {} decimation 1: a_x,", SAMPLES_PER_FRAME)));
+    assert_eq!(SessionMetadata::read(&text), metadata);
+    let segments = read_segments(&text);
+    let labels: Vec<Option<&str>> = segments.iter().map(|segment| segment.label.as_deref()).collect();
+    assert_eq!(labels, [None, Some("vertical"), None]);
+    let lengths: Vec<usize> = segments.iter().map(|segment| segment.samples.len()).collect();
+    assert_eq!(lengths, [SAMPLES_PER_FRAME, 2 * SAMPLES_PER_FRAME, SAMPLES_PER_FRAME]);
+    assert_eq!(segments[1].columns.len(), 12);
+    assert_eq!(segments[1].samples[0][8], 90.0);
+}
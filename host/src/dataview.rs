@@ -0,0 +1,132 @@
+// Formats telemetry records as text lines for plotting tools. Records of each
+// kind have their own columns, the first one names the kind:
+//   sample,sequence,delta,a_x,a_y,a_z,g_x,g_y,g_z,roll,pitch,yaw,lin_x,lin_y,lin_z
+//   analysis,sequence,horizontal,vertical,activity,direction
+//   message,topic,payload
+
+use core::fmt::Write;
+
+use crate::telemetry::Record;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineFormat {
+    Csv,
+    Json,
+}
+
+impl LineFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "csv" => Some(LineFormat::Csv),
+            "json" => Some(LineFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+fn direction_name(record: &Record) -> String {
+    match record {
+        Record::Analysis { direction: Some(direction), .. } => format!("{:?}", direction),
+        _ => String::new(),
+    }
+}
+
+pub fn csv_line(record: &Record) -> String {
+    match record {
+        Record::Sample { sequence, delta, accel, gyro, euler, linear_accel } => {
+            let mut line = format!("sample,{},{}", sequence, delta);
+            for value in [accel, gyro, euler, linear_accel].into_iter().flatten() {
+                let _ = write!(line, ",{}", value);
+            }
+            line
+        },
+        Record::Analysis { sequence, horizontal, vertical, activity, .. } =>
+            format!("analysis,{},{},{},{},{}", sequence, horizontal, vertical, activity, direction_name(record)),
+        // Escaped as on the serial sink, commas would only break the columns
+        Record::Message { topic, payload } =>
+            format!("message,{},{}", topic, payload.escape_ascii().to_string().replace(',', ";")),
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            },
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// NaN and infinities are not JSON
+fn json_number(value: f32) -> String {
+    if value.is_finite() { value.to_string() } else { String::from("null") }
+}
+
+fn json_vector(vector: &[f32; 3]) -> String {
+    format!("[{},{},{}]", json_number(vector[0]), json_number(vector[1]), json_number(vector[2]))
+}
+
+pub fn json_line(record: &Record) -> String {
+    match record {
+        Record::Sample { sequence, delta, accel, gyro, euler, linear_accel } => format!(
+            "{{\"type\":\"sample\",\"sequence\":{},\"delta\":{},\"accel\":{},\"gyro\":{},\"euler\":{},\"linear_accel\":{}}}",
+            sequence, json_number(*delta), json_vector(accel), json_vector(gyro), json_vector(euler),
+            json_vector(linear_accel)),
+        Record::Analysis { sequence, horizontal, vertical, activity, direction } => format!(
+            "{{\"type\":\"analysis\",\"sequence\":{},\"horizontal\":{},\"vertical\":{},\"activity\":{},\"direction\":{}}}",
+            sequence, json_number(*horizontal), json_number(*vertical), json_number(*activity),
+            direction.map_or(String::from("null"), |_| json_string(&direction_name(record)))),
+        Record::Message { topic, payload } => format!(
+            "{{\"type\":\"message\",\"topic\":{},\"payload\":{}}}",
+            json_string(topic), json_string(&String::from_utf8_lossy(payload))),
+    }
+}
+
+pub fn format_line(format: LineFormat, record: &Record) -> String {
+    match format {
+        LineFormat::Csv => csv_line(record),
+        LineFormat::Json => json_line(record),
+    }
+}
+
+#[test]
+fn test_record_lines() {
+    use crate::analysis::MovementDirection;
+
+    let sample = Record::Sample {
+        sequence: 3,
+        delta: 0.005,
+        accel: [0.0, 0.0, 1.0],
+        gyro: [0.5, 0.0, 0.0],
+        euler: [0.0, 0.0, 90.0],
+        linear_accel: [0.0, 0.0, f32::NAN],
+    };
+    assert_eq!(csv_line(&sample), "sample,3,0.005,0,0,1,0.5,0,0,0,0,90,0,0,NaN");
+    assert_eq!(json_line(&sample), "{\"type\":\"sample\",\"sequence\":3,\"delta\":0.005,\"accel\":[0,0,1],\
+                                    \"gyro\":[0.5,0,0],\"euler\":[0,0,90],\"linear_accel\":[0,0,null]}");
+
+    let analysis = Record::Analysis {
+        sequence: 3,
+        horizontal: 0.25,
+        vertical: 2.0,
+        activity: 1.5,
+        direction: Some(MovementDirection::Vertical),
+    };
+    assert_eq!(csv_line(&analysis), "analysis,3,0.25,2,1.5,Vertical");
+    assert_eq!(json_line(&analysis), "{\"type\":\"analysis\",\"sequence\":3,\"horizontal\":0.25,\"vertical\":2,\
+                                      \"activity\":1.5,\"direction\":\"Vertical\"}");
+    let idle = Record::Analysis { sequence: 4, horizontal: 0.0, vertical: 0.0, activity: 0.0, direction: None };
+    assert_eq!(csv_line(&idle), "analysis,4,0,0,0,");
+    assert!(json_line(&idle).ends_with("\"direction\":null}"));
+
+    let message = Record::Message { topic: String::from("fault"), payload: b"timeout, \"recovered\"\n".to_vec() };
+    assert_eq!(csv_line(&message), "message,fault,timeout; \\\"recovered\\\"\\n");
+    assert_eq!(json_line(&message), "{\"type\":\"message\",\"topic\":\"fault\",\"payload\":\"timeout, \\\"recovered\\\"\\u000a\"}");
+}
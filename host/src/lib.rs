@@ -0,0 +1,49 @@
+// The firmware modules below do not depend on esp-idf, so they are compiled
+// as-is from the firmware sources and re-exported at the crate root, where the
+// firmware expects them.
+
+pub use firmware::*;
+
+#[path = "../../src"]
+mod firmware {
+    pub mod activity;
+    pub mod analysis;
+    pub mod combos;
+    pub mod commands;
+    pub mod connection;
+    pub mod event_sink;
+    #[cfg(test)]
+    pub mod fake_sensor;
+    pub mod gesture_classifier;
+    pub mod gesture_features;
+    pub mod gesture_model;
+    pub mod gesture_templates;
+    pub mod imu_tracker;
+    #[cfg(test)]
+    pub mod mock_bus;
+    pub mod mounting;
+    pub mod mpu_fifo;
+    pub mod outbox;
+    pub mod pipeline;
+    pub mod pose;
+    pub mod rotation;
+    pub mod sample_timing;
+    pub mod sample_trigger;
+    pub mod sensor;
+    pub mod sensor_health;
+    pub mod sensor_recovery;
+    pub mod spi_registers;
+    pub mod stream;
+    pub mod state_machine;
+    pub mod tap;
+    pub mod telemetry;
+}
+
+// Host-only pieces: the simulated device, and tools for the device topics and
+// the serial telemetry
+pub mod capture;
+pub mod dataview;
+pub mod mqtt;
+pub mod simulator;
+pub mod training;
+pub mod viewer;
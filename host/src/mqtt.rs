@@ -0,0 +1,82 @@
+// Host counterpart of the firmware `MqttSink`, on top of rumqttc
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
+
+use crate::commands;
+use crate::event_sink::{topic, EventSink};
+use crate::outbox::Outbound;
+
+// Delay between reconnection attempts, as `reconnect_timeout` on the device
+const RECONNECT_DELAY: Duration = Duration::from_millis(200);
+
+pub struct MqttSink {
+    client: Client,
+    prefix: String,
+    connected: Arc<AtomicBool>,
+    closed: Arc<AtomicBool>,
+}
+
+impl EventSink for MqttSink {
+    fn is_ready(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    fn send(&mut self, message: &Outbound) -> Result<()> {
+        self.client.publish(topic(&self.prefix, message), QoS::AtLeastOnce, false, message.payload())?;
+        Ok(())
+    }
+}
+
+impl Drop for MqttSink {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+        let _ = self.client.disconnect();
+    }
+}
+
+// Connects as `id`, and keeps reconnecting in the background like the device
+// does. Payloads received on the command topic are forwarded to `commands`.
+pub fn connect(host: &str, port: u16, id: &str, commands: Sender<Vec<u8>>) -> MqttSink {
+    let mut options = MqttOptions::new(id, host, port);
+    options.set_keep_alive(Duration::from_secs(5));
+    let (client, mut connection) = Client::new(options, 64);
+
+    let connected = Arc::new(AtomicBool::new(false));
+    let closed = Arc::new(AtomicBool::new(false));
+    let subscriber = client.clone();
+    let (status, done) = (connected.clone(), closed.clone());
+    std::thread::spawn(move || {
+        for event in connection.iter() {
+            match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    // Subscriptions do not survive a clean session
+                    if subscriber.subscribe(commands::TOPIC, QoS::AtLeastOnce).is_err() {
+                        break;
+                    }
+                    status.store(true, Ordering::Relaxed);
+                },
+                Ok(Event::Incoming(Packet::Publish(publish))) if publish.topic == commands::TOPIC => {
+                    if commands.send(publish.payload.to_vec()).is_err() {
+                        break;
+                    }
+                },
+                Ok(_) => {},
+                Err(_) => {
+                    status.store(false, Ordering::Relaxed);
+                    if done.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    std::thread::sleep(RECONNECT_DELAY);
+                },
+            }
+        }
+    });
+
+    MqttSink { client, prefix: String::from(id), connected, closed }
+}
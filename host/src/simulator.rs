@@ -0,0 +1,153 @@
+// Runs the firmware pipeline on the host, with a simulated sensor standing in
+// for the IMU and any event sink for the output
+
+use core::f32::consts::PI;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use embedded_hal::blocking::delay::DelayMs;
+use imu_fusion::{FusionMatrix, FusionVector};
+
+use crate::commands;
+use crate::event_sink::EventSink;
+use crate::imu_tracker::ImuTracker;
+use crate::outbox::{Outbound, Outbox, OverflowPolicy};
+use crate::pipeline::Pipeline;
+use crate::sensor::{ImuSample, ImuSensor, SensorConfig, SensorError};
+use crate::sensor_health::HealthMonitor;
+use crate::sensor_recovery::{RecoveryPolicy, SensorSupervisor};
+use crate::state_machine::SensorFSM;
+
+// Vertical oscillation of the simulated device, strong enough to be reported
+const MOTION_FREQUENCY: f32 = 2.0;
+const MOTION_AMPLITUDE: f32 = 0.5;
+
+const OUTBOX_CAPACITY: usize = 64;
+const FLUSH_INTERVAL: Duration = Duration::from_millis(20);
+
+// Level device moving up and down, sampled every `period`
+pub struct SimulatedSensor {
+    config: SensorConfig,
+    period: Duration,
+    samples: u32,
+}
+
+impl SimulatedSensor {
+    pub fn new(period: Duration) -> Self {
+        Self { config: SensorConfig::default(), period, samples: 0 }
+    }
+}
+
+impl ImuSensor for SimulatedSensor {
+    fn who_am_i(&mut self) -> Result<u8, SensorError> {
+        Ok(0x71)
+    }
+
+    fn read(&mut self) -> Result<ImuSample, SensorError> {
+        let t = self.samples as f32 * self.period.as_secs_f32();
+        self.samples += 1;
+        let lift = MOTION_AMPLITUDE * (2.0 * PI * MOTION_FREQUENCY * t).sin();
+        Ok(ImuSample {
+            accel: FusionVector::new(0.0, 0.0, 1.0 + lift),
+            gyro: FusionVector::zero(),
+        })
+    }
+
+    fn enable_data_ready(&mut self) -> Result<(), SensorError> {
+        Ok(())
+    }
+
+    fn config(&self) -> &SensorConfig {
+        &self.config
+    }
+
+    fn sample_period(&self) -> Duration {
+        self.period
+    }
+
+    fn reinit(&mut self, _delay: &mut dyn DelayMs<u8>) -> Result<(), SensorError> {
+        Ok(())
+    }
+}
+
+struct SleepDelay;
+
+impl DelayMs<u8> for SleepDelay {
+    fn delay_ms(&mut self, ms: u8) {
+        std::thread::sleep(Duration::from_millis(ms as u64));
+    }
+}
+
+// Samples the simulated sensor every `period` until `stop` is set, answering
+// the commands received meanwhile. Output goes through an outbox into `sink`,
+// as on the device.
+pub fn run(mut sink: Box<dyn EventSink + Send>, commands: Receiver<Vec<u8>>, period: Duration, stop: &AtomicBool)
+    -> Result<()>
+{
+    let (tx, rx) = channel::<Outbound>();
+    let sender = std::thread::spawn(move || -> Result<()> {
+        let mut outbox = Outbox::new(OUTBOX_CAPACITY, OverflowPolicy::DropOldest);
+        loop {
+            match rx.recv_timeout(FLUSH_INTERVAL) {
+                Ok(message) => outbox.push(message),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            outbox.flush(sink.as_mut())?;
+        }
+        // Whatever is left goes out if the sink allows it
+        outbox.flush(sink.as_mut())
+    });
+
+    let mut sensor = SimulatedSensor::new(period);
+    let tracker = ImuTracker::new(period, 2000.0, FusionMatrix::identity(), FusionVector::zero(),
+                                  FusionVector::ones(), FusionVector::zero());
+    let health = HealthMonitor::new(sensor.config());
+    let mut pipeline = Pipeline::new(tracker, health, period, tx.clone());
+
+    let mut fsm = SensorFSM::new();
+    fsm.bootup_complete().map_err(|err| anyhow!("IMUError: {:?}", err))?;
+    fsm.peripherals_complete().map_err(|err| anyhow!("IMUError: {:?}", err))?;
+    fsm.calibration_complete().map_err(|err| anyhow!("IMUError: {:?}", err))?;
+    let mut supervisor = SensorSupervisor::new(fsm, RecoveryPolicy::default());
+
+    while !stop.load(Ordering::Relaxed) {
+        std::thread::sleep(period);
+        for payload in commands.try_iter() {
+            tx.send(Outbound::Ack(commands::execute(&payload, &mut supervisor, &mut pipeline).to_string()))?;
+        }
+        let sample = supervisor.sample(&mut sensor, &mut SleepDelay).map_err(|err| anyhow!("IMUError: {:?}", err))?;
+        for event in supervisor.take_events() {
+            tx.send(Outbound::Fault(event.to_string()))?;
+        }
+        if let Some(sample) = sample {
+            pipeline.process(period.as_secs_f32(), &sample)?;
+        }
+    }
+
+    drop((tx, pipeline));
+    sender.join().map_err(|_| anyhow!("Sender thread panicked"))?
+}
+
+#[test]
+fn test_simulated_motion_is_reported() {
+    let (tx, rx) = channel::<Outbound>();
+    let period = Duration::from_millis(5);
+    let mut sensor = SimulatedSensor::new(period);
+    let tracker = ImuTracker::new(period, 2000.0, FusionMatrix::identity(), FusionVector::zero(),
+                                  FusionVector::ones(), FusionVector::zero());
+    let mut pipeline = Pipeline::new(tracker, HealthMonitor::new(sensor.config()), period, tx);
+
+    // Two seconds of movement
+    for _ in 0..400 {
+        let sample = sensor.read().unwrap();
+        pipeline.process(period.as_secs_f32(), &sample).unwrap();
+    }
+    drop(pipeline);
+    let events: Vec<Outbound> = rx.iter().filter(|message| message.topic() == "event").collect();
+    assert!(!events.is_empty());
+    // Vertical, with some activity
+    assert!(events.iter().all(|event| event.payload().starts_with(b"0 ")), "{:?}", events);
+}
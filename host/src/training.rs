@@ -0,0 +1,238 @@
+// Training of the gesture classifier from labeled captures: windows of the
+// segments become feature vectors as on the device, a multinomial logistic
+// regression is fitted on them by gradient descent, then quantized into the
+// model the firmware builds in
+
+use anyhow::{anyhow, Result};
+
+use crate::capture::Segment;
+use crate::gesture_classifier::{Classifier, FEATURE_LIMIT, NO_GESTURE};
+use crate::gesture_features::{extract, Features, Sample, AXES, FEATURES};
+
+// Capture columns of the classifier samples, see `gesture_features::sample`
+const SAMPLE_COLUMNS: [&str; AXES] = ["lin_x", "lin_y", "lin_z", "g_x", "g_y", "g_z"];
+
+pub struct TrainingSet {
+    pub labels: Vec<String>,
+    pub examples: Vec<(Features, usize)>,
+}
+
+impl TrainingSet {
+    /* Windows of `window` samples every `stride` samples within each segment.
+     * Shorter segments make one window, padded with rest on both sides.
+     * Unlabeled segments are examples of no gesture.
+     */
+    pub fn from_segments(segments: &[Segment], window: usize, stride: usize) -> Result<Self> {
+        let mut labels: Vec<String> = segments.iter()
+            .map(|segment| segment.label.clone().unwrap_or_else(|| String::from(NO_GESTURE)))
+            .collect();
+        labels.sort();
+        labels.dedup();
+        // No gesture first, as the default
+        if let Some(index) = labels.iter().position(|label| label == NO_GESTURE) {
+            let none = labels.remove(index);
+            labels.insert(0, none);
+        }
+
+        let mut examples = Vec::new();
+        for segment in segments {
+            let columns: Vec<usize> = SAMPLE_COLUMNS.iter()
+                .map(|name| segment.columns.iter().position(|column| column == name))
+                .collect::<Option<_>>()
+                .ok_or_else(|| anyhow!("Segment without {:?}, fused captures are needed", SAMPLE_COLUMNS))?;
+            let samples: Vec<Sample> = segment.samples.iter()
+                .map(|values| core::array::from_fn(|axis| values[columns[axis]]))
+                .collect();
+            if samples.is_empty() {
+                continue;
+            }
+            let label = segment.label.as_deref().unwrap_or(NO_GESTURE);
+            let class = labels.iter().position(|known| known == label).unwrap_or(0);
+            if samples.len() < window {
+                let padding = (window - samples.len()) / 2;
+                let rest = [0.0; AXES];
+                let padded = core::iter::repeat(&rest).take(padding)
+                    .chain(samples.iter())
+                    .chain(core::iter::repeat(&rest).take(window - samples.len() - padding));
+                examples.push((extract(padded), class));
+                continue;
+            }
+            for start in (0..=samples.len() - window).step_by(stride.max(1)) {
+                examples.push((extract(samples[start..start + window].iter()), class));
+            }
+        }
+        if examples.is_empty() {
+            return Err(anyhow!("No samples to train on"));
+        }
+        Ok(Self { labels, examples })
+    }
+
+    // Examples of each label, in the order of the labels
+    pub fn counts(&self) -> Vec<usize> {
+        (0..self.labels.len())
+            .map(|class| self.examples.iter().filter(|(_, label)| *label == class).count())
+            .collect()
+    }
+}
+
+// Floating point version of the classifier, before quantization
+pub struct FloatModel {
+    pub labels: Vec<String>,
+    offsets: Vec<f32>,
+    scales: Vec<f32>,
+    weights: Vec<f32>,
+    biases: Vec<f32>,
+}
+
+impl FloatModel {
+    fn standardize(&self, features: &Features) -> Features {
+        core::array::from_fn(|index| {
+            ((features[index] - self.offsets[index]) * self.scales[index]).clamp(-FEATURE_LIMIT, FEATURE_LIMIT)
+        })
+    }
+
+    fn scores(&self, standard: &Features) -> Vec<f32> {
+        self.weights.chunks(FEATURES).zip(&self.biases)
+            .map(|(row, bias)| bias + row.iter().zip(standard).map(|(weight, value)| weight * value).sum::<f32>())
+            .collect()
+    }
+
+    pub fn predict(&self, features: &Features) -> usize {
+        let scores = self.scores(&self.standardize(features));
+        (0..scores.len()).max_by(|a, b| scores[*a].total_cmp(&scores[*b])).unwrap_or(0)
+    }
+
+    pub fn quantize(&self, window: u16, stride: u16) -> Classifier {
+        Classifier::quantize(self.labels.clone(), window, stride, &self.offsets, &self.scales, &self.weights, &self.biases)
+    }
+}
+
+// Full batch gradient descent of the softmax cross entropy, with L2 decay
+pub fn train(set: &TrainingSet, epochs: usize, learning_rate: f32) -> FloatModel {
+    const DECAY: f32 = 1e-3;
+    let classes = set.labels.len();
+    let count = set.examples.len() as f32;
+    let mut offsets = vec![0.0; FEATURES];
+    let mut scales = vec![0.0; FEATURES];
+    for index in 0..FEATURES {
+        let mean = set.examples.iter().map(|(features, _)| features[index]).sum::<f32>() / count;
+        let variance = set.examples.iter().map(|(features, _)| (features[index] - mean).powi(2)).sum::<f32>() / count;
+        offsets[index] = mean;
+        // Constant features are left out
+        scales[index] = if variance > 1e-12 { 1.0 / variance.sqrt() } else { 0.0 };
+    }
+    let mut model = FloatModel {
+        labels: set.labels.clone(),
+        offsets,
+        scales,
+        weights: vec![0.0; classes * FEATURES],
+        biases: vec![0.0; classes],
+    };
+
+    let standard: Vec<(Features, usize)> = set.examples.iter()
+        .map(|(features, class)| (model.standardize(features), *class))
+        .collect();
+    for _ in 0..epochs {
+        let mut weight_gradient = vec![0.0; classes * FEATURES];
+        let mut bias_gradient = vec![0.0; classes];
+        for (features, class) in &standard {
+            let scores = model.scores(features);
+            let peak = scores.iter().fold(f32::MIN, |peak, score| peak.max(*score));
+            let exps: Vec<f32> = scores.iter().map(|score| (score - peak).exp()).collect();
+            let total: f32 = exps.iter().sum();
+            for (label, exp) in exps.iter().enumerate() {
+                let error = exp / total - if label == *class { 1.0 } else { 0.0 };
+                bias_gradient[label] += error;
+                for (gradient, value) in weight_gradient[label * FEATURES..(label + 1) * FEATURES].iter_mut().zip(features) {
+                    *gradient += error * value;
+                }
+            }
+        }
+        for (weight, gradient) in model.weights.iter_mut().zip(&weight_gradient) {
+            *weight -= learning_rate * (gradient / count + DECAY * *weight);
+        }
+        for (bias, gradient) in model.biases.iter_mut().zip(&bias_gradient) {
+            *bias -= learning_rate * gradient / count;
+        }
+    }
+    model
+}
+
+// Share of the examples labeled right by `predict`
+pub fn accuracy(set: &TrainingSet, predict: impl Fn(&Features) -> usize) -> f32 {
+    let right = set.examples.iter().filter(|(features, class)| predict(features) == *class).count();
+    right as f32 / set.examples.len() as f32
+}
+
+// Contents of `gesture_model.rs` building in the blob of a classifier
+pub fn rust_source(classifier: &Classifier) -> String {
+    let blob = classifier.to_blob();
+    let mut source = String::from("// Gesture classifier built into the firmware, see `gesture_classifier`.\n\
+                                   // Generated by `cargo run --bin train_classifier` in `host`, empty for none.\n");
+    source.push_str(&format!("// Labels: {}, window {} samples every {}\n",
+                             classifier.labels().join(", "), classifier.window, classifier.stride));
+    source.push_str("pub const MODEL: &[u8] = &[\n");
+    for line in blob.chunks(12) {
+        let bytes: Vec<String> = line.iter().map(|byte| format!("0x{:02x}", byte)).collect();
+        source.push_str(&format!("    {},\n", bytes.join(", ")));
+    }
+    source.push_str("];\n");
+    source
+}
+
+#[test]
+fn test_train_and_export() {
+    // Deterministic noise
+    let mut state = 12345u32;
+    let mut noise = move || {
+        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+        ((state >> 16) as f32 / 65536.0 - 0.5) * 0.1
+    };
+    let columns: Vec<String> = ["a_x", "a_y", "a_z", "g_x", "g_y", "g_z", "roll", "pitch", "yaw", "lin_x", "lin_y", "lin_z"]
+        .iter().map(|column| String::from(*column)).collect();
+    let mut segment = |label: Option<&str>, len: usize, lift: f32, twist: f32| {
+        let samples = (0..len).map(|index| {
+            let phase = (index as f32 * 0.3).sin();
+            let mut values: Vec<f32> = (0..12).map(|_| noise()).collect();
+            values[5] = twist * phase + 10.0 * noise();
+            values[11] = lift * phase + noise();
+            values
+        }).collect();
+        Segment { label: label.map(String::from), columns: columns.clone(), samples }
+    };
+    let mut segments = Vec::new();
+    for _ in 0..3 {
+        segments.push(segment(None, 300, 0.0, 0.0));
+        segments.push(segment(Some("vertical"), 200, 0.6, 0.0));
+        segments.push(segment(Some("twist"), 200, 0.0, 120.0));
+    }
+    // Shorter than the window
+    segments.push(segment(Some("twist"), 60, 0.0, 120.0));
+
+    let set = TrainingSet::from_segments(&segments, 100, 25).unwrap();
+    assert_eq!(set.labels, ["none", "twist", "vertical"]);
+    assert_eq!(set.counts(), [3 * 9, 3 * 5 + 1, 3 * 5]);
+    let model = train(&set, 200, 0.5);
+    assert!(accuracy(&set, |features| model.predict(features)) > 0.95);
+
+    // What the device runs gives the same answers as the host model
+    let classifier = model.quantize(100, 25);
+    let loaded = Classifier::from_blob(&classifier.to_blob()).unwrap();
+    assert_eq!(loaded, classifier);
+    let agreement = accuracy(&TrainingSet {
+        labels: set.labels.clone(),
+        examples: set.examples.iter().map(|(features, _)| (*features, model.predict(features))).collect(),
+    }, |features| loaded.classify(features).0);
+    assert!(agreement > 0.98, "Agreement {}", agreement);
+
+    let source = rust_source(&classifier);
+    let bytes: Vec<u8> = source.split(|c: char| c == ',' || c.is_whitespace())
+        .filter_map(|token| token.strip_prefix("0x"))
+        .map(|hex| u8::from_str_radix(hex, 16).unwrap())
+        .collect();
+    assert_eq!(bytes, classifier.to_blob());
+    assert!(source.contains("// Labels: none, twist, vertical, window 100 samples every 25\n"));
+
+    let raw = Segment { label: None, columns: columns[..6].to_vec(), samples: vec![vec![0.0; 6]] };
+    assert!(TrainingSet::from_segments(&[raw], 100, 25).is_err());
+}
@@ -0,0 +1,239 @@
+// Live view of the telemetry records: orientation, linear acceleration, the
+// movement detection figures against their threshold, and the direction the
+// device currently reports
+
+use std::collections::VecDeque;
+
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::symbols::Marker;
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Axis, Block, Borders, Chart, Dataset, GraphType, Paragraph};
+use ratatui::Frame;
+
+use crate::analysis::MovementDirection;
+use crate::telemetry::Record;
+
+// Latest messages shown below the charts
+const MESSAGES: usize = 5;
+
+const AXIS_COLORS: [Color; 3] = [Color::Red, Color::Green, Color::Cyan];
+
+type Trace = VecDeque<(f64, f64)>;
+
+pub struct ViewerState {
+    history: usize,
+    threshold: f32,
+    euler: [Trace; 3],
+    linear_accel: [Trace; 3],
+    // Horizontal and vertical figures of the movement detection, and the
+    // activity intensity
+    indicators: [Trace; 3],
+    direction: Option<MovementDirection>,
+    // Last direction reported, with the sample it was reported on
+    last_direction: Option<(MovementDirection, u32)>,
+    last_sequence: Option<u32>,
+    messages: VecDeque<String>,
+    pub samples: u32,
+    // Samples missing from the sequence, e.g. dropped by the outbox
+    pub lost: u32,
+    // Frames that could not be decoded, mostly log output
+    pub skipped: u32,
+    // Set once the input is exhausted
+    pub ended: bool,
+}
+
+fn push(trace: &mut Trace, history: usize, point: (f64, f64)) {
+    if trace.len() >= history {
+        trace.pop_front();
+    }
+    trace.push_back(point);
+}
+
+impl ViewerState {
+    pub fn new(history: usize, threshold: f32) -> Self {
+        Self {
+            history: history.max(2),
+            threshold,
+            euler: Default::default(),
+            linear_accel: Default::default(),
+            indicators: Default::default(),
+            direction: None,
+            last_direction: None,
+            last_sequence: None,
+            messages: VecDeque::with_capacity(MESSAGES),
+            samples: 0,
+            lost: 0,
+            skipped: 0,
+            ended: false,
+        }
+    }
+
+    pub fn apply(&mut self, record: &Record) {
+        match record {
+            Record::Sample { sequence, euler, linear_accel, .. } => {
+                if let Some(last) = self.last_sequence {
+                    self.lost += sequence.wrapping_sub(last).saturating_sub(1);
+                }
+                self.last_sequence = Some(*sequence);
+                self.samples += 1;
+                let x = *sequence as f64;
+                for (trace, value) in self.euler.iter_mut().zip(euler) {
+                    push(trace, self.history, (x, *value as f64));
+                }
+                for (trace, value) in self.linear_accel.iter_mut().zip(linear_accel) {
+                    push(trace, self.history, (x, *value as f64));
+                }
+            },
+            Record::Analysis { sequence, horizontal, vertical, activity, direction } => {
+                let x = *sequence as f64;
+                for (trace, value) in self.indicators.iter_mut().zip([horizontal, vertical, activity]) {
+                    push(trace, self.history, (x, *value as f64));
+                }
+                self.direction = *direction;
+                if let Some(direction) = direction {
+                    self.last_direction = Some((*direction, *sequence));
+                }
+            },
+            Record::Message { topic, payload } => {
+                if self.messages.len() >= MESSAGES {
+                    self.messages.pop_front();
+                }
+                self.messages.push_back(format!("{} {}", topic, payload.escape_ascii()));
+            },
+        }
+    }
+
+    pub fn direction(&self) -> Option<MovementDirection> {
+        self.direction
+    }
+
+    pub fn render(&self, frame: &mut Frame) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(3),
+                Constraint::Percentage(34),
+                Constraint::Percentage(33),
+                Constraint::Percentage(33),
+                Constraint::Length(MESSAGES as u16 + 2),
+            ])
+            .split(frame.size());
+        frame.render_widget(self.status(), rows[0]);
+        self.render_chart(frame, rows[1], "Euler angles (deg)", &self.euler, &["roll", "pitch", "yaw"], Some([-180.0, 180.0]));
+        self.render_chart(frame, rows[2], "Linear acceleration (m/s²)", &self.linear_accel, &["x", "y", "z"], None);
+        self.render_detection(frame, rows[3]);
+        let messages: Vec<Line> = self.messages.iter().map(|message| Line::from(message.as_str())).collect();
+        frame.render_widget(Paragraph::new(messages).block(Block::default().title("Messages").borders(Borders::ALL)), rows[4]);
+    }
+
+    fn status(&self) -> Paragraph<'static> {
+        let current = match self.direction {
+            Some(direction) => Span::styled(format!("{:?}", direction),
+                                            Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
+            None => Span::raw("none"),
+        };
+        let last = match self.last_direction {
+            Some((direction, sequence)) => format!("{:?} at #{}", direction, sequence),
+            None => String::from("-"),
+        };
+        let mut line = vec![
+            Span::raw("Direction: "),
+            current,
+            Span::raw(format!("  last: {}  samples: {}  lost: {}  skipped frames: {}",
+                              last, self.samples, self.lost, self.skipped)),
+        ];
+        if self.ended {
+            line.push(Span::styled("  input ended", Style::default().fg(Color::Red)));
+        }
+        Paragraph::new(Line::from(line)).block(Block::default().borders(Borders::ALL).title("Device (q to quit)"))
+    }
+
+    // X bounds follow the samples shown, Y bounds are fixed or fit the data
+    fn render_chart(&self, frame: &mut Frame, area: Rect, title: &str, traces: &[Trace], names: &[&str],
+                    y_bounds: Option<[f64; 2]>) {
+        let points: Vec<Vec<(f64, f64)>> = traces.iter().map(|trace| trace.iter().copied().collect()).collect();
+        let datasets = points.iter().zip(names).zip(AXIS_COLORS).map(|((points, name), color)| {
+            Dataset::default()
+                .name(name.to_string())
+                .marker(Marker::Braille)
+                .graph_type(GraphType::Line)
+                .style(Style::default().fg(color))
+                .data(points)
+        }).collect();
+        let y_bounds = y_bounds.unwrap_or_else(|| {
+            let peak = points.iter().flatten().fold(1.0f64, |peak, (_, y)| peak.max(y.abs()));
+            [-peak, peak]
+        });
+        frame.render_widget(self.chart(title, datasets, y_bounds), area);
+    }
+
+    fn render_detection(&self, frame: &mut Frame, area: Rect) {
+        let [horizontal, vertical, activity]: [Vec<(f64, f64)>; 3] =
+            [0, 1, 2].map(|index| self.indicators[index].iter().copied().collect());
+        let [start, end] = self.x_bounds();
+        let threshold = [(start, self.threshold as f64), (end, self.threshold as f64)];
+        let peak = horizontal.iter().chain(&vertical).chain(&activity)
+            .fold(1.5 * self.threshold as f64, |peak, (_, y)| peak.max(*y));
+        let series = [("horizontal", &horizontal[..], AXIS_COLORS[0]), ("vertical", &vertical[..], AXIS_COLORS[2]),
+                      ("activity", &activity[..], Color::Yellow), ("threshold", &threshold[..], Color::Gray)];
+        let datasets = series.into_iter().map(|(name, points, color)| {
+            Dataset::default()
+                .name(name)
+                .marker(Marker::Braille)
+                .graph_type(GraphType::Line)
+                .style(Style::default().fg(color))
+                .data(points)
+        }).collect();
+        frame.render_widget(self.chart("Movement detection (m/s²)", datasets, [0.0, peak]), area);
+    }
+
+    fn x_bounds(&self) -> [f64; 2] {
+        let first = self.euler[0].front().or(self.indicators[0].front()).map_or(0.0, |point| point.0);
+        let last = self.euler[0].back().or(self.indicators[0].back()).map_or(0.0, |point| point.0);
+        [first, last.max(first + 1.0)]
+    }
+
+    fn chart<'a>(&self, title: &str, datasets: Vec<Dataset<'a>>, y_bounds: [f64; 2]) -> Chart<'a> {
+        let label = |value: f64| Span::raw(format!("{:.1}", value));
+        Chart::new(datasets)
+            .block(Block::default().title(title.to_string()).borders(Borders::ALL))
+            .x_axis(Axis::default().bounds(self.x_bounds()))
+            .y_axis(Axis::default()
+                .bounds(y_bounds)
+                .labels(vec![label(y_bounds[0]), label((y_bounds[0] + y_bounds[1]) / 2.0), label(y_bounds[1])]))
+    }
+}
+
+#[test]
+fn test_viewer_state() {
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    let mut state = ViewerState::new(100, 1.5);
+    for sequence in (1..300).filter(|sequence| sequence % 50 != 0) {
+        state.apply(&Record::Sample {
+            sequence,
+            delta: 0.005,
+            accel: [0.0, 0.0, 1.0],
+            gyro: [0.0; 3],
+            euler: [0.0, 0.0, sequence as f32 % 360.0 - 180.0],
+            linear_accel: [0.0, 0.0, 0.5],
+        });
+        let direction = if sequence > 200 { Some(MovementDirection::Vertical) } else { None };
+        state.apply(&Record::Analysis { sequence, horizontal: 0.1, vertical: 2.0, activity: 1.0, direction });
+    }
+    state.apply(&Record::Analysis { sequence: 300, horizontal: 0.1, vertical: 0.2, activity: 0.5, direction: None });
+    state.apply(&Record::Message { topic: String::from("fault"), payload: b"recovered".to_vec() });
+    assert_eq!((state.samples, state.lost), (294, 5));
+    assert_eq!(state.euler[2].len(), 100);
+    assert_eq!(state.direction(), None);
+    assert_eq!(state.last_direction, Some((MovementDirection::Vertical, 299)));
+
+    let mut terminal = Terminal::new(TestBackend::new(120, 40)).unwrap();
+    terminal.draw(|frame| state.render(frame)).unwrap();
+    let text: String = terminal.backend().buffer().content.iter().map(|cell| cell.symbol()).collect();
+    assert!(text.contains("last: Vertical at #299"));
+    assert!(text.contains("fault recovered"));
+    assert!(text.contains("Movement detection"));
+}
@@ -0,0 +1,149 @@
+// Minimal MQTT 3.1.1 broker, just enough for the device and a test client:
+// QoS 0 delivery, `+` and `#` filters, no retained messages nor sessions
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+struct Subscriber {
+    stream: TcpStream,
+    filters: Vec<String>,
+}
+
+pub struct Broker {
+    pub port: u16,
+}
+
+type Subscribers = Arc<Mutex<Vec<Subscriber>>>;
+
+impl Broker {
+    pub fn start() -> Broker {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let subscribers: Subscribers = Arc::new(Mutex::new(Vec::new()));
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let subscribers = subscribers.clone();
+                std::thread::spawn(move || {
+                    let _ = serve(stream, subscribers);
+                });
+            }
+        });
+        Broker { port }
+    }
+}
+
+fn matches(filter: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+    for part in filter.split('/') {
+        match (part, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {},
+            (part, Some(level)) if part == level => {},
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+fn read_remaining_length(stream: &mut TcpStream) -> std::io::Result<usize> {
+    let mut length = 0;
+    for shift in (0..28).step_by(7) {
+        let mut byte = [0u8];
+        stream.read_exact(&mut byte)?;
+        length |= ((byte[0] & 0x7F) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+    Ok(length)
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut length = body.len();
+    loop {
+        let byte = (length % 128) as u8;
+        length /= 128;
+        packet.push(if length > 0 { byte | 0x80 } else { byte });
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn string(body: &[u8]) -> (String, &[u8]) {
+    let len = u16::from_be_bytes([body[0], body[1]]) as usize;
+    (String::from_utf8_lossy(&body[2..2 + len]).into_owned(), &body[2 + len..])
+}
+
+fn serve(mut stream: TcpStream, subscribers: Subscribers) -> std::io::Result<()> {
+    // Forwarded messages are written from other connections, under the lock
+    let reply = |stream: &mut TcpStream, packet: Vec<u8>| {
+        let _guard = subscribers.lock().unwrap();
+        stream.write_all(&packet)
+    };
+    let mut filters = Vec::new();
+    loop {
+        let mut header = [0u8];
+        stream.read_exact(&mut header)?;
+        let mut body = vec![0u8; read_remaining_length(&mut stream)?];
+        stream.read_exact(&mut body)?;
+
+        match header[0] >> 4 {
+            // CONNECT
+            1 => reply(&mut stream, packet(0x20, &[0, 0]))?,
+            // PUBLISH
+            3 => {
+                let qos = (header[0] >> 1) & 0x03;
+                let (topic, rest) = string(&body);
+                let payload = if qos > 0 {
+                    reply(&mut stream, packet(0x40, &rest[..2]))?;
+                    &rest[2..]
+                } else {
+                    rest
+                };
+                let mut forward = (topic.len() as u16).to_be_bytes().to_vec();
+                forward.extend_from_slice(topic.as_bytes());
+                forward.extend_from_slice(payload);
+                let forward = packet(0x30, &forward);
+                let mut subscribers = subscribers.lock().unwrap();
+                subscribers.retain_mut(|subscriber| {
+                    !subscriber.filters.iter().any(|filter| matches(filter, &topic))
+                        || subscriber.stream.write_all(&forward).is_ok()
+                });
+            },
+            // SUBSCRIBE
+            8 => {
+                let (id, mut rest) = body.split_at(2);
+                let mut granted = id.to_vec();
+                while !rest.is_empty() {
+                    let (filter, tail) = string(rest);
+                    filters.push(filter);
+                    granted.push(0);
+                    rest = &tail[1..];
+                }
+                let mut subscribers = subscribers.lock().unwrap();
+                subscribers.retain(|subscriber| subscriber.stream.peer_addr().ok() != stream.peer_addr().ok());
+                subscribers.push(Subscriber { stream: stream.try_clone()?, filters: filters.clone() });
+                stream.write_all(&packet(0x90, &granted))?;
+            },
+            // PINGREQ
+            12 => reply(&mut stream, packet(0xD0, &[]))?,
+            // DISCONNECT
+            14 => return Ok(()),
+            _ => {},
+        }
+    }
+}
+
+#[test]
+fn test_topic_filters() {
+    assert!(matches("imu1/#", "imu1/event"));
+    assert!(matches("+/ack", "imu1/ack"));
+    assert!(matches("commands", "commands"));
+    assert!(!matches("imu1/+", "imu1/event/extra"));
+    assert!(!matches("imu1/event", "imu1"));
+}
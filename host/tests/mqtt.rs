@@ -0,0 +1,118 @@
+// End-to-end run of the device topics: the simulated device publishes through
+// the local broker stand-in, and a test client sends commands and watches the
+// device topics.
+
+mod broker;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use motion_host::stream::{self, StreamContent};
+use motion_host::{mqtt, simulator};
+use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
+
+const DEVICE_ID: &str = "imu1";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Test client seeing everything the device publishes
+struct Observer {
+    client: Client,
+    messages: Receiver<(String, Vec<u8>)>,
+}
+
+impl Observer {
+    fn connect(port: u16) -> Observer {
+        let (client, mut connection) = Client::new(MqttOptions::new("observer", "127.0.0.1", port), 64);
+        client.subscribe(format!("{}/#", DEVICE_ID), QoS::AtMostOnce).unwrap();
+        let (tx, messages) = channel();
+        std::thread::spawn(move || {
+            for event in connection.iter() {
+                match event {
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        if tx.send((publish.topic, publish.payload.to_vec())).is_err() {
+                            break;
+                        }
+                    },
+                    Ok(_) => {},
+                    Err(_) => break,
+                }
+            }
+        });
+        Observer { client, messages }
+    }
+
+    fn command(&self, command: &str) {
+        self.client.publish("commands", QoS::AtLeastOnce, false, command).unwrap();
+    }
+
+    // Waits for a message on `<device>/<topic>` that satisfies `check`,
+    // returning the ones skipped on the way
+    fn wait_for(&self, topic: &str, check: impl Fn(&[u8]) -> bool) -> (Vec<u8>, Vec<(String, Vec<u8>)>) {
+        let topic = format!("{}/{}", DEVICE_ID, topic);
+        let deadline = Instant::now() + TIMEOUT;
+        let mut skipped = Vec::new();
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let message = self.messages.recv_timeout(remaining)
+                .unwrap_or_else(|_| panic!("Nothing on {} within {:?}, got {:?}", topic, TIMEOUT, skipped));
+            if message.0 == topic && check(&message.1) {
+                return (message.1, skipped);
+            }
+            skipped.push(message);
+        }
+    }
+
+    fn expect(&self, topic: &str, payload: &str) -> Vec<(String, Vec<u8>)> {
+        self.wait_for(topic, |received| received == payload.as_bytes()).1
+    }
+}
+
+#[test]
+fn test_events_and_commands() {
+    let broker = broker::Broker::start();
+    let observer = Observer::connect(broker.port);
+
+    let (command_tx, commands) = channel();
+    let sink = mqtt::connect("127.0.0.1", broker.port, DEVICE_ID, command_tx);
+    let stop = Arc::new(AtomicBool::new(false));
+    let running = stop.clone();
+    let device = std::thread::spawn(move || {
+        simulator::run(Box::new(sink), commands, Duration::from_millis(2), &running)
+    });
+
+    // Vertical movement, the device is subscribed to the commands by now
+    observer.wait_for("event", |payload| payload.starts_with(b"0 "));
+
+    observer.command("ping");
+    observer.expect("ack", "ping ok");
+
+    observer.command("pause");
+    observer.expect("ack", "pause ok");
+    observer.command("status");
+    let skipped = observer.expect("ack", "status ok Paused");
+    assert!(skipped.iter().all(|(topic, _)| topic != "imu1/event"), "Events while paused: {:?}", skipped);
+
+    observer.command("resume");
+    observer.expect("ack", "resume ok");
+    observer.wait_for("event", |payload| payload.starts_with(b"0 "));
+
+    // Two full frames in a row, every other sample
+    observer.command("stream raw 2");
+    observer.expect("ack", "stream raw 2 ok");
+    let (first, _) = observer.wait_for("stream", |_| true);
+    let (second, _) = observer.wait_for("stream", |_| true);
+    let (first, second) = (stream::decode(&first).unwrap(), stream::decode(&second).unwrap());
+    assert_eq!((first.content, first.decimation), (StreamContent::Raw, 2));
+    assert_eq!(second.sequence, first.sequence + 1);
+    assert_eq!(second.samples.len(), stream::SAMPLES_PER_FRAME);
+    observer.command("stream off");
+    observer.expect("ack", "stream off ok");
+
+    observer.command("calibrate");
+    observer.expect("ack", "calibrate error unknown_command");
+
+    stop.store(true, Ordering::Relaxed);
+    device.join().unwrap().unwrap();
+}
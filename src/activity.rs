@@ -0,0 +1,164 @@
+use std::collections::VecDeque;
+
+use imu_fusion::FusionVector;
+
+/* Shakes and the activity level, from the linear acceleration once smoothed by
+ * `Analysis`, in m/s².
+ *
+ * A shake is `reversals` reversals of the acceleration within `window`
+ * seconds, counting only samples at `threshold` or beyond: a reversal is one
+ * such sample pointing against the previous one. After a shake, the next one
+ * is only reported `hold` seconds later.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShakeConfig {
+    pub threshold: f32,
+    pub reversals: usize,
+    pub window: f32,
+    pub hold: f32,
+}
+
+impl Default for ShakeConfig {
+    fn default() -> Self {
+        Self { threshold: 8.0, reversals: 4, window: 1.0, hold: 1.0 }
+    }
+}
+
+pub struct ShakeDetector {
+    config: ShakeConfig,
+    // Latest sample beyond the threshold
+    swing: Option<FusionVector>,
+    // Time since each reversal still within the window
+    reversals: VecDeque<f32>,
+    hold: f32,
+}
+
+impl ShakeDetector {
+    pub fn new(config: ShakeConfig) -> Self {
+        Self { config, swing: None, reversals: VecDeque::with_capacity(config.reversals), hold: 0.0 }
+    }
+
+    // Whether the device was just shaken
+    pub fn add_measurement(&mut self, delta: f32, smoothed: FusionVector) -> bool {
+        self.hold = (self.hold - delta).max(0.0);
+        for age in self.reversals.iter_mut() {
+            *age += delta;
+        }
+        while self.reversals.front().is_some_and(|age| *age > self.config.window) {
+            self.reversals.pop_front();
+        }
+
+        // `magnitude` is the squared one
+        if smoothed.magnitude() < self.config.threshold * self.config.threshold {
+            return false;
+        }
+        if let Some(swing) = self.swing.replace(smoothed) {
+            if swing.dot_product(&smoothed) < 0.0 {
+                self.reversals.push_back(0.0);
+            }
+        }
+        if self.reversals.len() < self.config.reversals || self.hold > 0.0 {
+            return false;
+        }
+        self.reversals.clear();
+        self.hold = self.config.hold;
+        true
+    }
+}
+
+/* Activity intensity as vector magnitude counts: the magnitude beyond
+ * `dead_band` summed over epochs of `epoch` seconds, then given per second,
+ * i.e. the mean magnitude over the latest epoch, in m/s².
+ */
+pub struct ActivityMeter {
+    epoch: f32,
+    dead_band: f32,
+    elapsed: f32,
+    counts: f32,
+    intensity: f32,
+}
+
+impl Default for ActivityMeter {
+    fn default() -> Self {
+        Self::new(1.0, 0.2)
+    }
+}
+
+impl ActivityMeter {
+    pub fn new(epoch: f32, dead_band: f32) -> Self {
+        assert!(epoch > 0.0);
+        Self { epoch, dead_band, elapsed: 0.0, counts: 0.0, intensity: 0.0 }
+    }
+
+    // Intensity of the latest complete epoch
+    pub fn intensity(&self) -> f32 {
+        self.intensity
+    }
+
+    // The intensity when an epoch completes
+    pub fn add_measurement(&mut self, delta: f32, smoothed: FusionVector) -> Option<f32> {
+        let magnitude = smoothed.magnitude().sqrt();
+        if magnitude.is_finite() {
+            self.counts += (magnitude - self.dead_band).max(0.0) * delta;
+        }
+        self.elapsed += delta;
+        // Ends on the sample nearest to the epoch, whatever the rounding
+        if self.elapsed + delta / 2.0 < self.epoch {
+            return None;
+        }
+        self.intensity = self.counts / self.elapsed;
+        self.elapsed = 0.0;
+        self.counts = 0.0;
+        Some(self.intensity)
+    }
+}
+
+#[cfg(test)]
+const TEST_DELTA: f32 = 0.005;
+
+// Sinusoidal acceleration along x for `seconds`
+#[cfg(test)]
+fn swinging(amplitude: f32, frequency: f32, seconds: f32) -> Vec<FusionVector> {
+    (0..(seconds / TEST_DELTA) as usize).map(|index| {
+        let phase = 2.0 * core::f32::consts::PI * frequency * index as f32 * TEST_DELTA;
+        FusionVector::new(amplitude * phase.sin(), 0.0, 0.0)
+    }).collect()
+}
+
+#[test]
+fn test_shake() {
+    let mut detector = ShakeDetector::new(ShakeConfig::default());
+    let shakes = |detector: &mut ShakeDetector, samples: &[FusionVector]| -> Vec<usize> {
+        samples.iter().enumerate()
+            .filter(|(_, smoothed)| detector.add_measurement(TEST_DELTA, **smoothed))
+            .map(|(index, _)| index)
+            .collect()
+    };
+    // Waving gently, then swinging once
+    assert!(shakes(&mut detector, &swinging(4.0, 4.0, 2.0)).is_empty());
+    assert!(shakes(&mut detector, &swinging(12.0, 1.0, 1.0)).is_empty());
+    // Too slow for the reversals to add up
+    assert!(shakes(&mut detector, &swinging(12.0, 1.2, 3.0)).is_empty());
+
+    // Shaken at 4 Hz for 2 s: the 4th reversal comes after half a second, then
+    // again after the hold
+    let detected = shakes(&mut detector, &swinging(12.0, 4.0, 2.0));
+    assert_eq!(detected.len(), 2, "{:?}", detected);
+    assert!(detected[0] < 120, "{:?}", detected);
+    assert!(detected[1] - detected[0] >= 200, "{:?}", detected);
+}
+
+#[test]
+fn test_activity_intensity() {
+    let mut meter = ActivityMeter::default();
+    let epochs: Vec<f32> = swinging(0.1, 2.0, 2.0).into_iter()
+        .chain(swinging(3.0, 2.0, 2.0))
+        .filter_map(|smoothed| meter.add_measurement(TEST_DELTA, smoothed))
+        .collect();
+    assert_eq!(epochs.len(), 4);
+    // Noise below the dead band, then the mean of |sin| less the dead band
+    assert_eq!(epochs[..2], [0.0, 0.0]);
+    let expected = 3.0 * 2.0 / core::f32::consts::PI - 0.2;
+    assert!(epochs[2..].iter().all(|intensity| (intensity - expected).abs() < 0.1), "{:?}", epochs);
+    assert_eq!(meter.intensity(), epochs[3]);
+}
@@ -19,6 +19,23 @@ impl MovementDirection {
             MovementDirection::Diagonal => 2,
         }
     }
+
+    pub fn as_name(&self) -> &'static str {
+        match *self {
+            MovementDirection::Vertical => "vertical",
+            MovementDirection::Horizontal => "horizontal",
+            MovementDirection::Diagonal => "diagonal",
+        }
+    }
+
+    pub fn from_payload(payload: u8) -> Option<Self> {
+        match payload {
+            0 => Some(MovementDirection::Vertical),
+            1 => Some(MovementDirection::Horizontal),
+            2 => Some(MovementDirection::Diagonal),
+            _ => None,
+        }
+    }
 }
 
 struct Smoothing {
@@ -49,8 +66,7 @@ impl Smoothing {
         }
 
         let num_elems = (if self.measurements.is_empty() { 1 } else { self.measurements.len() }) as f32;
-        let sm = FusionVector::new(sum_x / num_elems, sum_y / num_elems, sum_z / num_elems);
-        sm
+        FusionVector::new(sum_x / num_elems, sum_y / num_elems, sum_z / num_elems)
     }
 }
 
@@ -60,31 +76,32 @@ struct MovementDetection {
     angle_low_threshold: f32,
     angle_high_threshold: f32,
     prev_direction: Option<MovementDirection>,
+    // Horizontal and vertical figures the direction was last decided on
+    indicators: (f32, f32),
 }
 
 impl MovementDetection {
     fn add_measurement(&mut self, x: f32, y: f32) -> Option<MovementDirection> {
         let (x, y) = self.movement_computation.add_measurement(x, y);
+        self.indicators = (x, y);
         self.next_direction(x, y)
     }
 
     fn next_direction(&mut self, x_accel: f32, y_accel: f32) -> Option<MovementDirection> {
-        let mut next_state = self.prev_direction;
         let below_thres = self.below_acceleration_threshold(x_accel, y_accel);
 
-        if below_thres {
-            next_state = None;
+        let next_state = if below_thres {
+            None
         } else {
             let angle = y_accel.atan2(x_accel);
-            next_state =
-                if self.angle_low_threshold < angle && angle < self.angle_high_threshold {
-                    Some(MovementDirection::Diagonal)
-                } else if angle < self.angle_low_threshold {
-                    Some(MovementDirection::Horizontal)
-                } else {
-                    Some(MovementDirection::Vertical)
-                };
-        }
+            if self.angle_low_threshold < angle && angle < self.angle_high_threshold {
+                Some(MovementDirection::Diagonal)
+            } else if angle < self.angle_low_threshold {
+                Some(MovementDirection::Horizontal)
+            } else {
+                Some(MovementDirection::Vertical)
+            }
+        };
         self.prev_direction = next_state;
         next_state
     }
@@ -94,12 +111,15 @@ impl MovementDetection {
     }
 }
 
+// Alternative to the quantile, see `MovementComputation`
+#[allow(dead_code)]
 struct AverageMovementComputation {
     horizontal_measurements: VecDeque<f32>,
     vertical_measurements: VecDeque<f32>,
     detection_window_size: usize,
 }
 
+#[allow(dead_code)]
 impl AverageMovementComputation {
     fn new(detection_window_size: usize) -> Self {
         Self {
@@ -189,6 +209,7 @@ impl QuantileMovementComputation {
 pub struct Analysis {
     smoothing: Smoothing,
     movement_detection: MovementDetection,
+    smoothed: FusionVector,
 }
 
 impl Default for Analysis {
@@ -222,15 +243,33 @@ impl Analysis {
                 angle_low_threshold,
                 angle_high_threshold,
                 prev_direction: None,
+                indicators: (0.0, 0.0),
             },
+            smoothed: FusionVector::zero(),
         }
     }
 
+    // Horizontal and vertical acceleration figures behind the latest direction
+    pub fn indicators(&self) -> (f32, f32) {
+        self.movement_detection.indicators
+    }
+
+    // Both indicators stay below it while there is no movement
+    pub fn acceleration_threshold(&self) -> f32 {
+        self.movement_detection.acceleration_threshold
+    }
+
+    // Latest linear acceleration less its moving average
+    pub fn smoothed(&self) -> FusionVector {
+        self.smoothed
+    }
+
     pub fn add_measurement(
         &mut self,
         linear_acceleration: FusionVector,
     ) -> Option<MovementDirection> {
         let smoothed = self.smoothing.add_measurement(linear_acceleration);
+        self.smoothed = smoothed;
         let x = (smoothed.x * smoothed.x + smoothed.y * smoothed.y).sqrt(); // compute euclidean norm of x and y component
         let y = smoothed.z.abs();
         assert!(!x.is_nan());
@@ -243,18 +282,7 @@ impl Analysis {
 fn test_simple_quantile_movement_computation() {
     let mut movement_detection = QuantileMovementComputation::new(30);
 
-    for i in 0..100 {
-        let movement = movement_detection.add_measurement(0.0, 0.0);
-        assert_eq!(movement, (0.0, 0.0));
-    }
-}
-
-
-#[test]
-fn test_simple_quantile_movement_computation() {
-    let mut movement_detection = QuantileMovementComputation::new(30);
-
-    for i in 0..100 {
+    for _ in 0..100 {
         let movement = movement_detection.add_measurement(0.0, 0.0);
         assert_eq!(movement, (0.0, 0.0));
     }
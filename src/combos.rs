@@ -0,0 +1,219 @@
+use std::collections::VecDeque;
+
+/* Combos: named sequences of the discrete events of the pipeline, e.g. two
+ * vertical movements then a clockwise twist. Events are named as published,
+ * without their figures: a `MovementDirection` name, `tap` or `double_tap`,
+ * a `RotationGesture` payload, `shake`, or the name of a custom gesture or a
+ * label of the classifier.
+ *
+ * The events of a combo follow each other with no other in between, less than
+ * `max_gap` seconds apart and within `max_duration` from first to last. The
+ * longest combo wins: one that may still grow into a longer one is held back
+ * until the longer one can no longer complete. Events only count towards one
+ * combo.
+ *
+ * Combos are configured as `<name>=<event>,<event>...[/max_gap[/max_duration]]`
+ * separated by `;`, e.g. `nod=vertical,vertical/0.6;unlock=double_tap,twist_cw`.
+ */
+pub const DEFAULT_MAX_GAP: f32 = 1.0;
+pub const DEFAULT_MAX_DURATION: f32 = 3.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Combo {
+    pub name: String,
+    pub events: Vec<String>,
+    pub max_gap: f32,
+    pub max_duration: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ComboError {
+    // The combo has no `=`, no name or no events
+    Malformed(String),
+    BadTiming(String),
+    Duplicate(String),
+}
+
+pub fn parse(spec: &str) -> Result<Vec<Combo>, ComboError> {
+    let mut combos: Vec<Combo> = Vec::new();
+    for definition in spec.split(';').map(str::trim).filter(|definition| !definition.is_empty()) {
+        let malformed = || ComboError::Malformed(String::from(definition));
+        let (name, rest) = definition.split_once('=').ok_or_else(malformed)?;
+        let mut parts = rest.split('/');
+        let events: Vec<String> = parts.next().unwrap_or_default().split(',')
+            .map(str::trim)
+            .filter(|event| !event.is_empty())
+            .map(String::from)
+            .collect();
+        let name = name.trim();
+        if name.is_empty() || events.is_empty() {
+            return Err(malformed());
+        }
+        let mut timing = [DEFAULT_MAX_GAP, DEFAULT_MAX_DURATION];
+        for value in timing.iter_mut() {
+            if let Some(part) = parts.next() {
+                *value = part.trim().parse().ok()
+                    .filter(|value: &f32| *value > 0.0)
+                    .ok_or_else(|| ComboError::BadTiming(String::from(definition)))?;
+            }
+        }
+        if parts.next().is_some() {
+            return Err(malformed());
+        }
+        if combos.iter().any(|combo| combo.name == name) {
+            return Err(ComboError::Duplicate(String::from(name)));
+        }
+        combos.push(Combo { name: String::from(name), events, max_gap: timing[0], max_duration: timing[1] });
+    }
+    Ok(combos)
+}
+
+pub struct ComboMatcher {
+    combos: Vec<Combo>,
+    // Latest events, with the time they happened
+    history: VecDeque<(String, f32)>,
+    now: f32,
+    // Combo matched but held back for a longer one, until the deadline
+    pending: Option<(usize, f32)>,
+}
+
+impl ComboMatcher {
+    pub fn new(combos: Vec<Combo>) -> Self {
+        Self { combos, history: VecDeque::new(), now: 0.0, pending: None }
+    }
+
+    pub fn combos(&self) -> &[Combo] {
+        &self.combos
+    }
+
+    // Whether the latest events are the first `len` ones of the combo, in time
+    fn matches(&self, combo: &Combo, len: usize) -> bool {
+        if len == 0 || len > self.history.len() {
+            return false;
+        }
+        let tail = self.history.range(self.history.len() - len..);
+        let mut previous: Option<f32> = None;
+        for ((event, time), expected) in tail.zip(&combo.events) {
+            if event != expected || previous.is_some_and(|previous| time - previous > combo.max_gap) {
+                return false;
+            }
+            previous = Some(*time);
+        }
+        self.history[self.history.len() - len].1 + combo.max_duration >= self.now
+    }
+
+    // Deadline of the longest combo the latest events may still grow into,
+    // beyond `len` events
+    fn continuation(&self, len: usize) -> Option<f32> {
+        self.combos.iter()
+            .filter(|combo| (len.max(1)..combo.events.len()).any(|prefix| self.matches(combo, prefix)))
+            .map(|combo| self.now + combo.max_gap)
+            .reduce(f32::max)
+    }
+
+    fn complete(&mut self, index: usize) -> Option<&str> {
+        self.history.clear();
+        self.pending = None;
+        Some(self.combos[index].name.as_str())
+    }
+
+    // Moves the clock on, giving a held back combo once nothing longer can follow
+    pub fn advance(&mut self, delta: f32) -> Option<&str> {
+        self.now += delta;
+        match self.pending {
+            Some((index, deadline)) if self.now > deadline => self.complete(index),
+            _ => None,
+        }
+    }
+
+    pub fn add_event(&mut self, event: &str) -> Option<&str> {
+        if self.combos.is_empty() {
+            return None;
+        }
+        let longest = self.combos.iter().map(|combo| combo.events.len()).max().unwrap_or(0);
+        if self.history.len() >= longest {
+            self.history.pop_front();
+        }
+        self.history.push_back((String::from(event), self.now));
+
+        let matched = (0..self.combos.len())
+            .filter(|index| self.matches(&self.combos[*index], self.combos[*index].events.len()))
+            .max_by_key(|index| self.combos[*index].events.len());
+        if let Some(index) = matched {
+            return match self.continuation(self.combos[index].events.len()) {
+                Some(deadline) => {
+                    self.pending = Some((index, deadline));
+                    None
+                },
+                None => self.complete(index),
+            };
+        }
+        let (index, _) = self.pending?;
+        match self.continuation(self.combos[index].events.len() + 1) {
+            Some(deadline) => {
+                self.pending = Some((index, deadline));
+                None
+            },
+            // The longer combo took another turn
+            None => {
+                self.history.drain(..self.history.len() - 1);
+                self.pending = None;
+                Some(self.combos[index].name.as_str())
+            },
+        }
+    }
+}
+
+#[test]
+fn test_parse_combos() {
+    let combos = parse(" nod = vertical, vertical /0.6; unlock=double_tap,twist_cw/1/2.5;").unwrap();
+    assert_eq!(combos, [
+        Combo { name: String::from("nod"), events: vec![String::from("vertical"); 2], max_gap: 0.6, max_duration: 3.0 },
+        Combo {
+            name: String::from("unlock"),
+            events: vec![String::from("double_tap"), String::from("twist_cw")],
+            max_gap: 1.0,
+            max_duration: 2.5,
+        },
+    ]);
+    assert_eq!(parse(""), Ok(Vec::new()));
+    assert_eq!(parse("nod"), Err(ComboError::Malformed(String::from("nod"))));
+    assert_eq!(parse("nod=/1"), Err(ComboError::Malformed(String::from("nod=/1"))));
+    assert_eq!(parse("nod=vertical/soon"), Err(ComboError::BadTiming(String::from("nod=vertical/soon"))));
+    assert_eq!(parse("nod=vertical/1/2/3"), Err(ComboError::Malformed(String::from("nod=vertical/1/2/3"))));
+    assert_eq!(parse("a=tap;a=shake"), Err(ComboError::Duplicate(String::from("a"))));
+}
+
+#[test]
+fn test_combo_matching() {
+    let combos = parse("up_down_up=vertical,horizontal,vertical/0.8/1.2;double=tap,tap/0.5;triple=tap,tap,tap/0.5").unwrap();
+    let mut matcher = ComboMatcher::new(combos);
+    // Events with the time since the previous one, and the clock moved on
+    // by steps of 0.1 s for a second after them
+    let mut run = |events: &[(f32, &str)]| -> Vec<String> {
+        let mut found = Vec::new();
+        for (wait, event) in events {
+            found.extend(matcher.advance(*wait).map(String::from));
+            found.extend(matcher.add_event(event).map(String::from));
+        }
+        for _ in 0..10 {
+            found.extend(matcher.advance(0.1).map(String::from));
+        }
+        found
+    };
+
+    assert_eq!(run(&[(0.0, "vertical"), (0.5, "horizontal"), (0.5, "vertical")]), ["up_down_up"]);
+    // A gap too long, then too long overall
+    assert!(run(&[(0.5, "vertical"), (0.9, "horizontal"), (0.5, "vertical")]).is_empty());
+    assert!(run(&[(0.5, "vertical"), (0.7, "horizontal"), (0.7, "vertical")]).is_empty());
+    // Something else in between
+    assert!(run(&[(0.5, "vertical"), (0.3, "shake"), (0.3, "horizontal"), (0.3, "vertical")]).is_empty());
+
+    // The longest combo wins, the shorter one once the longer one cannot follow
+    assert_eq!(run(&[(0.5, "tap"), (0.3, "tap"), (0.3, "tap")]), ["triple"]);
+    assert_eq!(run(&[(0.5, "tap"), (0.3, "tap")]), ["double"]);
+    assert_eq!(run(&[(0.5, "tap"), (0.3, "tap"), (0.3, "vertical"), (0.3, "horizontal"), (0.3, "vertical")]),
+               ["double", "up_down_up"]);
+    // Events count once
+    assert_eq!(run(&[(0.5, "tap"), (0.1, "tap"), (0.1, "tap"), (0.1, "tap"), (0.1, "tap")]), ["triple", "double"]);
+}
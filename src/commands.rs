@@ -0,0 +1,172 @@
+use core::fmt;
+
+use crate::pipeline::Pipeline;
+use crate::sensor_recovery::SensorSupervisor;
+use crate::stream::{StreamConfig, StreamContent};
+use crate::tap::{TapConfig, MAX_SENSITIVITY, MIN_SENSITIVITY};
+
+// Topic the device listens on, commands are plain text
+pub const TOPIC: &str = "commands";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Ping,
+    // Replies with the sensor state
+    Status,
+    Pause,
+    Resume,
+    // `stream raw|fused [decimation]` or `stream off`
+    Stream(Option<StreamConfig>),
+    // `record <gesture>` starts an example of a custom gesture, `record stop`
+    // ends it, see `gesture_templates`
+    Record(Option<String>),
+    // Makes a template of the recorded examples
+    Enroll(String),
+    Forget(String),
+    // `tap <sensitivity>`, from 1 to 10, see `tap::TapConfig::with_sensitivity`
+    TapSensitivity(u8),
+    // `mount level` finds the mounting with the device lying face up, acked
+    // again once done, see `mounting::MountingCalibration`
+    MountLevel,
+}
+
+impl Command {
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        let mut words = core::str::from_utf8(payload).ok()?.split_whitespace();
+        let command = match words.next()? {
+            "ping" => Command::Ping,
+            "status" => Command::Status,
+            "pause" => Command::Pause,
+            "resume" => Command::Resume,
+            "stream" => match words.next()? {
+                "off" => Command::Stream(None),
+                content => Command::Stream(Some(StreamConfig {
+                    content: StreamContent::parse(content)?,
+                    decimation: words.next().map_or(Some(1), |decimation| decimation.parse().ok())?.max(1),
+                })),
+            },
+            "record" => match words.next()? {
+                "stop" => Command::Record(None),
+                name => Command::Record(Some(String::from(name))),
+            },
+            "enroll" => Command::Enroll(String::from(words.next()?)),
+            "forget" => Command::Forget(String::from(words.next()?)),
+            "tap" => Command::TapSensitivity(words.next()?.parse().ok()
+                .filter(|sensitivity| (MIN_SENSITIVITY..=MAX_SENSITIVITY).contains(sensitivity))?),
+            "mount" => match words.next()? {
+                "level" => Command::MountLevel,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        // Trailing words are a mistake rather than something to ignore
+        match words.next() {
+            None => Some(command),
+            Some(_) => None,
+        }
+    }
+}
+
+// Reply to every received command, published on `<mqtt_id>/ack`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ack {
+    Ok { command: String, detail: Option<String> },
+    Error { command: String, reason: String },
+}
+
+impl fmt::Display for Ack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ack::Ok { command, detail: None } => write!(f, "{} ok", command),
+            Ack::Ok { command, detail: Some(detail) } => write!(f, "{} ok {}", command, detail),
+            Ack::Error { command, reason } => write!(f, "{} error {}", command, reason),
+        }
+    }
+}
+
+pub fn execute(payload: &[u8], supervisor: &mut SensorSupervisor, pipeline: &mut Pipeline) -> Ack {
+    let command = String::from_utf8_lossy(payload).trim().to_string();
+    let result = match Command::parse(payload) {
+        None => Err(String::from("unknown_command")),
+        Some(Command::Ping) => Ok(None),
+        Some(Command::Status) => Ok(Some(format!("{:?}", supervisor.status()))),
+        Some(Command::Pause) => supervisor.pause().map(|_| None).map_err(|err| format!("{:?}", err)),
+        Some(Command::Resume) => supervisor.resume().map(|_| None).map_err(|err| format!("{:?}", err)),
+        Some(Command::Stream(config)) => {
+            pipeline.set_stream(config);
+            Ok(None)
+        },
+        Some(Command::Record(Some(name))) => {
+            pipeline.gestures.start_recording(&name);
+            Ok(None)
+        },
+        Some(Command::Record(None)) => pipeline.gestures.stop_recording()
+            .map(|points| Some(points.to_string())).map_err(|err| format!("{:?}", err)),
+        Some(Command::Enroll(name)) => pipeline.gestures.enroll_recorded(&name)
+            .map(|examples| Some(examples.to_string())).map_err(|err| format!("{:?}", err)),
+        Some(Command::Forget(name)) => pipeline.gestures.forget(&name).map(|_| None).map_err(|err| format!("{:?}", err)),
+        Some(Command::TapSensitivity(sensitivity)) => {
+            pipeline.taps.set_config(TapConfig::with_sensitivity(sensitivity));
+            Ok(None)
+        },
+        Some(Command::MountLevel) => {
+            pipeline.start_mount_calibration();
+            Ok(Some(String::from("started")))
+        },
+    };
+    match result {
+        Ok(detail) => Ack::Ok { command, detail },
+        Err(reason) => Ack::Error { command, reason },
+    }
+}
+
+#[test]
+fn test_commands() {
+    use core::time::Duration;
+    use imu_fusion::{FusionMatrix, FusionVector};
+
+    use crate::imu_tracker::ImuTracker;
+    use crate::sensor::SensorConfig;
+    use crate::sensor_health::HealthMonitor;
+    use crate::sensor_recovery::RecoveryPolicy;
+    use crate::state_machine::SensorFSM;
+
+    let mut fsm = SensorFSM::new();
+    fsm.bootup_complete().unwrap();
+    fsm.peripherals_complete().unwrap();
+    fsm.calibration_complete().unwrap();
+    let mut supervisor = SensorSupervisor::new(fsm, RecoveryPolicy::default());
+
+    let period = Duration::from_millis(5);
+    let tracker = ImuTracker::new(period, 2000.0, FusionMatrix::identity(), FusionVector::zero(),
+                                  FusionVector::ones(), FusionVector::zero());
+    let health = HealthMonitor::new(&SensorConfig::default());
+    let (tx, _rx) = std::sync::mpsc::channel();
+    let mut pipeline = Pipeline::new(tracker, health, period, tx);
+
+    let mut run = |payload: &str| execute(payload.as_bytes(), &mut supervisor, &mut pipeline).to_string();
+    assert_eq!(run("ping"), "ping ok");
+    assert_eq!(run("pause\n"), "pause ok");
+    assert_eq!(run("status"), "status ok Paused");
+    assert_eq!(run("pause"), "pause error InvalidTransition");
+    assert_eq!(run("resume"), "resume ok");
+    assert_eq!(run("status"), "status ok Sampling");
+    assert_eq!(run("reboot"), "reboot error unknown_command");
+
+    assert_eq!(run("stream fused 4"), "stream fused 4 ok");
+    assert_eq!(run("stream raw"), "stream raw ok");
+    assert_eq!(run("stream raw 2 now"), "stream raw 2 now error unknown_command");
+    assert_eq!(run("stream sideways"), "stream sideways error unknown_command");
+
+    assert_eq!(run("record stop"), "record stop error NotRecording");
+    assert_eq!(run("record wave"), "record wave ok");
+    assert_eq!(run("enroll wave"), "enroll wave error NoExamples");
+    assert_eq!(run("forget wave"), "forget wave error UnknownTemplate");
+    assert_eq!(run("enroll"), "enroll error unknown_command");
+    assert_eq!(run("tap 8"), "tap 8 ok");
+    assert_eq!(run("tap 11"), "tap 11 error unknown_command");
+    assert_eq!(run("mount level"), "mount level ok started");
+    assert_eq!(run("mount"), "mount error unknown_command");
+    assert_eq!(pipeline.stream_config(), Some(StreamConfig { content: StreamContent::Raw, decimation: 1 }));
+    assert_eq!(pipeline.taps.config(), TapConfig::with_sensitivity(8));
+}
@@ -0,0 +1,384 @@
+use core::fmt;
+use core::time::Duration;
+use std::time::Instant;
+
+use crate::state_machine::{ConnectionFSM, FSMError, LinkInput, LinkStatus, Observer};
+
+// Exponential backoff with random jitter, so that devices dropped at the same
+// time do not retry in lockstep
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    // Fraction of the delay by which it is randomly shortened or stretched
+    jitter: f32,
+    attempt: u32,
+    rng: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            jitter: 0.25,
+            attempt: 0,
+            rng: 0x9E37_79B9,
+        }
+    }
+
+    pub fn with_seed(mut self, seed: u32) -> Self {
+        // xorshift gets stuck at zero
+        self.rng = seed.max(1);
+        self
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.base.saturating_mul(1 << self.attempt.min(16)).min(self.max);
+        self.attempt += 1;
+        // Uniform in [-1, 1]
+        let spread = self.random() as f32 / u32::MAX as f32 * 2.0 - 1.0;
+        delay.mul_f32(1.0 + self.jitter * spread).min(self.max)
+    }
+
+    fn random(&mut self) -> u32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.rng
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkEvent {
+    WifiUp,
+    WifiDown,
+    WifiFailed,
+    MqttUp,
+    MqttDown,
+    MqttError,
+}
+
+// Work the manager hands over to whoever owns the network drivers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkAction {
+    ConnectWifi,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ConnectionMetrics {
+    pub wifi_reconnects: u32,
+    pub wifi_failures: u32,
+    pub wifi_uptime: Duration,
+    pub mqtt_reconnects: u32,
+    pub mqtt_errors: u32,
+    pub mqtt_uptime: Duration,
+}
+
+impl fmt::Display for ConnectionMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "wifi_reconnects {} wifi_failures {} wifi_uptime {} mqtt_reconnects {} mqtt_errors {} mqtt_uptime {}",
+               self.wifi_reconnects, self.wifi_failures, self.wifi_uptime.as_secs(),
+               self.mqtt_reconnects, self.mqtt_errors, self.mqtt_uptime.as_secs())
+    }
+}
+
+// Uptime bookkeeping for one link
+#[derive(Default)]
+struct Session {
+    since: Option<Instant>,
+    connections: u32,
+    uptime: Duration,
+}
+
+impl Session {
+    fn open(&mut self, now: Instant) {
+        self.since = Some(now);
+        self.connections += 1;
+    }
+
+    fn close(&mut self, now: Instant) {
+        if let Some(since) = self.since.take() {
+            self.uptime += now.saturating_duration_since(since);
+        }
+    }
+
+    fn uptime(&self, now: Instant) -> Duration {
+        self.uptime + self.since.map_or(Duration::ZERO, |since| now.saturating_duration_since(since))
+    }
+
+    fn reconnects(&self) -> u32 {
+        self.connections.saturating_sub(1)
+    }
+}
+
+/* Connection policy, kept apart from the network drivers so that it can run on
+ * host. Wi-Fi connections are requested through `poll` with exponential backoff
+ * between failures. The MQTT client reconnects by itself every `mqtt_retry`
+ * while Wi-Fi is up, the manager only keeps track of it.
+ */
+pub struct ConnectionManager {
+    fsm: ConnectionFSM,
+    wifi_backoff: Backoff,
+    mqtt_retry: Duration,
+    wifi_retry_at: Option<Instant>,
+    mqtt_retry_at: Option<Instant>,
+    wifi: Session,
+    mqtt: Session,
+    wifi_failures: u32,
+    mqtt_errors: u32,
+}
+
+impl ConnectionManager {
+    pub fn new(fsm: ConnectionFSM, wifi_backoff: Backoff, mqtt_retry: Duration) -> Self {
+        Self {
+            fsm,
+            wifi_backoff,
+            mqtt_retry,
+            wifi_retry_at: None,
+            mqtt_retry_at: None,
+            wifi: Session::default(),
+            mqtt: Session::default(),
+            wifi_failures: 0,
+            mqtt_errors: 0,
+        }
+    }
+
+    pub fn wifi_status(&self) -> LinkStatus {
+        self.fsm.wifi_status()
+    }
+
+    pub fn mqtt_status(&self) -> LinkStatus {
+        self.fsm.mqtt_status()
+    }
+
+    pub fn wifi_observer(&self) -> Observer<LinkStatus> {
+        self.fsm.wifi_observer()
+    }
+
+    pub fn mqtt_observer(&self) -> Observer<LinkStatus> {
+        self.fsm.mqtt_observer()
+    }
+
+    // Requests the first Wi-Fi connection right away
+    pub fn start(&mut self, now: Instant) {
+        self.wifi_retry_at = Some(now);
+    }
+
+    // Earliest time `poll` has something to do
+    pub fn next_deadline(&self) -> Option<Instant> {
+        match (self.wifi_retry_at, self.mqtt_retry_at) {
+            (Some(wifi), Some(mqtt)) => Some(wifi.min(mqtt)),
+            (wifi, mqtt) => wifi.or(mqtt),
+        }
+    }
+
+    pub fn poll(&mut self, now: Instant) -> Result<Option<LinkAction>, FSMError> {
+        if self.mqtt_retry_at.is_some_and(|at| at <= now) {
+            // The client is retrying on its own at this point
+            self.mqtt_retry_at = None;
+            self.fsm.mqtt(LinkInput::Connect)?;
+        }
+        if self.wifi_retry_at.is_some_and(|at| at <= now) {
+            self.wifi_retry_at = None;
+            self.fsm.wifi(LinkInput::Connect)?;
+            return Ok(Some(LinkAction::ConnectWifi));
+        }
+        Ok(None)
+    }
+
+    pub fn handle(&mut self, event: LinkEvent, now: Instant) -> Result<(), FSMError> {
+        match event {
+            LinkEvent::WifiUp => {
+                self.fsm.wifi(LinkInput::Connected)?;
+                self.wifi_backoff.reset();
+                self.wifi.open(now);
+                if self.fsm.mqtt_status() == LinkStatus::Down {
+                    self.fsm.mqtt(LinkInput::Connect)?;
+                }
+            },
+            LinkEvent::WifiFailed => {
+                self.wifi_failures += 1;
+                self.fsm.wifi(LinkInput::Failed)?;
+                self.wifi_retry_at = Some(now + self.wifi_backoff.next_delay());
+            },
+            LinkEvent::WifiDown => {
+                if self.fsm.wifi_status() != LinkStatus::Up {
+                    return Ok(());
+                }
+                self.wifi.close(now);
+                self.fsm.wifi(LinkInput::Dropped)?;
+                self.wifi_retry_at = Some(now + self.wifi_backoff.next_delay());
+                self.mqtt.close(now);
+                self.mqtt_retry_at = None;
+                if self.fsm.mqtt_status() != LinkStatus::Down {
+                    self.fsm.mqtt(LinkInput::Reset)?;
+                }
+            },
+            LinkEvent::MqttUp => {
+                match self.fsm.mqtt_status() {
+                    LinkStatus::Up => return Ok(()),
+                    // Came back before the retry was due
+                    LinkStatus::Down | LinkStatus::Backoff => self.fsm.mqtt(LinkInput::Connect)?,
+                    LinkStatus::Connecting => {},
+                }
+                self.mqtt_retry_at = None;
+                self.fsm.mqtt(LinkInput::Connected)?;
+                self.mqtt.open(now);
+            },
+            LinkEvent::MqttDown => {
+                if self.fsm.mqtt_status() == LinkStatus::Up {
+                    self.mqtt.close(now);
+                    self.fsm.mqtt(LinkInput::Dropped)?;
+                    self.mqtt_retry_at = Some(now + self.mqtt_retry);
+                }
+            },
+            LinkEvent::MqttError => {
+                self.mqtt_errors += 1;
+                if self.fsm.mqtt_status() == LinkStatus::Connecting {
+                    self.fsm.mqtt(LinkInput::Failed)?;
+                    self.mqtt_retry_at = Some(now + self.mqtt_retry);
+                }
+            },
+        }
+        Ok(())
+    }
+
+    pub fn metrics(&self, now: Instant) -> ConnectionMetrics {
+        ConnectionMetrics {
+            wifi_reconnects: self.wifi.reconnects(),
+            wifi_failures: self.wifi_failures,
+            wifi_uptime: self.wifi.uptime(now),
+            mqtt_reconnects: self.mqtt.reconnects(),
+            mqtt_errors: self.mqtt_errors,
+            mqtt_uptime: self.mqtt.uptime(now),
+        }
+    }
+}
+
+#[cfg(test)]
+const BASE: Duration = Duration::from_secs(1);
+#[cfg(test)]
+const MQTT_RETRY: Duration = Duration::from_secs(5);
+
+#[cfg(test)]
+fn manager() -> ConnectionManager {
+    ConnectionManager::new(ConnectionFSM::new(), Backoff::new(BASE, Duration::from_secs(60)), MQTT_RETRY)
+}
+
+#[test]
+fn test_backoff_grows_with_jitter() {
+    let mut backoff = Backoff::new(BASE, Duration::from_secs(20)).with_seed(7);
+    let delays: Vec<_> = (0..8).map(|_| backoff.next_delay()).collect();
+    for (attempt, delay) in delays.iter().enumerate() {
+        let nominal = BASE.saturating_mul(1 << attempt).min(Duration::from_secs(20));
+        assert!(*delay >= nominal.mul_f32(0.75) && *delay <= nominal.mul_f32(1.25), "{:?}", delays);
+        assert!(*delay <= Duration::from_secs(20));
+    }
+    // Not a plain doubling sequence
+    assert!(delays.iter().take(4).enumerate().any(|(attempt, delay)| *delay != BASE * (1 << attempt)));
+
+    backoff.reset();
+    assert!(backoff.next_delay() <= BASE.mul_f32(1.25));
+    assert_eq!(backoff.attempt(), 1);
+}
+
+#[test]
+fn test_wifi_retries_with_backoff() {
+    let mut manager = manager();
+    let start = Instant::now();
+    manager.start(start);
+
+    // Two failed attempts, each waiting longer than the previous one
+    let mut now = start;
+    let mut waits = Vec::new();
+    for _ in 0..2 {
+        assert_eq!(manager.poll(now).unwrap(), Some(LinkAction::ConnectWifi));
+        assert_eq!(manager.wifi_status(), LinkStatus::Connecting);
+        manager.handle(LinkEvent::WifiFailed, now).unwrap();
+        assert_eq!(manager.wifi_status(), LinkStatus::Backoff);
+        let deadline = manager.next_deadline().unwrap();
+        assert_eq!(manager.poll(deadline - Duration::from_millis(1)).unwrap(), None);
+        waits.push(deadline - now);
+        now = deadline;
+    }
+    assert!(waits[1] > waits[0]);
+
+    assert_eq!(manager.poll(now).unwrap(), Some(LinkAction::ConnectWifi));
+    manager.handle(LinkEvent::WifiUp, now).unwrap();
+    assert_eq!(manager.wifi_status(), LinkStatus::Up);
+    assert_eq!(manager.mqtt_status(), LinkStatus::Connecting);
+    assert_eq!(manager.next_deadline(), None);
+    assert_eq!(manager.metrics(now).wifi_failures, 2);
+}
+
+#[test]
+fn test_wifi_drop_resets_mqtt() {
+    let mut manager = manager();
+    let start = Instant::now();
+    manager.start(start);
+    manager.poll(start).unwrap();
+    manager.handle(LinkEvent::WifiUp, start).unwrap();
+    manager.handle(LinkEvent::MqttUp, start).unwrap();
+
+    let dropped = start + Duration::from_secs(100);
+    manager.handle(LinkEvent::WifiDown, dropped).unwrap();
+    assert_eq!(manager.wifi_status(), LinkStatus::Backoff);
+    assert_eq!(manager.mqtt_status(), LinkStatus::Down);
+    // Errors from the client while Wi-Fi is down change nothing
+    manager.handle(LinkEvent::MqttError, dropped).unwrap();
+    manager.handle(LinkEvent::MqttDown, dropped).unwrap();
+    assert_eq!(manager.mqtt_status(), LinkStatus::Down);
+
+    // Backoff starts over after a successful connection
+    let retry = manager.next_deadline().unwrap();
+    assert!(retry - dropped <= BASE.mul_f32(1.25));
+    assert_eq!(manager.poll(retry).unwrap(), Some(LinkAction::ConnectWifi));
+    manager.handle(LinkEvent::WifiUp, retry).unwrap();
+    manager.handle(LinkEvent::MqttUp, retry).unwrap();
+
+    let later = retry + Duration::from_secs(10);
+    let metrics = manager.metrics(later);
+    assert_eq!(metrics.wifi_reconnects, 1);
+    assert_eq!(metrics.mqtt_reconnects, 1);
+    assert_eq!(metrics.wifi_uptime, Duration::from_secs(100) + Duration::from_secs(10));
+    assert_eq!(metrics.mqtt_errors, 1);
+}
+
+#[test]
+fn test_mqtt_errors_do_not_stop_the_link() {
+    let mut manager = manager();
+    let start = Instant::now();
+    manager.start(start);
+    manager.poll(start).unwrap();
+    manager.handle(LinkEvent::WifiUp, start).unwrap();
+
+    // Broker unreachable: every client retry fails
+    let mut now = start;
+    for _ in 0..3 {
+        manager.handle(LinkEvent::MqttError, now).unwrap();
+        assert_eq!(manager.mqtt_status(), LinkStatus::Backoff);
+        now += MQTT_RETRY;
+        assert_eq!(manager.poll(now).unwrap(), None);
+        assert_eq!(manager.mqtt_status(), LinkStatus::Connecting);
+    }
+    manager.handle(LinkEvent::MqttUp, now).unwrap();
+    assert_eq!(manager.mqtt_status(), LinkStatus::Up);
+
+    // Broker restart
+    manager.handle(LinkEvent::MqttDown, now).unwrap();
+    manager.handle(LinkEvent::MqttUp, now + Duration::from_secs(1)).unwrap();
+    assert_eq!(manager.mqtt_status(), LinkStatus::Up);
+    let metrics = manager.metrics(now + Duration::from_secs(1));
+    assert_eq!(metrics.mqtt_errors, 3);
+    assert_eq!(metrics.mqtt_reconnects, 1);
+    assert_eq!(metrics.wifi_reconnects, 0);
+    assert!(manager.mqtt_observer().history().iter().any(|t| t.to == LinkStatus::Backoff));
+}
@@ -0,0 +1,148 @@
+use std::io::Write;
+
+use anyhow::Result;
+
+use crate::outbox::Outbound;
+
+// Destination of the outbound messages
+pub trait EventSink {
+    // Whether messages can go out right now, otherwise they stay queued
+    fn is_ready(&self) -> bool {
+        true
+    }
+
+    fn send(&mut self, message: &Outbound) -> Result<()>;
+
+    // Called regularly from the sending task, e.g. to restore a session
+    fn maintain(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Same layout as the MQTT topics, e.g. `<mqtt_id>/event`
+pub fn topic(prefix: &str, message: &Outbound) -> String {
+    format!("{}/{}", prefix, message.topic())
+}
+
+/* One message per line, `<topic> <payload>`, with the payload escaped to
+ * printable ASCII so that it never breaks the line. Meant for a serial console,
+ * where a reader can tell the messages from the log output by their topic.
+ */
+pub struct LineSink<W: Write> {
+    writer: W,
+    prefix: String,
+}
+
+impl<W: Write> LineSink<W> {
+    pub fn new(writer: W, prefix: &str) -> Self {
+        Self { writer, prefix: String::from(prefix) }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> EventSink for LineSink<W> {
+    fn send(&mut self, message: &Outbound) -> Result<()> {
+        writeln!(self.writer, "{} {}", topic(&self.prefix, message), message.payload().escape_ascii())?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// Host sinks: "stdout" or "file:<path>", the file is appended to
+#[cfg(not(target_os = "espidf"))]
+pub fn open(spec: &str, prefix: &str) -> Result<Box<dyn EventSink + Send>> {
+    match spec.split_once(':') {
+        None if spec == "stdout" => Ok(Box::new(LineSink::new(std::io::stdout(), prefix))),
+        Some(("file", path)) => {
+            let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+            Ok(Box::new(LineSink::new(file, prefix)))
+        },
+        _ => Err(anyhow::anyhow!("Unknown event sink {}", spec)),
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub use esp::MqttSink;
+
+#[cfg(target_os = "espidf")]
+mod esp {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use anyhow::Result;
+    use esp_idf_svc::mqtt::client::{EspMqttClient, QoS};
+
+    use super::{topic, EventSink};
+    use crate::commands;
+    use crate::outbox::Outbound;
+    use crate::state_machine::{LinkStatus, Observer};
+
+    // Publishes on the device topics while the broker connection is up, and
+    // subscribes to the commands after every connection
+    pub struct MqttSink {
+        client: EspMqttClient<'static>,
+        prefix: String,
+        status: Observer<LinkStatus>,
+        // Set by the MQTT event task on every connection: subscriptions do not
+        // survive a clean session
+        subscribe: Arc<AtomicBool>,
+    }
+
+    impl MqttSink {
+        pub fn new(client: EspMqttClient<'static>, prefix: &str, status: Observer<LinkStatus>,
+                   subscribe: Arc<AtomicBool>) -> Self {
+            Self { client, prefix: String::from(prefix), status, subscribe }
+        }
+    }
+
+    impl EventSink for MqttSink {
+        fn is_ready(&self) -> bool {
+            self.status.state() == LinkStatus::Up
+        }
+
+        fn send(&mut self, message: &Outbound) -> Result<()> {
+            self.client.publish(&topic(&self.prefix, message), QoS::AtLeastOnce, false, message.payload())?;
+            Ok(())
+        }
+
+        fn maintain(&mut self) -> Result<()> {
+            if self.subscribe.swap(false, Ordering::Relaxed) {
+                if let Err(error) = self.client.subscribe(commands::TOPIC, QoS::AtLeastOnce) {
+                    // Again on the next call
+                    self.subscribe.store(true, Ordering::Relaxed);
+                    return Err(error.into());
+                }
+                log::info!("Subscribed to topic!");
+            }
+            Ok(())
+        }
+    }
+}
+
+#[test]
+fn test_line_format() {
+    let mut sink = LineSink::new(Vec::new(), "imu1");
+    sink.send(&Outbound::Event(vec![0x33])).unwrap();
+    sink.send(&Outbound::Fault(String::from("read_error 1 Bus(\"timeout\")"))).unwrap();
+    sink.send(&Outbound::Event(vec![b'\n', 0xFF])).unwrap();
+    assert_eq!(String::from_utf8(sink.into_inner()).unwrap(),
+               "imu1/event 3\nimu1/fault read_error 1 Bus(\\\"timeout\\\")\nimu1/event \\n\\xff\n");
+}
+
+#[test]
+fn test_file_sink_appends() {
+    let path = std::env::temp_dir().join(format!("event_sink_{}.log", std::process::id()));
+    let spec = format!("file:{}", path.display());
+    for payload in [b"1", b"2"] {
+        let mut sink = open(&spec, "imu1").unwrap();
+        assert!(sink.is_ready());
+        sink.send(&Outbound::Event(payload.to_vec())).unwrap();
+    }
+    let written = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(written, "imu1/event 1\nimu1/event 2\n");
+    assert!(open("udp:1234", "imu1").is_err());
+}
@@ -0,0 +1,85 @@
+use core::time::Duration;
+
+use embedded_hal::blocking::delay::DelayMs;
+use imu_fusion::FusionVector;
+
+use crate::sensor::{ImuSample, ImuSensor, SensorConfig, SensorError};
+
+// Sensor stand-in returning a fixed sample, with scripted read and
+// reinitialization failures
+pub struct FakeSensor {
+    config: SensorConfig,
+    pub sample: ImuSample,
+    failing_reads: u32,
+    failing_reinits: u32,
+    pub reads: u32,
+    pub reinits: u32,
+}
+
+impl FakeSensor {
+    pub fn new() -> Self {
+        Self {
+            config: SensorConfig::default(),
+            sample: ImuSample {
+                accel: FusionVector::new(0.0, 0.0, 1.0),
+                gyro: FusionVector::zero(),
+            },
+            failing_reads: 0,
+            failing_reinits: 0,
+            reads: 0,
+            reinits: 0,
+        }
+    }
+
+    // The next `count` reads fail
+    pub fn fail_reads(&mut self, count: u32) {
+        self.failing_reads = count;
+    }
+
+    // The next `count` reinitializations fail
+    pub fn fail_reinits(&mut self, count: u32) {
+        self.failing_reinits = count;
+    }
+}
+
+impl Default for FakeSensor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ImuSensor for FakeSensor {
+    fn who_am_i(&mut self) -> Result<u8, SensorError> {
+        Ok(0)
+    }
+
+    fn read(&mut self) -> Result<ImuSample, SensorError> {
+        self.reads += 1;
+        if self.failing_reads > 0 {
+            self.failing_reads -= 1;
+            return Err(SensorError::Bus(String::from("injected read error")));
+        }
+        Ok(self.sample)
+    }
+
+    fn enable_data_ready(&mut self) -> Result<(), SensorError> {
+        Ok(())
+    }
+
+    fn config(&self) -> &SensorConfig {
+        &self.config
+    }
+
+    fn sample_period(&self) -> Duration {
+        Duration::from_millis(4)
+    }
+
+    fn reinit(&mut self, _delay: &mut dyn DelayMs<u8>) -> Result<(), SensorError> {
+        self.reinits += 1;
+        if self.failing_reinits > 0 {
+            self.failing_reinits -= 1;
+            return Err(SensorError::Bus(String::from("injected reinit error")));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+use crate::gesture_features::{sample, FeatureWindow, Features, FEATURES};
+
+use imu_fusion::FusionVector;
+
+/* Linear classifier of gesture windows in fixed point, trained on the host
+ * from labeled captures (see the `train_classifier` host tool) and built into
+ * the firmware as a blob in `gesture_model`.
+ *
+ * Features are standardized with the offset and scale of the model, then
+ * quantized with `FRACTION_BITS` and clamped to `FEATURE_LIMIT`. The score of
+ * each class is the dot product with its weights, quantized the same way, plus
+ * its bias with twice the fraction bits. The best score wins.
+ *
+ * Blob, all little endian: `GCL1`, u8 classes, u8 features, u16 window and
+ * u16 stride in samples, the labels as u8 length and text, the offset and
+ * scale of each feature as f32, the i16 weights class by class, then the i32
+ * bias of each class.
+ */
+const MAGIC: &[u8] = b"GCL1";
+pub const FRACTION_BITS: u32 = 8;
+// Standardized features beyond this many deviations are clamped
+pub const FEATURE_LIMIT: f32 = 8.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModelError {
+    BadMagic,
+    Truncated,
+    // The model was made for another feature set
+    FeatureCount(u8),
+    NoClasses,
+}
+
+fn to_fixed(value: f32, fraction_bits: u32) -> f32 {
+    (value * (1u32 << fraction_bits) as f32).round()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Classifier {
+    labels: Vec<String>,
+    // Samples per window and between two classifications
+    pub window: u16,
+    pub stride: u16,
+    offsets: Vec<f32>,
+    scales: Vec<f32>,
+    weights: Vec<i16>,
+    biases: Vec<i32>,
+}
+
+impl Classifier {
+    /* Quantizes a model trained in floating point: features are standardized
+     * as `(feature - offset) * scale` before being weighed, and `weights` has
+     * one row of `FEATURES` per label.
+     */
+    pub fn quantize(labels: Vec<String>, window: u16, stride: u16, offsets: &[f32], scales: &[f32],
+                    weights: &[f32], biases: &[f32]) -> Self {
+        assert!(offsets.len() == FEATURES && scales.len() == FEATURES);
+        assert!(weights.len() == labels.len() * FEATURES && biases.len() == labels.len());
+        Self {
+            window,
+            stride,
+            offsets: offsets.to_vec(),
+            scales: scales.to_vec(),
+            weights: weights.iter()
+                .map(|weight| to_fixed(*weight, FRACTION_BITS).clamp(i16::MIN as f32, i16::MAX as f32) as i16)
+                .collect(),
+            biases: biases.iter().map(|bias| to_fixed(*bias, 2 * FRACTION_BITS) as i32).collect(),
+            labels,
+        }
+    }
+
+    pub fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    pub fn quantize_features(&self, features: &Features) -> [i16; FEATURES] {
+        let mut quantized = [0; FEATURES];
+        for (index, value) in quantized.iter_mut().enumerate() {
+            let standard = ((features[index] - self.offsets[index]) * self.scales[index])
+                .clamp(-FEATURE_LIMIT, FEATURE_LIMIT);
+            // NaN ends up as 0, which is the mean
+            *value = to_fixed(standard, FRACTION_BITS) as i16;
+        }
+        quantized
+    }
+
+    // Best class and its margin over the second best, in fixed point
+    pub fn classify(&self, features: &Features) -> (usize, i64) {
+        let features = self.quantize_features(features);
+        let mut best = (0, i64::MIN);
+        let mut second = i64::MIN;
+        for (class, row) in self.weights.chunks(FEATURES).enumerate() {
+            let score = self.biases[class] as i64
+                + row.iter().zip(features).map(|(weight, feature)| *weight as i64 * feature as i64).sum::<i64>();
+            if score > best.1 {
+                second = best.1;
+                best = (class, score);
+            } else if score > second {
+                second = score;
+            }
+        }
+        (best.0, best.1.saturating_sub(second))
+    }
+
+    pub fn to_blob(&self) -> Vec<u8> {
+        let mut blob = MAGIC.to_vec();
+        blob.extend_from_slice(&[self.labels.len() as u8, FEATURES as u8]);
+        blob.extend_from_slice(&self.window.to_le_bytes());
+        blob.extend_from_slice(&self.stride.to_le_bytes());
+        for label in &self.labels {
+            blob.push(label.len() as u8);
+            blob.extend_from_slice(label.as_bytes());
+        }
+        for (offset, scale) in self.offsets.iter().zip(&self.scales) {
+            blob.extend_from_slice(&offset.to_le_bytes());
+            blob.extend_from_slice(&scale.to_le_bytes());
+        }
+        for weight in &self.weights {
+            blob.extend_from_slice(&weight.to_le_bytes());
+        }
+        for bias in &self.biases {
+            blob.extend_from_slice(&bias.to_le_bytes());
+        }
+        blob
+    }
+
+    pub fn from_blob(blob: &[u8]) -> Result<Self, ModelError> {
+        let mut rest = blob.strip_prefix(MAGIC).ok_or(ModelError::BadMagic)?;
+        let mut take = |len: usize| -> Result<&[u8], ModelError> {
+            if rest.len() < len {
+                return Err(ModelError::Truncated);
+            }
+            let (taken, tail) = rest.split_at(len);
+            rest = tail;
+            Ok(taken)
+        };
+        let counts = take(2)?;
+        let (classes, features) = (counts[0] as usize, counts[1]);
+        if features as usize != FEATURES {
+            return Err(ModelError::FeatureCount(features));
+        }
+        if classes == 0 {
+            return Err(ModelError::NoClasses);
+        }
+        let window = u16::from_le_bytes(take(2)?.try_into().unwrap());
+        let stride = u16::from_le_bytes(take(2)?.try_into().unwrap());
+        let mut labels = Vec::with_capacity(classes);
+        for _ in 0..classes {
+            let len = take(1)?[0] as usize;
+            labels.push(String::from_utf8_lossy(take(len)?).into_owned());
+        }
+        let (mut offsets, mut scales) = (Vec::with_capacity(FEATURES), Vec::with_capacity(FEATURES));
+        for _ in 0..FEATURES {
+            offsets.push(f32::from_le_bytes(take(4)?.try_into().unwrap()));
+            scales.push(f32::from_le_bytes(take(4)?.try_into().unwrap()));
+        }
+        let weights = take(2 * classes * FEATURES)?.chunks(2)
+            .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect();
+        let biases = take(4 * classes)?.chunks(4)
+            .map(|bytes| i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect();
+        Ok(Self { labels, window, stride, offsets, scales, weights, biases })
+    }
+}
+
+// Label of the windows that are no gesture in particular, never reported
+pub const NO_GESTURE: &str = "none";
+
+// Runs the classifier over the samples, reporting gestures as they start
+pub struct GestureClassifier {
+    pub classifier: Classifier,
+    window: FeatureWindow,
+    previous: Option<usize>,
+}
+
+impl GestureClassifier {
+    pub fn new(classifier: Classifier) -> Self {
+        let window = FeatureWindow::new((classifier.window as usize).max(2), (classifier.stride as usize).max(1));
+        Self { classifier, window, previous: None }
+    }
+
+    // Label and margin of a gesture different from the previous window's
+    pub fn add_sample(&mut self, linear_accel: FusionVector, gyro: FusionVector) -> Option<(&str, f32)> {
+        let features = self.window.push(sample(linear_accel, gyro))?;
+        let (class, margin) = self.classifier.classify(&features);
+        let previous = self.previous.replace(class);
+        let label = self.classifier.labels[class].as_str();
+        if previous == Some(class) || label == NO_GESTURE {
+            return None;
+        }
+        Some((label, margin as f32 / (1u32 << (2 * FRACTION_BITS)) as f32))
+    }
+}
+
+#[test]
+fn test_model_blob() {
+    use crate::gesture_features::STATS;
+
+    // Vertical when the z energy is high, none otherwise
+    let z_energy = 2 * STATS + 3;
+    let mut offsets = [0.0; FEATURES];
+    offsets[z_energy] = 0.1;
+    let mut scales = [1.0; FEATURES];
+    scales[z_energy] = 10.0;
+    let mut weights = [0.0; 2 * FEATURES];
+    weights[FEATURES + z_energy] = 1.0;
+    let classifier = Classifier::quantize(vec![String::from(NO_GESTURE), String::from("vertical")], 40, 10,
+                                          &offsets, &scales, &weights, &[0.0, 0.0]);
+
+    let blob = classifier.to_blob();
+    assert_eq!(blob.len(), 4 + 2 + 4 + 5 + 9 + 8 * FEATURES + 2 * 2 * FEATURES + 2 * 4);
+    assert_eq!(Classifier::from_blob(&blob), Ok(classifier.clone()));
+    assert_eq!(Classifier::from_blob(&blob[..blob.len() - 1]), Err(ModelError::Truncated));
+    assert_eq!(Classifier::from_blob(&[]), Err(ModelError::BadMagic));
+
+    let mut features = [0.0; FEATURES];
+    // One deviation under the offset, weighed 1
+    assert_eq!(classifier.classify(&features), (0, 256 * 256));
+    features[z_energy] = 0.25;
+    // 1.5 deviations, weighed 1
+    assert_eq!(classifier.classify(&features), (1, 384 * 256));
+
+    let mut gestures = GestureClassifier::new(classifier);
+    let mut reported = Vec::new();
+    for index in 0..200 {
+        let lift = if (60..120).contains(&index) && index % 4 < 2 { 0.5 } else { 0.0 };
+        if let Some((label, margin)) = gestures.add_sample(FusionVector::new(0.0, 0.0, lift), FusionVector::zero()) {
+            reported.push((index, String::from(label), margin));
+        }
+    }
+    assert_eq!(reported.len(), 1, "{:?}", reported);
+    assert_eq!(reported[0].1, "vertical");
+    assert!(reported[0].2 > 0.0);
+}
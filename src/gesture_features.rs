@@ -0,0 +1,102 @@
+use std::collections::VecDeque;
+
+use imu_fusion::FusionVector;
+
+/* Features of a window of samples, for the gesture classifier. For each axis
+ * of the linear acceleration (m/s²) and of the gyro (deg/s): mean, variance,
+ * peak (largest absolute value), energy (mean square) and the rate of zero
+ * crossings around the mean. Then the dominant axis of the linear
+ * acceleration and of the gyro, by energy, as 0, 1 or 2.
+ */
+pub const AXES: usize = 6;
+pub const STATS: usize = 5;
+pub const FEATURES: usize = AXES * STATS + 2;
+pub type Features = [f32; FEATURES];
+pub type Sample = [f32; AXES];
+
+pub fn sample(linear_accel: FusionVector, gyro: FusionVector) -> Sample {
+    [linear_accel.x, linear_accel.y, linear_accel.z, gyro.x, gyro.y, gyro.z]
+}
+
+fn dominant_axis(energies: &[f32]) -> f32 {
+    let mut dominant = 0;
+    for (axis, energy) in energies.iter().enumerate() {
+        if *energy > energies[dominant] {
+            dominant = axis;
+        }
+    }
+    dominant as f32
+}
+
+pub fn extract<'a>(window: impl Iterator<Item = &'a Sample> + Clone) -> Features {
+    let mut features = [0.0; FEATURES];
+    let len = window.clone().count().max(1) as f32;
+    for axis in 0..AXES {
+        let values = window.clone().map(|sample| sample[axis]);
+        let mean = values.clone().sum::<f32>() / len;
+        let energy = values.clone().map(|value| value * value).sum::<f32>() / len;
+        let variance = values.clone().map(|value| (value - mean) * (value - mean)).sum::<f32>() / len;
+        let peak = values.clone().fold(0.0f32, |peak, value| peak.max(value.abs()));
+        let mut crossings = 0;
+        let mut previous: Option<bool> = None;
+        for above in values.map(|value| value > mean) {
+            if previous.is_some_and(|previous| previous != above) {
+                crossings += 1;
+            }
+            previous = Some(above);
+        }
+        features[axis * STATS..(axis + 1) * STATS]
+            .copy_from_slice(&[mean, variance, peak, energy, crossings as f32 / len]);
+    }
+    let energies: Vec<f32> = (0..AXES).map(|axis| features[axis * STATS + 3]).collect();
+    features[AXES * STATS] = dominant_axis(&energies[..3]);
+    features[AXES * STATS + 1] = dominant_axis(&energies[3..]);
+    features
+}
+
+// The latest `len` samples, handing out their features every `stride` samples
+pub struct FeatureWindow {
+    samples: VecDeque<Sample>,
+    len: usize,
+    stride: usize,
+    since_features: usize,
+}
+
+impl FeatureWindow {
+    pub fn new(len: usize, stride: usize) -> Self {
+        assert!(len > 1 && stride > 0);
+        Self { samples: VecDeque::with_capacity(len), len, stride, since_features: 0 }
+    }
+
+    pub fn push(&mut self, sample: Sample) -> Option<Features> {
+        if self.samples.len() >= self.len {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+        self.since_features += 1;
+        if self.samples.len() < self.len || self.since_features < self.stride {
+            return None;
+        }
+        self.since_features = 0;
+        Some(extract(self.samples.iter()))
+    }
+}
+
+#[test]
+fn test_features() {
+    // Oscillation along the vertical, with a steady rotation about x
+    let window: Vec<Sample> = (0..100).map(|index| {
+        let lift = if index % 10 < 5 { 0.5 } else { -0.5 };
+        [0.0, 0.0, lift, 20.0, 0.0, 0.0]
+    }).collect();
+    let features = extract(window.iter());
+    let z = 2 * STATS;
+    assert_eq!(&features[z..z + STATS], &[0.0, 0.25, 0.5, 0.25, 0.19]);
+    let gyro_x = 3 * STATS;
+    assert_eq!(&features[gyro_x..gyro_x + STATS], &[20.0, 0.0, 20.0, 400.0, 0.0]);
+    assert_eq!(features[AXES * STATS..], [2.0, 0.0]);
+
+    let mut features = FeatureWindow::new(100, 25);
+    let extracted: Vec<usize> = (0..200).filter(|index| features.push(window[index % 100]).is_some()).collect();
+    assert_eq!(extracted, [99, 124, 149, 174, 199]);
+}
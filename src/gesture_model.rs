@@ -0,0 +1,3 @@
+// Gesture classifier built into the firmware, see `gesture_classifier`.
+// Generated by `cargo run --bin train_classifier` in `host`, empty for none.
+pub const MODEL: &[u8] = &[];
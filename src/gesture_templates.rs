@@ -0,0 +1,389 @@
+use std::collections::VecDeque;
+
+use imu_fusion::{FusionEuler, FusionVector};
+
+use crate::imu_tracker::wrap_degrees;
+
+/* Recognition of user-recorded gestures by dynamic time warping (DTW).
+ *
+ * Samples are averaged down to one point every `decimation` samples, and the
+ * latest `window` points are compared against every template of that length,
+ * within a band of `band` points around the diagonal of the cost matrix. Only
+ * one template is compared per sample, so that the per-sample cost stays at
+ * `window * (2 * band + 1)` cells however many templates there are: with the
+ * defaults at 200 Hz, 288 cells every 5 ms, and all templates are compared
+ * between two points as long as there are no more than `decimation` of them.
+ *
+ * Points are the linear acceleration in m/s² and the orientation, the latter
+ * relative to the start of the window so that the heading does not matter.
+ */
+pub const FEATURES: usize = 6;
+pub type Point = [f32; FEATURES];
+
+// Orientation change counting as much as 1 m/s² of linear acceleration, so
+// that 90 degrees weigh about as much as 1 g
+const DEGREES_PER_MS2: f32 = 9.0;
+pub const MAX_TEMPLATES: usize = 8;
+// Longest recording kept for enrollment, in windows
+const MAX_RECORDING_WINDOWS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DtwConfig {
+    // Samples averaged into a point
+    pub decimation: usize,
+    // Points per template and compared window
+    pub window: usize,
+    // How far the alignment may stray from the diagonal, in points
+    pub band: usize,
+    // Distance under which a single example template matches
+    pub max_distance: f32,
+}
+
+impl Default for DtwConfig {
+    fn default() -> Self {
+        Self { decimation: 8, window: 32, band: 4, max_distance: 1.5 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TemplateError {
+    NoExamples,
+    // Examples need at least two points
+    TooShort,
+    TooManyTemplates,
+    NotRecording,
+    UnknownTemplate,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    pub name: String,
+    points: Vec<Point>,
+    // Largest distance still matching, wider for examples that vary more
+    pub threshold: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GestureMatch {
+    pub name: String,
+    pub distance: f32,
+}
+
+pub fn point(linear_accel: FusionVector, euler: &FusionEuler) -> Point {
+    let angle = &euler.angle;
+    [linear_accel.x, linear_accel.y, linear_accel.z, angle.roll, angle.pitch, angle.yaw]
+}
+
+// Orientation relative to the first point, scaled to compare with m/s²
+fn normalize_into(points: &[Point], normalized: &mut Vec<Point>) {
+    normalized.clear();
+    let Some(first) = points.first().copied() else {
+        return;
+    };
+    normalized.extend(points.iter().map(|point| {
+        let mut relative = *point;
+        for axis in 3..FEATURES {
+            relative[axis] = wrap_degrees(point[axis] - first[axis]) / DEGREES_PER_MS2;
+        }
+        relative
+    }));
+}
+
+fn normalize(points: &[Point]) -> Vec<Point> {
+    let mut normalized = Vec::with_capacity(points.len());
+    normalize_into(points, &mut normalized);
+    normalized
+}
+
+// Linear interpolation to `len` points, examples come in any length
+pub fn resample(points: &[Point], len: usize) -> Vec<Point> {
+    if points.len() < 2 || len < 2 {
+        return points.first().map_or(Vec::new(), |point| vec![*point; len]);
+    }
+    let step = (points.len() - 1) as f32 / (len - 1) as f32;
+    (0..len).map(|index| {
+        let position = index as f32 * step;
+        let before = (position as usize).min(points.len() - 2);
+        let weight = position - before as f32;
+        let mut point = [0.0; FEATURES];
+        for (axis, value) in point.iter_mut().enumerate() {
+            *value = points[before][axis] * (1.0 - weight) + points[before + 1][axis] * weight;
+        }
+        point
+    }).collect()
+}
+
+fn point_distance(a: &Point, b: &Point) -> f32 {
+    a.iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum::<f32>().sqrt()
+}
+
+/* DTW distance of two sequences, averaged over the steps of the alignment so
+ * that it does not depend on the length. `rows` holds two rows of the cost
+ * matrix and is reused between calls, nothing is allocated once it is sized.
+ */
+pub fn dtw(a: &[Point], b: &[Point], band: usize, rows: &mut Vec<f32>) -> f32 {
+    let (n, m) = (a.len(), b.len());
+    if n == 0 || m == 0 {
+        return f32::INFINITY;
+    }
+    // The end of both sequences has to be within the band
+    let band = band.max(n.abs_diff(m));
+    rows.clear();
+    rows.resize(2 * (m + 1), f32::INFINITY);
+    let (mut previous, mut current) = (0, m + 1);
+    rows[0] = 0.0;
+    for i in 1..=n {
+        rows[current..current + m + 1].fill(f32::INFINITY);
+        let from = i.saturating_sub(band).max(1);
+        let to = (i + band).min(m);
+        for j in from..=to {
+            let best = rows[previous + j - 1].min(rows[previous + j]).min(rows[current + j - 1]);
+            rows[current + j] = point_distance(&a[i - 1], &b[j - 1]) + best;
+        }
+        (previous, current) = (current, previous);
+    }
+    rows[previous + m] / (n + m) as f32
+}
+
+pub struct TemplateRecognizer {
+    config: DtwConfig,
+    templates: Vec<Template>,
+    // Sum and count of the samples of the next point
+    accumulated: (Point, usize),
+    points: VecDeque<Point>,
+    // Comparisons of the current round, next template and best match so far
+    next_template: Option<usize>,
+    best: Option<(usize, f32)>,
+    // Scratch space of the comparisons
+    normalized: Vec<Point>,
+    rows: Vec<f32>,
+    recording: Option<(String, Vec<Point>)>,
+    examples: Vec<(String, Vec<Point>)>,
+}
+
+impl TemplateRecognizer {
+    pub fn new(config: DtwConfig) -> Self {
+        assert!(config.decimation > 0);
+        assert!(config.window > 1);
+        Self {
+            config,
+            templates: Vec::new(),
+            accumulated: ([0.0; FEATURES], 0),
+            points: VecDeque::with_capacity(config.window),
+            next_template: None,
+            best: None,
+            normalized: Vec::with_capacity(config.window),
+            rows: Vec::with_capacity(2 * (config.window + 1)),
+            recording: None,
+            examples: Vec::new(),
+        }
+    }
+
+    pub fn templates(&self) -> &[Template] {
+        &self.templates
+    }
+
+    /* Builds a template from a few examples of the gesture, replacing any
+     * with the same name. The template is the example closest to the others,
+     * and the spread of the examples widens its threshold.
+     */
+    pub fn enroll(&mut self, name: &str, examples: &[Vec<Point>]) -> Result<&Template, TemplateError> {
+        if examples.is_empty() {
+            return Err(TemplateError::NoExamples);
+        }
+        if examples.iter().any(|example| example.len() < 2) {
+            return Err(TemplateError::TooShort);
+        }
+        let existing = self.templates.iter().position(|template| template.name == name);
+        if existing.is_none() && self.templates.len() >= MAX_TEMPLATES {
+            return Err(TemplateError::TooManyTemplates);
+        }
+
+        let examples: Vec<Vec<Point>> = examples.iter()
+            .map(|example| normalize(&resample(example, self.config.window)))
+            .collect();
+        let totals: Vec<f32> = examples.iter().map(|example| {
+            examples.iter().map(|other| dtw(example, other, self.config.band, &mut self.rows)).sum()
+        }).collect();
+        let medoid = (0..examples.len()).min_by(|a, b| totals[*a].total_cmp(&totals[*b])).unwrap_or(0);
+        let spread = if examples.len() > 1 { totals[medoid] / (examples.len() - 1) as f32 } else { 0.0 };
+
+        let template = Template {
+            name: String::from(name),
+            points: examples[medoid].clone(),
+            threshold: self.config.max_distance + spread,
+        };
+        // A round in progress may point past the end once templates change
+        self.next_template = None;
+        self.best = None;
+        let index = match existing {
+            Some(index) => {
+                self.templates[index] = template;
+                index
+            },
+            None => {
+                self.templates.push(template);
+                self.templates.len() - 1
+            },
+        };
+        Ok(&self.templates[index])
+    }
+
+    pub fn forget(&mut self, name: &str) -> Result<(), TemplateError> {
+        let index = self.templates.iter().position(|template| template.name == name)
+            .ok_or(TemplateError::UnknownTemplate)?;
+        self.templates.remove(index);
+        self.next_template = None;
+        self.best = None;
+        Ok(())
+    }
+
+    // Points from now on are an example of `name`, until `stop_recording`
+    pub fn start_recording(&mut self, name: &str) {
+        self.recording = Some((String::from(name), Vec::new()));
+    }
+
+    // Keeps the recorded example, returning its number of points
+    pub fn stop_recording(&mut self) -> Result<usize, TemplateError> {
+        let (name, points) = self.recording.take().ok_or(TemplateError::NotRecording)?;
+        if points.len() < 2 {
+            return Err(TemplateError::TooShort);
+        }
+        let len = points.len();
+        self.examples.push((name, points));
+        Ok(len)
+    }
+
+    // Enrolls the recorded examples of `name`, returning how many there were
+    pub fn enroll_recorded(&mut self, name: &str) -> Result<usize, TemplateError> {
+        let (examples, others) = std::mem::take(&mut self.examples).into_iter()
+            .partition::<Vec<_>, _>(|(example, _)| example == name);
+        self.examples = others;
+        let examples: Vec<Vec<Point>> = examples.into_iter().map(|(_, points)| points).collect();
+        self.enroll(name, &examples)?;
+        Ok(examples.len())
+    }
+
+    /* Takes a sample, returning the best matching template once a round of
+     * comparisons ends with one under its threshold. The window starts over
+     * after a match, so that one gesture is reported once.
+     */
+    pub fn add_sample(&mut self, linear_accel: FusionVector, euler: &FusionEuler) -> Option<GestureMatch> {
+        let sample = point(linear_accel, euler);
+        let (sum, count) = &mut self.accumulated;
+        for (total, value) in sum.iter_mut().zip(sample) {
+            *total += value;
+        }
+        *count += 1;
+        if *count >= self.config.decimation {
+            let point = sum.map(|total| total / *count as f32);
+            self.accumulated = ([0.0; FEATURES], 0);
+            self.add_point(point);
+        }
+        self.compare_next()
+    }
+
+    fn add_point(&mut self, point: Point) {
+        if let Some((_, recorded)) = self.recording.as_mut() {
+            if recorded.len() < MAX_RECORDING_WINDOWS * self.config.window {
+                recorded.push(point);
+            }
+        }
+        if self.points.len() >= self.config.window {
+            self.points.pop_front();
+        }
+        self.points.push_back(point);
+        if self.points.len() == self.config.window && self.next_template.is_none() && !self.templates.is_empty() {
+            self.next_template = Some(0);
+        }
+    }
+
+    fn compare_next(&mut self) -> Option<GestureMatch> {
+        let index = self.next_template?;
+        normalize_into(self.points.make_contiguous(), &mut self.normalized);
+        let template = &self.templates[index];
+        let distance = dtw(&self.normalized, &template.points, self.config.band, &mut self.rows);
+        // Ranked relative to the threshold, templates can be stricter than others
+        let ratio = distance / template.threshold;
+        if ratio < 1.0 && self.best.map_or(true, |(_, best)| ratio < best) {
+            self.best = Some((index, ratio));
+        }
+
+        if index + 1 < self.templates.len() {
+            self.next_template = Some(index + 1);
+            return None;
+        }
+        self.next_template = None;
+        let (best, ratio) = self.best.take()?;
+        self.points.clear();
+        let template = &self.templates[best];
+        Some(GestureMatch { name: template.name.clone(), distance: ratio * template.threshold })
+    }
+}
+
+#[cfg(test)]
+fn gesture(points: usize, axis: usize, amplitude: f32) -> Vec<Point> {
+    (0..points).map(|index| {
+        let mut point = [0.0; FEATURES];
+        point[axis] = amplitude * (core::f32::consts::PI * index as f32 / (points - 1) as f32).sin();
+        point
+    }).collect()
+}
+
+#[test]
+fn test_dtw_distance() {
+    let mut rows = Vec::new();
+    let up = gesture(32, 2, 1.0);
+    assert_eq!(dtw(&up, &up, 4, &mut rows), 0.0);
+    // Same movement, slower
+    let slow_up = resample(&gesture(20, 2, 1.0), 32);
+    let stretched: Vec<Point> = up[..24].iter().copied().chain(core::iter::repeat(up[31]).take(8)).collect();
+    let sideways = gesture(32, 0, 1.0);
+    assert!(dtw(&up, &slow_up, 4, &mut rows) < 0.05);
+    assert!(dtw(&up, &stretched, 4, &mut rows) < dtw(&up, &sideways, 4, &mut rows));
+
+    assert_eq!(wrap_degrees(190.0), -170.0);
+    assert_eq!(wrap_degrees(-190.0), 170.0);
+    let normalized = normalize(&[[0.0, 0.0, 0.0, 0.0, 0.0, 170.0], [0.0, 0.0, 0.0, 0.0, 0.0, -170.0]]);
+    assert!((normalized[1][5] - 20.0 / DEGREES_PER_MS2).abs() < 1e-5);
+}
+
+#[test]
+fn test_enroll_and_recognize() {
+    let config = DtwConfig { decimation: 2, ..Default::default() };
+    let mut recognizer = TemplateRecognizer::new(config);
+    assert_eq!(recognizer.enroll("up", &[]).unwrap_err(), TemplateError::NoExamples);
+    let examples = [gesture(30, 2, 10.0), gesture(34, 2, 11.0), gesture(32, 2, 9.0)];
+    let threshold = recognizer.enroll("up", &examples).unwrap().threshold;
+    assert!(threshold > config.max_distance);
+    recognizer.enroll("left", &[gesture(32, 0, -10.0)]).unwrap();
+
+    let euler = FusionEuler::zero();
+    let feed = |recognizer: &mut TemplateRecognizer, points: &[Point]| -> Vec<GestureMatch> {
+        points.iter().flat_map(|point| [point, point]).filter_map(|point| {
+            recognizer.add_sample(FusionVector::new(point[0], point[1], point[2]), &euler)
+        }).collect()
+    };
+    // Standing still matches nothing
+    assert!(feed(&mut recognizer, &[[0.0; FEATURES]; 64]).is_empty());
+    // A slower upwards movement, followed by rest
+    let mut movement = gesture(40, 2, 10.0);
+    movement.extend([[0.0; FEATURES]; 8]);
+    let matches = feed(&mut recognizer, &movement);
+    assert_eq!(matches.len(), 1, "{:?}", matches);
+    assert_eq!(matches[0].name, "up");
+    assert!(matches[0].distance < threshold);
+    let matches = feed(&mut recognizer, &gesture(32, 0, -10.0));
+    assert_eq!(matches.iter().map(|matched| matched.name.as_str()).collect::<Vec<_>>(), ["left"]);
+
+    // Enrollment from recorded examples
+    recognizer.start_recording("down");
+    feed(&mut recognizer, &gesture(28, 2, -10.0));
+    assert_eq!(recognizer.stop_recording(), Ok(28));
+    assert_eq!(recognizer.stop_recording(), Err(TemplateError::NotRecording));
+    assert_eq!(recognizer.enroll_recorded("down"), Ok(1));
+    assert_eq!(recognizer.enroll_recorded("down"), Err(TemplateError::NoExamples));
+    assert_eq!(recognizer.templates().len(), 3);
+    recognizer.forget("left").unwrap();
+    assert_eq!(recognizer.forget("left"), Err(TemplateError::UnknownTemplate));
+}
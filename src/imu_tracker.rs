@@ -1,23 +1,61 @@
 use core::time::Duration;
-use std::time::Instant;
 use imu_fusion::{Fusion, FusionAhrsSettings, FusionConvention, FusionEuler, FusionMatrix, FusionQuaternion, FusionVector};
 
+use crate::mounting::{self, Mounting};
+
+// Time constant of the user heading following the yaw, in seconds
+const USER_FOLLOW_TIME: f32 = 5.0;
+
+/* Frames of the linear acceleration, see `ImuTracker::linear_accel_in`:
+ * - sensor, the axes of the device once mounted, see `mounting`;
+ * - earth, z up and x towards the heading at startup;
+ * - user, z up and x towards where the user faces, taken as the heading of
+ *   the device followed slowly: "forward" turns with the user, not with a
+ *   quick twist of the device.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frame {
+    Sensor,
+    Earth,
+    User,
+}
+
+impl Frame {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "sensor" => Some(Frame::Sensor),
+            "earth" => Some(Frame::Earth),
+            "user" => Some(Frame::User),
+            _ => None,
+        }
+    }
+}
+
 pub struct ImuTracker {
-    time: Instant,
     pub fusion: Fusion,
     pub euler: FusionEuler,
     pub latest_delta: f32,
     pub earth_accel: FusionVector,
+    // In the earth frame, m/s²
     pub linear_accel: FusionVector,
+    pub sensor_linear_accel: FusionVector,
+    pub user_linear_accel: FusionVector,
+    pub user_follow_time: f32,
+    // Yaw the user faces, in degrees, from the first update
+    user_heading: Option<f32>,
+    mounting: Mounting,
+    // Misalignments from the sensor calibration, before the mounting
+    acc_misalignment: FusionMatrix,
+    gyr_misalignment: FusionMatrix,
 }
 
 impl ImuTracker {
-    pub fn new(sampling_period: Duration, now: Instant, gyr_range: f32,
+    pub fn new(sampling_period: Duration, gyr_range: f32,
                acc_misalignment: FusionMatrix, acc_offset: FusionVector,
                acc_sensitivity: FusionVector, gyr_offset: FusionVector) -> Self {
         // Set the gyroscope range in degrees/s
 
-        let sampling_freq: f32 = 1000_f32 / sampling_period.subsec_millis() as f32;
+        let sampling_freq: f32 = 1.0 / sampling_period.as_secs_f32();
 
         // Set AHRS algorithm settings
         let mut ahrs_settings = FusionAhrsSettings::new();
@@ -32,34 +70,66 @@ impl ImuTracker {
         ahrs_settings.recovery_trigger_period = 5;// * sampling_freq as i32;
         ahrs_settings.gyr_range = gyr_range;
 
-        let mut fusion = Fusion::new(sampling_freq as u32, ahrs_settings);
+        let mut fusion = Fusion::new(sampling_freq.round() as u32, ahrs_settings);
         fusion.acc_misalignment = acc_misalignment;
         fusion.acc_sensitivity = acc_sensitivity;
         fusion.acc_offset = acc_offset;
         fusion.gyr_offset = gyr_offset;
+        let gyr_misalignment = fusion.gyr_misalignment;
 
         Self {
-            time: now,
             fusion,
             euler: FusionEuler::zero(),
             latest_delta: 0f32,
             earth_accel: FusionVector::zero(),
             linear_accel: FusionVector::zero(),
+            sensor_linear_accel: FusionVector::zero(),
+            user_linear_accel: FusionVector::zero(),
+            user_follow_time: USER_FOLLOW_TIME,
+            user_heading: None,
+            mounting: Mounting::default(),
+            acc_misalignment,
+            gyr_misalignment,
+        }
+    }
+
+    pub fn user_heading(&self) -> Option<f32> {
+        self.user_heading
+    }
+
+    pub fn linear_accel_in(&self, frame: Frame) -> FusionVector {
+        match frame {
+            Frame::Sensor => self.sensor_linear_accel,
+            Frame::Earth => self.linear_accel,
+            Frame::User => self.user_linear_accel,
         }
     }
 
-    pub fn update(&mut self, time: Instant, imu_accel: FusionVector, imu_gyro: FusionVector) {
-        // Gets: acceleration in units of standard gravity
+    pub fn mounting(&self) -> Mounting {
+        self.mounting
+    }
+
+    /* Readings are brought into the device axes along with the misalignment
+     * correction, so fusion and everything after it work in those axes. Best
+     * set before the first update, the orientation otherwise jumps.
+     */
+    pub fn set_mounting(&mut self, mounting: Mounting) {
+        self.mounting = mounting;
+        self.fusion.acc_misalignment = mounting::multiply(&mounting.rotation(), &self.acc_misalignment);
+        self.fusion.gyr_misalignment = mounting::multiply(&mounting.rotation(), &self.gyr_misalignment);
+    }
+
+    pub fn update(&mut self, delta: f32, imu_accel: FusionVector, imu_gyro: FusionVector) {
+        // Gets: time step in seconds, as corrected by the sample timing
+        //       acceleration in units of standard gravity
         //       angular rotation in degrees/sec
-        let delta = time.duration_since(self.time).as_secs_f32();
-        self.time = time;
         self.latest_delta = delta;
         self.fusion.update_no_mag_by_duration_seconds(imu_gyro, imu_accel, delta);
 
-        self.compute(imu_accel, delta);
+        self.compute(imu_accel);
     }
 
-    pub fn compute(&mut self, imu_accel: FusionVector, delta_t: f32) {
+    pub fn compute(&mut self, imu_accel: FusionVector) {
         // Gets heading in units of degrees
         self.euler = self.fusion.euler();
 
@@ -68,15 +138,38 @@ impl ImuTracker {
         self.linear_acc = self.fusion.ahrs.linear_acc();
          */
         let q = self.fusion.quaternion();
-        self.earth_accel = rotate(imu_accel, q);
+        self.earth_accel = rotate(self.mounting.apply(imu_accel), q);
 
         self.linear_accel.x = self.earth_accel.x * 9.807;
         self.linear_accel.y = self.earth_accel.y * 9.807;
         self.linear_accel.z = (self.earth_accel.z - 1.) * 9.807;
+        self.sensor_linear_accel = rotate(self.linear_accel, conjugate(&q));
+
+        let yaw = self.euler.angle.yaw;
+        let heading = match self.user_heading {
+            Some(heading) => {
+                let follow = self.latest_delta / (self.user_follow_time + self.latest_delta);
+                wrap_degrees(heading + wrap_degrees(yaw - heading) * follow)
+            },
+            None => yaw,
+        };
+        self.user_heading = Some(heading);
+        let (sin, cos) = heading.to_radians().sin_cos();
+        self.user_linear_accel = FusionVector::new(
+            self.linear_accel.x * cos + self.linear_accel.y * sin,
+            self.linear_accel.y * cos - self.linear_accel.x * sin,
+            self.linear_accel.z,
+        );
     }
 
 }
 
+// Angle in degrees brought within [-180, 180)
+pub fn wrap_degrees(angle: f32) -> f32 {
+    let wrapped = (angle + 180.0) % 360.0;
+    if wrapped < 0.0 { wrapped + 180.0 } else { wrapped - 180.0 }
+}
+
 fn conjugate(q: &FusionQuaternion) -> FusionQuaternion {
     FusionQuaternion {
         w: q.w, x: -q.x, y: -q.y, z: -q.z
@@ -89,4 +182,47 @@ fn rotate(vec: FusionVector, q: FusionQuaternion) -> FusionVector {
     FusionVector {
         x: rot_q.x, y: rot_q.y, z: rot_q.z
     }
-}
\ No newline at end of file
+}
+#[test]
+fn test_linear_accel_frames() {
+    const DELTA: f32 = 0.005;
+    let mut tracker = ImuTracker::new(Duration::from_secs_f32(DELTA), 2000.0, FusionMatrix::identity(),
+                                      FusionVector::zero(), FusionVector::ones(), FusionVector::zero());
+    let run = |tracker: &mut ImuTracker, seconds: f32, accel: FusionVector, gyro: FusionVector| {
+        for _ in 0..(seconds / DELTA).round() as usize {
+            tracker.update(DELTA, accel, gyro);
+        }
+    };
+    let up = FusionVector::new(0.0, 0.0, 1.0);
+    let pushed = FusionVector::new(0.2, 0.0, 1.0);
+    // At rest, then pushed forward: every frame agrees
+    run(&mut tracker, 4.0, up, FusionVector::zero());
+    run(&mut tracker, 0.1, pushed, FusionVector::zero());
+    for frame in [Frame::Sensor, Frame::Earth, Frame::User] {
+        let accel = tracker.linear_accel_in(frame);
+        assert!((accel.x - 1.96).abs() < 0.05 && accel.y.abs() < 0.05 && accel.z.abs() < 0.05, "{:?}", frame);
+    }
+
+    // Turned a quarter to the left: the earth frame stays, the user one
+    // follows once the turn has lasted
+    run(&mut tracker, 1.0, up, FusionVector::new(0.0, 0.0, 90.0));
+    run(&mut tracker, 0.1, pushed, FusionVector::zero());
+    let (earth, user) = (tracker.linear_accel_in(Frame::Earth), tracker.linear_accel_in(Frame::User));
+    assert!(earth.x.abs() < 0.05 && (earth.y - 1.96).abs() < 0.05);
+    let heading = tracker.user_heading().unwrap();
+    assert!(heading > 2.0 && heading < 20.0, "{}", heading);
+    assert!(user.x > 0.1 && user.y > 1.8, "{:?}", (user.x, user.y));
+    run(&mut tracker, 25.0, up, FusionVector::zero());
+    run(&mut tracker, 0.1, pushed, FusionVector::zero());
+    let (sensor, user) = (tracker.linear_accel_in(Frame::Sensor), tracker.linear_accel_in(Frame::User));
+    assert!((user.x - 1.96).abs() < 0.05 && user.y.abs() < 0.05, "{:?}", (user.x, user.y));
+    assert!((sensor.x - 1.96).abs() < 0.05 && sensor.y.abs() < 0.05);
+    assert!((tracker.user_heading().unwrap() - tracker.euler.angle.yaw).abs() < 1.0);
+
+    // Round the back, where the yaw wraps
+    run(&mut tracker, 2.0, up, FusionVector::new(0.0, 0.0, 60.0));
+    run(&mut tracker, 40.0, up, FusionVector::zero());
+    assert!(wrap_degrees(tracker.user_heading().unwrap() - tracker.euler.angle.yaw).abs() < 1.0);
+    assert_eq!(Frame::parse("user"), Some(Frame::User));
+    assert_eq!(Frame::parse("body"), None);
+}
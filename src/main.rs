@@ -1,6 +1,7 @@
 use core::time::Duration;
-use std::num::NonZeroU32;
-use std::sync::mpsc::channel;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
 use std::time::Instant;
 
 use anyhow::{anyhow, Result};
@@ -9,11 +10,11 @@ use esp_idf_svc::{
     nvs::EspDefaultNvsPartition,
     mqtt::client::*
 };
-use esp_idf_svc::hal::spi::{SpiDeviceDriver, SpiDriverConfig};
-use esp_idf_svc::hal::task::notification::Notification;
+use esp_idf_svc::hal::spi::{SpiDeviceDriver, SpiDriver, SpiDriverConfig};
 use esp_idf_svc::hal::{
     delay::FreeRtos,
-    gpio::{AnyOutputPin, PinDriver},
+    gpio::{AnyOutputPin, Gpio10, Output, PinDriver},
+    modem::Modem,
     peripherals::Peripherals,
     units::FromValueType,
     //sys::EspError,
@@ -22,16 +23,56 @@ use esp_idf_svc::hal::{
 use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
 use embedded_svc::wifi::{AuthMethod, ClientConfiguration, Configuration};
 use esp_idf_svc::timer::EspTaskTimerService;
-use mpu9250::{ Mpu9250, MpuConfig };
 
 use imu_fusion::{FusionMatrix, FusionVector};
 mod imu_tracker;
-use imu_tracker::ImuTracker;
-
+use imu_tracker::{Frame, ImuTracker};
+mod mounting;
+use mounting::Mounting;
+mod sample_timing;
+use sample_timing::{SampleTiming, SystemClock};
+mod sample_trigger;
+use sample_trigger::{DataReadyTrigger, SampleTrigger, TimerTrigger};
+mod spi_registers;
+mod mpu_fifo;
+use mpu_fifo::{Drain, FifoSequencer, FIFO_SIZE, FRAME_SIZE};
+mod sensor;
+use sensor::{ImuSensor, SensorConfig};
+use sensor::mpu::Mpu9250Sensor;
+mod sensor_health;
+use sensor_health::HealthMonitor;
+mod sensor_recovery;
+use sensor_recovery::{RecoveryPolicy, SensorEvent, SensorSupervisor};
+#[cfg(test)]
+mod mock_bus;
+#[cfg(test)]
+mod fake_sensor;
+
+mod activity;
 mod analysis;
-use analysis::{Analysis, MovementDirection};
+mod gesture_classifier;
+mod gesture_features;
+mod gesture_model;
+mod gesture_templates;
+mod pose;
+mod rotation;
+mod tap;
+mod pipeline;
+use pipeline::Pipeline;
+mod combos;
+use combos::ComboMatcher;
+mod commands;
+mod stream;
+mod telemetry;
+use telemetry::TelemetrySink;
 mod state_machine;
-use state_machine::{SensorFSM, ConnectionFSM, ConnectionStatus};
+use state_machine::{SensorFSM, ConnectionFSM, LinkStatus};
+mod connection;
+use connection::{Backoff, ConnectionManager, LinkAction, LinkEvent};
+mod outbox;
+use outbox::{NvsStore, Outbound, Outbox, OverflowPolicy};
+mod event_sink;
+use event_sink::{EventSink, LineSink, MqttSink};
 
 // The constant `CONFIG` is auto-generated by `toml_config`.
 #[toml_cfg::toml_config]
@@ -50,6 +91,58 @@ pub struct Config {
     wifi_ssid: &'static str,
     #[default("")]
     wifi_psk: &'static str,
+    // "polled" reads one sample per timer tick, "fifo" drains the sensor FIFO in bursts
+    #[default("polled")]
+    imu_acquisition: &'static str,
+    // "timer" wakes up on a software timer, "data_ready" on the sensor INT pin (GPIO3)
+    #[default("timer")]
+    imu_trigger: &'static str,
+    // One of "mpu9250", "icm20948", "lsm6dsox"
+    #[default("mpu9250")]
+    imu_model: &'static str,
+    // Messages kept while the broker is unreachable
+    #[default("64")]
+    outbox_capacity: &'static str,
+    // What to do once full: "drop_oldest", "drop_newest" or "coalesce"
+    #[default("drop_oldest")]
+    outbox_policy: &'static str,
+    // Keep queued messages in flash across reboots
+    #[default("false")]
+    outbox_persist: &'static str,
+    // "mqtt" publishes to the broker, "serial" writes one message per line to
+    // the console, e.g. for a desktop app over USB, and "telemetry" writes
+    // framed records of every sample to the console for the live notebooks,
+    // see `telemetry`. Wi-Fi stays off unless "mqtt".
+    #[default("mqtt")]
+    event_sink: &'static str,
+    // Named sequences of events, e.g. "nod=vertical,vertical/0.6", see `combos`
+    #[default("")]
+    combos: &'static str,
+    // Rotation of the board in the enclosure, "euler:<roll>,<pitch>,<yaw>" in
+    // degrees or "quat:<w>,<x>,<y>,<z>", see `mounting`. Also found with the
+    // `mount level` command.
+    #[default("")]
+    mounting: &'static str,
+    // Frame movements are analysed in: "earth", "user" to follow where the
+    // user faces, or "sensor", see `imu_tracker::Frame`
+    #[default("earth")]
+    analysis_frame: &'static str,
+}
+
+// FIFO acquisition needs direct access to the MPU9250 registers, every other
+// setup goes through the sensor trait.
+enum ImuSource {
+    Polled(Box<dyn ImuSensor>),
+    Fifo(Mpu9250Sensor<SpiDeviceDriver<'static, SpiDriver<'static>>, PinDriver<'static, Gpio10, Output>>),
+}
+
+impl ImuSource {
+    fn sensor(&mut self) -> &mut dyn ImuSensor {
+        match self {
+            ImuSource::Polled(imu) => imu.as_mut(),
+            ImuSource::Fifo(mpu) => mpu,
+        }
+    }
 }
 
 fn main() -> Result<()> {
@@ -61,7 +154,6 @@ fn main() -> Result<()> {
     esp_idf_svc::log::EspLogger::initialize_default();
 
     let mut imu_state = SensorFSM::new();
-    let mut link_state = ConnectionFSM::new();
 
     let peripherals = Peripherals::take()?;
     let sys_loop = EspSystemEventLoop::take()?;
@@ -74,7 +166,6 @@ fn main() -> Result<()> {
     let mosi = peripherals.pins.gpio7;
     let cs = PinDriver::output(peripherals.pins.gpio10)?;
     imu_state.bootup_complete().map_err(|err| anyhow!("IMUError: {:?}", err))?;
-    link_state.bootup_complete().map_err(|err| anyhow!("LinkError: {:?}", err))?;
 
     let spi = SpiDeviceDriver::new_single(
         peripherals.spi2,
@@ -85,33 +176,50 @@ fn main() -> Result<()> {
         &SpiDriverConfig::new(),
         &SpiConfig::default().baudrate(1.MHz().into()),
     )?;
-    let gyro_rate = mpu9250::GyroTempDataRate::DlpfConf(mpu9250::Dlpf::_0);
-    let mut imu = Mpu9250::imu(
-        spi,
-        cs,
-        &mut delay,
-        MpuConfig::imu()
-            .gyro_temp_data_rate(gyro_rate)
-            .sample_rate_divisor(3)
-    ).map_err(|err| anyhow!("IMUError: {:?}", err))?;
-
-    let who_am_i = imu.who_am_i().map_err(|err| anyhow!("IMUError: {:?}", err))?;
-    log::info!("WHO_AM_I: 0x{:x}", who_am_i);
+    let fifo_mode = CONFIG.imu_acquisition == "fifo";
+    let imu_config = SensorConfig::default();
+    let mut imu = if fifo_mode {
+        if CONFIG.imu_model != "mpu9250" {
+            return Err(anyhow!("FIFO acquisition is not supported on {}", CONFIG.imu_model));
+        }
+        ImuSource::Fifo(Mpu9250Sensor::new(spi, cs, &mut delay, &imu_config)
+            .map_err(|err| anyhow!("IMUError: {:?}", err))?)
+    } else {
+        ImuSource::Polled(sensor::init(CONFIG.imu_model, spi, cs, &mut delay, &imu_config)
+            .map_err(|err| anyhow!("IMUError: {:?}", err))?)
+    };
+
+    let who_am_i = imu.sensor().who_am_i().map_err(|err| anyhow!("IMUError: {:?}", err))?;
+    log::info!("{} WHO_AM_I: 0x{:x}", CONFIG.imu_model, who_am_i);
+    sensor_health::validate_chip_id(CONFIG.imu_model, who_am_i).map_err(|err| anyhow!("IMUError: {:?}", err))?;
 
     let mut flag_serialize = PinDriver::output(peripherals.pins.gpio21)?;
     let mut flag_acquire = PinDriver::output(peripherals.pins.gpio20)?;
     flag_serialize.set_low()?;
     flag_acquire.set_low()?;
 
-    // Sets up periodic sampling notification
-    let notification = Notification::new();
-    let notifier = notification.notifier();
+    // Sets up the sampling trigger. Whenever the sensor paces the samples by itself
+    // (FIFO or data-ready interrupt) the sensor output period is the nominal one,
+    // and in FIFO mode the timer only schedules the bursts.
+    let data_ready = !fifo_mode && CONFIG.imu_trigger == "data_ready";
     const IMU_SAMPLE_PERIOD: Duration = Duration::from_millis(5);
+    const IMU_FIFO_DRAIN_PERIOD: Duration = Duration::from_millis(20);
+    const IMU_DATA_READY_TIMEOUT: Duration = Duration::from_millis(100);
+    let sample_period = if fifo_mode || data_ready {
+        imu.sensor().sample_period()
+    } else {
+        IMU_SAMPLE_PERIOD
+    };
+    let wakeup_period = if fifo_mode { IMU_FIFO_DRAIN_PERIOD } else { IMU_SAMPLE_PERIOD };
     let timer_service = EspTaskTimerService::new()?;
-    let callback_timer = timer_service.timer(move || unsafe {
-        notifier.notify_and_yield(NonZeroU32::new(1).unwrap());
-    })?;
-    callback_timer.every(IMU_SAMPLE_PERIOD)?;
+    let mut trigger: Box<dyn SampleTrigger> = if data_ready {
+        imu.sensor().enable_data_ready().map_err(|err| anyhow!("IMUError: {:?}", err))?;
+        Box::new(DataReadyTrigger::new(peripherals.pins.gpio3, IMU_DATA_READY_TIMEOUT)?)
+    } else {
+        Box::new(TimerTrigger::new(&timer_service, wakeup_period)?)
+    };
+    log::info!("Sampling every {:?} in {} mode, triggered by {}", sample_period,
+               CONFIG.imu_acquisition, CONFIG.imu_trigger);
 
     /* Test calibration function. As expected, it cannot account for earth's gravity
     let offsets: [f32; 3] = imu.calibrate_at_rest(&mut delay).map_err(|err| anyhow!("Error: {:?}", err))?;
@@ -123,36 +231,200 @@ fn main() -> Result<()> {
     let acc_offset = FusionVector::new(0.0246591f32, -0.00429982f32, 0.137597f32);
     let acc_sensitivity = FusionVector::ones();
     let gyr_offset = FusionVector::new(1.275, 1.902, -1.202);
-    let mut tracker = ImuTracker::new(IMU_SAMPLE_PERIOD, Instant::now(), 2000.0f32,
+    let mut tracker = ImuTracker::new(sample_period, 2000.0f32,
                                       acc_misalignment, acc_sensitivity, acc_offset, gyr_offset);
+    let mounting = Mounting::parse(CONFIG.mounting)
+        .ok_or_else(|| anyhow!("Unknown mounting {}", CONFIG.mounting))?;
+    tracker.set_mounting(mounting);
 
     imu_state.peripherals_complete().map_err(|err| anyhow!("IMUError: {:?}", err))?;
+    // Calibration is fixed for now, see the constants above
+    imu_state.calibration_complete().map_err(|err| anyhow!("IMUError: {:?}", err))?;
+
+    // Messages to publish, queued until the broker is reachable
+    let (tx, rx) = channel::<Outbound>();
+    // Payloads received on the command topic, see `commands`
+    let (command_tx, command_rx) = channel::<Vec<u8>>();
+
+    let mut sink: Box<dyn EventSink + Send> = match CONFIG.event_sink {
+        "mqtt" => Box::new(connect_mqtt(peripherals.modem, sys_loop.clone(), nvs.clone(), tx.clone(), command_tx)?),
+        "serial" => Box::new(LineSink::new(std::io::stdout(), CONFIG.mqtt_id)),
+        "telemetry" => Box::new(TelemetrySink::new(std::io::stdout())),
+        other => return Err(anyhow!("Unknown event sink {}", other)),
+    };
+
+    let capacity: usize = CONFIG.outbox_capacity.parse().ok().filter(|capacity| *capacity > 0)
+        .ok_or_else(|| anyhow!("Bad outbox capacity {}", CONFIG.outbox_capacity))?;
+    let policy = OverflowPolicy::parse(CONFIG.outbox_policy)
+        .ok_or_else(|| anyhow!("Unknown outbox policy {}", CONFIG.outbox_policy))?;
+    let mut outbox = Outbox::new(capacity, policy);
+    if CONFIG.outbox_persist == "true" {
+        outbox = outbox.with_store(Box::new(NvsStore::new(nvs.clone())?))?;
+        log::info!("Restored {} queued messages", outbox.len());
+    }
+
+    // Background task sending samples, queueing them while the sink is not ready
+    std::thread::Builder::new()
+        //.stack_size(8192)
+        .name(String::from("sink_q"))
+        .spawn(move || {
+            log::info!("Awaiting samples to send");
+            const FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+            loop {
+                match rx.recv_timeout(FLUSH_INTERVAL) {
+                    Ok(message) => outbox.push(message),
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+                if let Err(error) = sink.maintain() {
+                    log::warn!("Event sink error: {:?}", error);
+                }
+                if let Err(error) = outbox.flush(sink.as_mut()) {
+                    log::warn!("Error saving queued messages: {:?}", error);
+                }
+            }
+            if let Err(error) = outbox.save() {
+                log::warn!("Error saving queued messages: {:?}", error);
+            }
+            log::info!("Sink thread closing...");
+        })?;
+
+    let mut timing = SampleTiming::new(sample_period);
+    let health = HealthMonitor::new(imu.sensor().config());
+    let mut pipeline = Pipeline::new(tracker, health, sample_period, tx.clone());
+    pipeline.set_telemetry(CONFIG.event_sink == "telemetry");
+    let combos = combos::parse(CONFIG.combos).map_err(|err| anyhow!("Bad combos {}: {:?}", CONFIG.combos, err))?;
+    pipeline.combos = ComboMatcher::new(combos);
+    pipeline.analysis_frame = Frame::parse(CONFIG.analysis_frame)
+        .ok_or_else(|| anyhow!("Unknown analysis frame {}", CONFIG.analysis_frame))?;
+
+    // Read failures are retried and the sensor reinitialized if they persist,
+    // only an unrecoverable sensor stops the acquisition
+    let mut supervisor = SensorSupervisor::new(imu_state, RecoveryPolicy::default());
+
+    let mut imu = match imu {
+        ImuSource::Polled(imu) => imu,
+        ImuSource::Fifo(mpu) => {
+            let mut fifo = mpu.into_fifo(&mut delay).map_err(|err| anyhow!("IMUError: {:?}", err))?;
+            let mut sequencer = FifoSequencer::new(sample_period);
+            let mut burst = Vec::with_capacity(FIFO_SIZE);
+            let mut samples = Vec::with_capacity(FIFO_SIZE / FRAME_SIZE);
+            while !supervisor.is_faulted() {
+                trigger.wait()?;
+                for payload in command_rx.try_iter() {
+                    tx.send(Outbound::Ack(commands::execute(&payload, &mut supervisor, &mut pipeline).to_string()))?;
+                }
+                flag_acquire.set_high()?;
+                burst.clear();
+                let drain = fifo.supervised_drain(&mut supervisor, &mut delay, &mut burst)
+                    .map_err(|err| anyhow!("IMUError: {:?}", err))?;
+                let drained_at = Instant::now();
+                flag_acquire.set_low()?;
+                for event in supervisor.take_events() {
+                    log::warn!("IMU {}", event);
+                    // Setting the FIFO up again emptied it
+                    if event == SensorEvent::Recovered {
+                        sequencer.reset();
+                    }
+                    tx.send(Outbound::Fault(event.to_string()))?;
+                }
+                // Nothing while paused either, the FIFO overflows meanwhile
+                let Some(drain) = drain else {
+                    continue;
+                };
+                if drain == Drain::Overflow {
+                    sequencer.overflowed();
+                    log::warn!("IMU FIFO overflow ({} so far)", sequencer.overflows);
+                    continue;
+                }
+
+                samples.clear();
+                sequencer.push(&burst, drained_at, &mut samples);
+                for sample in samples.iter() {
+                    let tick = timing.tick_at(sample.time);
+                    if tick.dropped > 0 {
+                        log::warn!("Dropped {} samples ({} so far)", tick.dropped, timing.stats.dropped);
+                    }
+                    let scaled = fifo.scale(sample);
+                    pipeline.process(tick.delta, &scaled)?;
+                }
+            }
+            stay_faulted()
+        },
+    };
+
+    while !supervisor.is_faulted() {
+        trigger.wait()?;
+        for payload in command_rx.try_iter() {
+            tx.send(Outbound::Ack(commands::execute(&payload, &mut supervisor, &mut pipeline).to_string()))?;
+        }
+        let tick = timing.tick(&SystemClock);
+        if tick.dropped > 0 {
+            log::warn!("Dropped {} samples ({} so far)", tick.dropped, timing.stats.dropped);
+        }
+        flag_acquire.set_high()?;
+        let sample = supervisor.sample(imu.as_mut(), &mut delay).map_err(|err| anyhow!("IMUError: {:?}", err))?;
+        flag_acquire.set_low()?;
+        for event in supervisor.take_events() {
+            log::warn!("IMU {}", event);
+            tx.send(Outbound::Fault(event.to_string()))?;
+        }
+        if let Some(sample) = sample {
+            pipeline.process(tick.delta, &sample)?;
+        }
+    }
+
+    stay_faulted()
+}
+
+// Keeps the device connected, so that the fault is visible and reported
+fn stay_faulted() -> ! {
+    log::error!("IMU faulted, sampling stopped");
+    loop {
+        std::thread::sleep(Duration::from_secs(60));
+    }
+}
 
-    // Connect to the Wi-Fi network
+// Starts bringing up the Wi-Fi network and the broker connection, the
+// connection task keeps them up from then on
+fn connect_mqtt(modem: Modem, sys_loop: EspSystemEventLoop, nvs: EspDefaultNvsPartition,
+                tx: Sender<Outbound>, command_tx: Sender<Vec<u8>>) -> Result<MqttSink> {
     log::info!("SSID: {}", CONFIG.wifi_ssid);
     let mut wifi = BlockingWifi::wrap(
-        EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs.clone()))?,
-        sys_loop.clone(),
+        EspWifi::new(modem, sys_loop.clone(), Some(nvs))?,
+        sys_loop,
     )?;
-    link_state.peripherals_complete().map_err(|err| anyhow!("LinkError: {:?}", err))?;
-
-    connect_wifi(&mut wifi)?;
-    log::info!("IP: {}", wifi.wifi().sta_netif().get_ip_info()?.ip);
+    configure_wifi(&mut wifi)?;
+
+    const WIFI_RETRY_BASE: Duration = Duration::from_secs(1);
+    const WIFI_RETRY_MAX: Duration = Duration::from_secs(60);
+    const MQTT_RECONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+    let backoff = Backoff::new(WIFI_RETRY_BASE, WIFI_RETRY_MAX)
+        .with_seed(unsafe { esp_idf_svc::sys::esp_random() });
+    let manager = ConnectionManager::new(ConnectionFSM::new(), backoff, MQTT_RECONNECT_TIMEOUT);
+    let observer = manager.mqtt_observer();
+    let (link_tx, link_rx) = channel::<LinkEvent>();
+    std::thread::Builder::new()
+        .name(String::from("link"))
+        .spawn(move || run_connection(wifi, manager, link_rx, tx))?;
 
     let mqtt_url: String = format!("mqtt://{}:{}", CONFIG.mqtt_host, CONFIG.mqtt_port);
-    let (mut client, mut conn) = EspMqttClient::new(
+    let (client, mut conn) = EspMqttClient::new(
         &mqtt_url,
         &MqttClientConfiguration {
             client_id: Some(CONFIG.mqtt_id),
             password: Some(CONFIG.mqtt_pass),
             username: Some(CONFIG.mqtt_user),
-            //reconnect_timeout: Some(Duration::from_millis(200)),
+            reconnect_timeout: Some(MQTT_RECONNECT_TIMEOUT),
             ..Default::default()
         },
     )?;
 
-    // Background task for handling MQTT events
-    let observer = link_state.get_observer();
+    // Background task for handling MQTT events. Errors are reported to the
+    // connection task, the client keeps reconnecting by itself, and the sink
+    // subscribes again to the commands after each connection.
+    let subscribe = Arc::new(AtomicBool::new(false));
+    let connected = subscribe.clone();
     std::thread::Builder::new()
         //.stack_size(8192)
         .name(String::from("mqtt_ev"))
@@ -161,20 +433,24 @@ fn main() -> Result<()> {
             while let Ok(event) = conn.next() {
                 match event.payload() {
                     EventPayload::Connected(_) => {
-                        link_state.connected().map_err(|err| anyhow!("LinkError: {:?}", err))?;
+                        connected.store(true, Ordering::Relaxed);
+                        link_tx.send(LinkEvent::MqttUp)?;
                         log::info!("Connected to broker!");
                     },
                     EventPayload::Disconnected => {
-                        link_state.disconnected().map_err(|err| anyhow!("LinkError: {:?}", err))?;
+                        link_tx.send(LinkEvent::MqttDown)?;
                         log::info!("Disconnected from broker!");
                     },
                     EventPayload::Published(_) => {
                         // Do nothing with this event.
                         continue;
                     },
+                    EventPayload::Received { topic: Some(commands::TOPIC), data, .. } => {
+                        command_tx.send(data.to_vec())?;
+                    },
                     EventPayload::Error(error) => {
+                        link_tx.send(LinkEvent::MqttError)?;
                         log::error!("[MQTT] Error: {:?}", error);
-                        break;
                     },
                     _ => {
                         log::info!("[MQTT] Event: {:?}", event.payload());
@@ -185,72 +461,12 @@ fn main() -> Result<()> {
             Ok(())
         })?;
 
-    loop {
-        let st = observer.lock().unwrap();
-        if *st == ConnectionStatus::Connected {
-            break;
-        }
-        else {
-            std::thread::sleep(Duration::from_millis(100));
-            log::info!("Waiting for connection to broker...");
-        }
-    }
-
-    while let Err(error) = client.subscribe("commands", QoS::AtLeastOnce) {
-        log::warn!("MQTT error: {:?}", error);
-        std::thread::sleep(Duration::from_millis(100));
-    }
-    log::info!("Subscribed to topic!");
-
-    // Background task for immediate sending of samples over MQTT
-    let (tx, rx) = channel::<Vec<u8>>();
-    std::thread::Builder::new()
-        //.stack_size(8192)
-        .name(String::from("mqtt_q"))
-        .spawn(move || {
-            log::info!("Awaiting samples to send");
-            let event_topic = format!("{}/event", CONFIG.mqtt_id);
-            while let Ok(payload) = rx.recv() {
-                if let Err(e) = client.publish(&event_topic,
-                                                         QoS::AtLeastOnce,
-                                                  false,
-                                                         payload.as_slice()) {
-                    log::warn!("Error sending sample! {e:?}");
-                }
-            }
-            log::info!("MQTT thread closing...");
-        })?;
-
-    let mut id = 1u32;
-    let mut last = Instant::now();
-    let mut analysis = Analysis::default();
-    let mut direction: Option<MovementDirection> = None;
-    loop {
-        notification.wait(esp_idf_svc::hal::delay::BLOCK);
-        flag_acquire.set_high()?;
-        /* Getting unscaled readings:
-        let all = imu.unscaled_all::<[i16; 3]>()
-        */
-        // TODO read up on the "turbofish" operator below
-        let all = imu.all::<[f32; 3]>().map_err(|err| anyhow!("Error: {:?}", err))?;
-        let now = Instant::now();
-        flag_acquire.set_low()?;
-        let imu_gyro = FusionVector::new(all.gyro[0], all.gyro[1],all.gyro[2]) * (180. / core::f32::consts::PI);
-        let imu_accel = FusionVector::new(all.accel[0],all.accel[1],all.accel[2]) * (1. / mpu9250::G);
-        tracker.update(now, imu_accel, imu_gyro);
-
-        let new_direction = analysis.add_measurement(tracker.linear_accel);
-        if id % 50 == 0 {
-            if let Some(dir) = new_direction {
-                println!("{} {:?}", id, dir);
-		        tx.send(vec![0x30 + dir.as_payload()])?;
-            }
-        }
-        id += 1;
-    }
+    // Without waiting for the broker: sampling starts right away and the
+    // outbox holds the messages until the sink is ready
+    Ok(MqttSink::new(client, CONFIG.mqtt_id, observer, subscribe))
 }
 
-fn connect_wifi(wifi: &mut BlockingWifi<EspWifi<'static>>) -> Result<()> {
+fn configure_wifi(wifi: &mut BlockingWifi<EspWifi<'static>>) -> Result<()> {
     let wifi_configuration: Configuration = Configuration::Client(ClientConfiguration {
         ssid: CONFIG.wifi_ssid.parse().map_err(|err| anyhow!("Error: {:?}", err))?,
         password: CONFIG.wifi_psk.parse().map_err(|err| anyhow!("Error: {:?}", err))?,
@@ -273,20 +489,55 @@ fn connect_wifi(wifi: &mut BlockingWifi<EspWifi<'static>>) -> Result<()> {
     }
     */
 
-    log::info!("Connecting to Wifi...");
+    Ok(())
+}
+
+// Connection task: owns the Wi-Fi driver, connects it whenever the manager asks
+// to and watches for it dropping. Also receives the MQTT client events.
+fn run_connection(mut wifi: BlockingWifi<EspWifi<'static>>, mut manager: ConnectionManager,
+                  events: Receiver<LinkEvent>, tx: Sender<Outbound>) -> Result<()> {
+    const WIFI_POLL_PERIOD: Duration = Duration::from_secs(1);
+
+    manager.start(Instant::now());
     loop {
-        match wifi.connect() {
-            Ok(_) => break,
-            Err(e) => {
-                log::warn!("Could not connect ({:?})! Attempting again...", e);
-            }
+        let timeout = manager.next_deadline()
+            .map_or(WIFI_POLL_PERIOD, |deadline| deadline.saturating_duration_since(Instant::now()))
+            .min(WIFI_POLL_PERIOD);
+        match events.recv_timeout(timeout) {
+            Ok(event) => {
+                manager.handle(event, Instant::now()).map_err(|err| anyhow!("LinkError: {:?}", err))?;
+                if event == LinkEvent::MqttUp {
+                    let metrics = manager.metrics(Instant::now());
+                    log::info!("Link metrics: {}", metrics);
+                    tx.send(Outbound::Link(metrics.to_string()))?;
+                }
+            },
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
         }
-    }
-    log::info!("Wifi connected");
 
-    wifi.wait_netif_up()?;
-    log::info!("Wifi enumerated");
+        if manager.wifi_status() == LinkStatus::Up && !wifi.is_connected()? {
+            log::warn!("Wifi connection lost");
+            manager.handle(LinkEvent::WifiDown, Instant::now()).map_err(|err| anyhow!("LinkError: {:?}", err))?;
+        }
 
-    Ok(())
+        while let Some(action) = manager.poll(Instant::now()).map_err(|err| anyhow!("LinkError: {:?}", err))? {
+            match action {
+                LinkAction::ConnectWifi => {
+                    log::info!("Connecting to Wifi...");
+                    let event = match wifi.connect().and_then(|_| wifi.wait_netif_up()) {
+                        Ok(()) => {
+                            log::info!("Wifi connected, IP: {}", wifi.wifi().sta_netif().get_ip_info()?.ip);
+                            LinkEvent::WifiUp
+                        },
+                        Err(e) => {
+                            log::warn!("Could not connect ({:?})!", e);
+                            LinkEvent::WifiFailed
+                        },
+                    };
+                    manager.handle(event, Instant::now()).map_err(|err| anyhow!("LinkError: {:?}", err))?;
+                },
+            }
+        }
+    }
 }
-
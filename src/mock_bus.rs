@@ -0,0 +1,285 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+
+use embedded_hal::blocking::{delay::DelayMs, spi};
+use embedded_hal::digital::v2::OutputPin;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MockError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transaction {
+    Read { bank: u8, register: u8, len: usize },
+    Write { bank: u8, register: u8, data: Vec<u8> },
+}
+
+// Host stand-in for a register-based SPI device. A transaction lasts while chip
+// select is low: its first byte is the register address (MSB set for reads),
+// and the following bytes are read from or written to consecutive registers.
+#[derive(Default)]
+struct Device {
+    registers: HashMap<(u8, u8), u8>,
+    // Values returned by the next reads of a register, before falling back to its content
+    scripted: HashMap<(u8, u8), VecDeque<u8>>,
+    // Registers that do not auto-increment the address, like FIFO data ports
+    streams: HashSet<(u8, u8)>,
+    // Register that selects the active bank, if the device has one
+    bank_select: Option<u8>,
+    bank: u8,
+    transactions: Vec<Transaction>,
+    current: Option<Transaction>,
+    selected: bool,
+    address: Option<u8>,
+    failed: bool,
+    fail_next: usize,
+    failing_registers: HashSet<u8>,
+}
+
+impl Device {
+    fn select(&mut self) {
+        // A previous transaction aborted on error may have left chip select low
+        self.current = None;
+        self.selected = true;
+        self.address = None;
+        self.failed = self.fail_next > 0;
+        self.fail_next = self.fail_next.saturating_sub(1);
+    }
+
+    fn deselect(&mut self) {
+        self.selected = false;
+        if let Some(transaction) = self.current.take() {
+            self.transactions.push(transaction);
+        }
+    }
+
+    fn exchange(&mut self, byte: u8) -> Result<u8, MockError> {
+        assert!(self.selected, "SPI traffic with chip select high");
+        if self.failed {
+            return Err(MockError);
+        }
+
+        let Some(address) = self.address else {
+            let register = byte & 0x7F;
+            if self.failing_registers.contains(&register) {
+                self.failed = true;
+                return Err(MockError);
+            }
+            self.address = Some(register);
+            self.current = Some(if byte & 0x80 != 0 {
+                Transaction::Read { bank: self.bank, register, len: 0 }
+            } else {
+                Transaction::Write { bank: self.bank, register, data: Vec::new() }
+            });
+            return Ok(0);
+        };
+
+        let key = (self.bank, address);
+        if !self.streams.contains(&key) {
+            self.address = Some(address.wrapping_add(1));
+        }
+        match self.current.as_mut() {
+            Some(Transaction::Read { len, .. }) => {
+                *len += 1;
+                let scripted = self.scripted.get_mut(&key).and_then(|values| values.pop_front());
+                Ok(scripted.unwrap_or_else(|| *self.registers.get(&key).unwrap_or(&0)))
+            },
+            Some(Transaction::Write { data, .. }) => {
+                data.push(byte);
+                if self.bank_select == Some(address) {
+                    self.bank = byte;
+                } else {
+                    self.registers.insert(key, byte);
+                }
+                Ok(0)
+            },
+            None => unreachable!(),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct MockBus {
+    device: Rc<RefCell<Device>>,
+}
+
+impl MockBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_bank_select(register: u8) -> Self {
+        let bus = Self::new();
+        bus.device.borrow_mut().bank_select = Some(register);
+        bus
+    }
+
+    pub fn spi(&self) -> MockSpi {
+        MockSpi { bus: self.clone() }
+    }
+
+    pub fn cs(&self) -> MockCs {
+        MockCs { bus: self.clone() }
+    }
+
+    pub fn set(&self, bank: u8, register: u8, value: u8) {
+        self.device.borrow_mut().registers.insert((bank, register), value);
+    }
+
+    pub fn get(&self, bank: u8, register: u8) -> u8 {
+        *self.device.borrow().registers.get(&(bank, register)).unwrap_or(&0)
+    }
+
+    pub fn script(&self, bank: u8, register: u8, values: &[u8]) {
+        self.device.borrow_mut().scripted.entry((bank, register)).or_default().extend(values);
+    }
+
+    pub fn set_stream(&self, bank: u8, register: u8) {
+        self.device.borrow_mut().streams.insert((bank, register));
+    }
+
+    // The next `count` transactions fail, without reaching the device
+    pub fn fail_next(&self, count: usize) {
+        self.device.borrow_mut().fail_next = count;
+    }
+
+    // Every access to `register` fails until cleared
+    pub fn fail_register(&self, register: u8) {
+        self.device.borrow_mut().failing_registers.insert(register);
+    }
+
+    pub fn clear_failures(&self) {
+        let mut device = self.device.borrow_mut();
+        device.fail_next = 0;
+        device.failing_registers.clear();
+    }
+
+    pub fn selected(&self) -> bool {
+        self.device.borrow().selected
+    }
+
+    pub fn transactions(&self) -> Vec<Transaction> {
+        self.device.borrow().transactions.clone()
+    }
+
+    // Every register write so far, as (bank, register, value)
+    pub fn writes(&self) -> Vec<(u8, u8, u8)> {
+        let mut writes = Vec::new();
+        for transaction in self.device.borrow().transactions.iter() {
+            if let Transaction::Write { bank, register, data } = transaction {
+                for (i, value) in data.iter().enumerate() {
+                    writes.push((*bank, register + i as u8, *value));
+                }
+            }
+        }
+        writes
+    }
+
+    pub fn clear_transactions(&self) {
+        self.device.borrow_mut().transactions.clear();
+    }
+}
+
+pub struct MockSpi {
+    bus: MockBus,
+}
+
+impl spi::Transfer<u8> for MockSpi {
+    type Error = MockError;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        let mut device = self.bus.device.borrow_mut();
+        for word in words.iter_mut() {
+            *word = device.exchange(*word)?;
+        }
+        Ok(words)
+    }
+}
+
+impl spi::Write<u8> for MockSpi {
+    type Error = MockError;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        let mut device = self.bus.device.borrow_mut();
+        for word in words {
+            device.exchange(*word)?;
+        }
+        Ok(())
+    }
+}
+
+// Chip select of the mocked device, delimits its transactions
+pub struct MockCs {
+    bus: MockBus,
+}
+
+impl OutputPin for MockCs {
+    type Error = MockError;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.bus.device.borrow_mut().select();
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.bus.device.borrow_mut().deselect();
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct PinState {
+    levels: Vec<bool>,
+    failing: bool,
+}
+
+// Standalone output pin, records every level it is driven to
+#[derive(Clone, Default)]
+pub struct MockPin {
+    state: Rc<RefCell<PinState>>,
+}
+
+impl MockPin {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_high(&self) -> bool {
+        self.state.borrow().levels.last().copied().unwrap_or(false)
+    }
+
+    pub fn levels(&self) -> Vec<bool> {
+        self.state.borrow().levels.clone()
+    }
+
+    pub fn set_failing(&self, failing: bool) {
+        self.state.borrow_mut().failing = failing;
+    }
+
+    fn drive(&mut self, level: bool) -> Result<(), MockError> {
+        let mut state = self.state.borrow_mut();
+        if state.failing {
+            return Err(MockError);
+        }
+        state.levels.push(level);
+        Ok(())
+    }
+}
+
+impl OutputPin for MockPin {
+    type Error = MockError;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.drive(false)
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.drive(true)
+    }
+}
+
+pub struct NoDelay;
+
+impl DelayMs<u8> for NoDelay {
+    fn delay_ms(&mut self, _ms: u8) {}
+}
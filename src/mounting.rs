@@ -0,0 +1,315 @@
+use imu_fusion::{FusionEuler, FusionMatrix, FusionQuaternion, FusionVector};
+
+/* Mounting of the board in the device: the rotation bringing readings in the
+ * sensor axes into the body axes of the device (z out of its face, y towards
+ * its top), for boards mounted rotated in their enclosure. `ImuTracker` applies
+ * it to the calibrated readings before fusion, so that the orientation and
+ * everything downstream are those of the device.
+ *
+ * Configured as `euler:<roll>,<pitch>,<yaw>` in degrees, with the conventions
+ * of `FusionEuler`, or `quat:<w>,<x>,<y>,<z>`, or found by `MountingCalibration`
+ * with the device lying face up.
+ */
+#[derive(Clone, Copy)]
+pub struct Mounting {
+    quaternion: FusionQuaternion,
+    rotation: FusionMatrix,
+}
+
+impl Default for Mounting {
+    fn default() -> Self {
+        Self::from_quaternion(FusionQuaternion::identity())
+    }
+}
+
+fn cross(a: FusionVector, b: FusionVector) -> FusionVector {
+    FusionVector::new(a.y * b.z - a.z * b.y, a.z * b.x - a.x * b.z, a.x * b.y - a.y * b.x)
+}
+
+// Product of two matrices, `a` applied last
+pub fn multiply(a: &FusionMatrix, b: &FusionMatrix) -> FusionMatrix {
+    let rows = [[a.xx, a.xy, a.xz], [a.yx, a.yy, a.yz], [a.zx, a.zy, a.zz]];
+    let columns = [[b.xx, b.yx, b.zx], [b.xy, b.yy, b.zy], [b.xz, b.yz, b.zz]];
+    let cell = |row: usize, column: usize| (0..3).map(|index| rows[row][index] * columns[column][index]).sum::<f32>();
+    FusionMatrix::new(cell(0, 0), cell(0, 1), cell(0, 2),
+                      cell(1, 0), cell(1, 1), cell(1, 2),
+                      cell(2, 0), cell(2, 1), cell(2, 2))
+}
+
+impl Mounting {
+    pub fn from_quaternion(quaternion: FusionQuaternion) -> Self {
+        let norm = (quaternion.w * quaternion.w + quaternion.x * quaternion.x
+            + quaternion.y * quaternion.y + quaternion.z * quaternion.z).sqrt();
+        let quaternion = FusionQuaternion {
+            w: quaternion.w / norm,
+            x: quaternion.x / norm,
+            y: quaternion.y / norm,
+            z: quaternion.z / norm,
+        };
+        Self { quaternion, rotation: FusionMatrix::from(quaternion) }
+    }
+
+    pub fn from_euler(roll: f32, pitch: f32, yaw: f32) -> Self {
+        let (sr, cr) = (roll.to_radians() / 2.0).sin_cos();
+        let (sp, cp) = (pitch.to_radians() / 2.0).sin_cos();
+        let (sy, cy) = (yaw.to_radians() / 2.0).sin_cos();
+        Self::from_quaternion(FusionQuaternion {
+            w: cr * cp * cy + sr * sp * sy,
+            x: sr * cp * cy - cr * sp * sy,
+            y: cr * sp * cy + sr * cp * sy,
+            z: cr * cp * sy - sr * sp * cy,
+        })
+    }
+
+    /* The shortest rotation bringing `gravity`, as read by the sensor with the
+     * device lying face up, onto the body z axis. The heading of the board in
+     * the device is left as it is, gravity says nothing of it. None without
+     * gravity to go by.
+     */
+    pub fn level(gravity: FusionVector) -> Option<Self> {
+        let norm = gravity.magnitude().sqrt();
+        if !norm.is_normal() {
+            return None;
+        }
+        let up = gravity * (1.0 / norm);
+        let axis = cross(up, FusionVector::new(0.0, 0.0, 1.0));
+        let w = 1.0 + up.z;
+        if w < 1e-6 {
+            // Upside down, half a turn about x
+            return Some(Self::from_euler(180.0, 0.0, 0.0));
+        }
+        Some(Self::from_quaternion(FusionQuaternion { w, x: axis.x, y: axis.y, z: axis.z }))
+    }
+
+    // `""` for none, see above
+    pub fn parse(spec: &str) -> Option<Self> {
+        if spec.is_empty() {
+            return Some(Self::default());
+        }
+        let (kind, values) = spec.split_once(':')?;
+        let values: Vec<f32> = values.split(',').map(|value| value.trim().parse().ok()).collect::<Option<_>>()?;
+        match (kind, values.as_slice()) {
+            ("euler", [roll, pitch, yaw]) => Some(Self::from_euler(*roll, *pitch, *yaw)),
+            ("quat", [w, x, y, z]) => {
+                let quaternion = FusionQuaternion { w: *w, x: *x, y: *y, z: *z };
+                (w * w + x * x + y * y + z * z > 0.0).then(|| Self::from_quaternion(quaternion))
+            },
+            _ => None,
+        }
+    }
+
+    pub fn rotation(&self) -> FusionMatrix {
+        self.rotation
+    }
+
+    pub fn euler(&self) -> FusionEuler {
+        self.quaternion.euler()
+    }
+
+    pub fn apply(&self, vector: FusionVector) -> FusionVector {
+        self.rotation * vector
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MountingError {
+    // The device moved during the calibration
+    Moving,
+}
+
+// Readings beyond these are movement rather than noise
+const STILL_GYRO: f32 = 5.0;
+const STILL_ACCEL: f32 = 0.1;
+
+/* Finds the mounting from `samples` readings of the device lying still, face
+ * up, see `Mounting::level`.
+ */
+pub struct MountingCalibration {
+    samples: usize,
+    gravity: FusionVector,
+    count: usize,
+}
+
+impl MountingCalibration {
+    pub fn new(samples: usize) -> Self {
+        Self { samples: samples.max(1), gravity: FusionVector::zero(), count: 0 }
+    }
+
+    // The outcome once enough samples are in, or as soon as the device moves
+    pub fn add_sample(&mut self, accel: FusionVector, gyro: FusionVector) -> Option<Result<Mounting, MountingError>> {
+        let still = gyro.magnitude().sqrt() < STILL_GYRO && (accel.magnitude().sqrt() - 1.0).abs() < STILL_ACCEL;
+        if !still {
+            return Some(Err(MountingError::Moving));
+        }
+        self.gravity += accel;
+        self.count += 1;
+        if self.count < self.samples {
+            return None;
+        }
+        Mounting::level(self.gravity).map(Ok)
+    }
+}
+
+#[cfg(test)]
+fn assert_close(actual: FusionVector, expected: FusionVector) {
+    let error = actual - expected;
+    assert!(error.magnitude() < 1e-6, "{:?} instead of {:?}", (actual.x, actual.y, actual.z),
+            (expected.x, expected.y, expected.z));
+}
+
+#[test]
+fn test_mounting() {
+    // Board upside down, then turned a quarter to the left
+    let flipped = Mounting::from_euler(180.0, 0.0, 0.0);
+    assert_close(flipped.apply(FusionVector::new(0.0, 0.0, -1.0)), FusionVector::new(0.0, 0.0, 1.0));
+    assert_close(flipped.apply(FusionVector::new(0.0, 1.0, 0.0)), FusionVector::new(0.0, -1.0, 0.0));
+    let turned = Mounting::from_euler(0.0, 0.0, 90.0);
+    assert_close(turned.apply(FusionVector::new(1.0, 0.0, 0.0)), FusionVector::new(0.0, 1.0, 0.0));
+    let euler = Mounting::from_euler(10.0, -20.0, 30.0).euler().angle;
+    assert!((euler.roll - 10.0).abs() < 1e-3 && (euler.pitch + 20.0).abs() < 1e-3 && (euler.yaw - 30.0).abs() < 1e-3);
+
+    let half = core::f32::consts::FRAC_1_SQRT_2;
+    let parsed = Mounting::parse("quat:0.7071068,0,0,0.7071068").unwrap();
+    assert_close(parsed.apply(FusionVector::new(1.0, 0.0, 0.0)), FusionVector::new(0.0, 1.0, 0.0));
+    assert_close(Mounting::parse("euler:0, 0, 90").unwrap().apply(FusionVector::new(half, half, 0.0)),
+                 FusionVector::new(-half, half, 0.0));
+    assert_close(Mounting::parse("").unwrap().apply(FusionVector::new(1.0, 2.0, 3.0)), FusionVector::new(1.0, 2.0, 3.0));
+    assert!(Mounting::parse("euler:0,90").is_none());
+    assert!(Mounting::parse("quat:0,0,0,0").is_none());
+    assert!(Mounting::parse("sideways").is_none());
+
+    let rotation = multiply(&turned.rotation(), &flipped.rotation());
+    assert_close(rotation * FusionVector::new(0.0, 1.0, 0.0), FusionVector::new(1.0, 0.0, 0.0));
+
+    // Board on its side in the enclosure, found while lying still
+    let mut calibration = MountingCalibration::new(50);
+    let mut outcome = None;
+    for _ in 0..50 {
+        outcome = calibration.add_sample(FusionVector::new(-0.98, 0.0, 0.05), FusionVector::new(0.5, -0.3, 0.1));
+    }
+    let level = outcome.unwrap().unwrap();
+    let up = level.apply(FusionVector::new(-0.98, 0.0, 0.05));
+    assert!(up.x.abs() < 1e-4 && up.y.abs() < 1e-4 && up.z > 0.98, "{:?}", (up.x, up.y, up.z));
+    assert_close(Mounting::level(FusionVector::new(0.0, 0.0, -1.0)).unwrap().apply(FusionVector::new(0.0, 0.0, -1.0)),
+                 FusionVector::new(0.0, 0.0, 1.0));
+    let mut calibration = MountingCalibration::new(50);
+    assert_eq!(calibration.add_sample(FusionVector::new(0.0, 0.0, 1.0), FusionVector::new(40.0, 0.0, 0.0)).map(|outcome| outcome.err()),
+               Some(Some(MountingError::Moving)));
+}
+
+// Published events from body readings of the device: resting face up, moved
+// up and down, twisted, moved sideways, then laid upright
+#[cfg(test)]
+fn mounted_events(mounting: Mounting, reading: Mounting) -> Vec<String> {
+    use core::time::Duration;
+
+    use crate::imu_tracker::ImuTracker;
+    use crate::outbox::Outbound;
+    use crate::pipeline::Pipeline;
+    use crate::sensor::{ImuSample, SensorConfig};
+    use crate::sensor_health::HealthMonitor;
+
+    const DELTA: f32 = 0.005;
+    let period = Duration::from_secs_f32(DELTA);
+    let mut tracker = ImuTracker::new(period, 2000.0, FusionMatrix::identity(), FusionVector::zero(),
+                                      FusionVector::ones(), FusionVector::zero());
+    tracker.set_mounting(mounting);
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut pipeline = Pipeline::new(tracker, HealthMonitor::new(&SensorConfig::default()), period, tx);
+    pipeline.classifier = None;
+
+    let still = |seconds: f32, up: FusionVector| vec![(up, FusionVector::zero()); (seconds / DELTA) as usize];
+    let swinging = |seconds: f32, axis: FusionVector| (0..(seconds / DELTA) as usize).map(|index| {
+        let phase = 2.0 * core::f32::consts::PI * 2.0 * index as f32 * DELTA;
+        (FusionVector::new(0.0, 0.0, 1.0) + axis * (0.5 * phase.sin()), FusionVector::zero())
+    }).collect::<Vec<_>>();
+    let face_up = FusionVector::new(0.0, 0.0, 1.0);
+    let body = still(4.0, face_up).into_iter()
+        .chain(swinging(2.0, FusionVector::new(0.0, 0.0, 1.0)))
+        .chain(still(1.0, face_up))
+        .chain(vec![(face_up, FusionVector::new(0.0, 0.0, -180.0)); 80])
+        .chain(still(1.0, face_up))
+        .chain(swinging(2.0, FusionVector::new(1.0, 0.0, 0.0)))
+        .chain(still(1.0, face_up))
+        .chain(still(1.0, FusionVector::new(0.0, 1.0, 0.0)));
+    for (accel, gyro) in body {
+        let sample = ImuSample { accel: reading.apply(accel), gyro: reading.apply(gyro) };
+        pipeline.process(DELTA, &sample).unwrap();
+    }
+    rx.try_iter().filter_map(|message| match message {
+        Outbound::Event(payload) => String::from_utf8(payload).ok().map(|payload| payload[..1].to_string()),
+        Outbound::Pose(pose) | Outbound::Rotation(pose) | Outbound::Tap(pose) => Some(pose),
+        _ => None,
+    }).collect()
+}
+
+#[test]
+fn test_mounted_events() {
+    let level = mounted_events(Mounting::default(), Mounting::default());
+    // Vertical, then horizontal, see `MovementDirection::as_payload`
+    assert!(level.contains(&String::from("0")) && level.contains(&String::from("1")), "{:?}", level);
+    assert!(level.contains(&String::from("twist_cw")) && level.ends_with(&[String::from("upright")]), "{:?}", level);
+
+    // Readings of a board mounted rotated, brought back into the device axes
+    let mounting = Mounting::from_euler(30.0, -100.0, 40.0);
+    let inverse = Mounting::from_quaternion(FusionQuaternion {
+        w: mounting.quaternion.w,
+        x: -mounting.quaternion.x,
+        y: -mounting.quaternion.y,
+        z: -mounting.quaternion.z,
+    });
+    assert_eq!(mounted_events(mounting, inverse), level);
+    // Left as they are, moving up and down passes for moving sideways
+    let unmounted = mounted_events(Mounting::default(), inverse);
+    assert_eq!(unmounted.iter().position(|event| event == "0" || event == "1").map(|index| &unmounted[index]),
+               Some(&String::from("1")), "{:?}", unmounted);
+    assert_ne!(unmounted, level);
+}
+
+#[test]
+fn test_streamed_in_device_axes() {
+    use core::time::Duration;
+
+    use crate::imu_tracker::ImuTracker;
+    use crate::outbox::Outbound;
+    use crate::pipeline::Pipeline;
+    use crate::sensor::{ImuSample, SensorConfig};
+    use crate::sensor_health::HealthMonitor;
+    use crate::stream::{self, StreamConfig, StreamContent};
+    use crate::telemetry::Record;
+
+    // Board upside down in the device, which lies face up
+    let period = Duration::from_millis(5);
+    let mut tracker = ImuTracker::new(period, 2000.0, FusionMatrix::identity(), FusionVector::zero(),
+                                      FusionVector::ones(), FusionVector::zero());
+    tracker.set_mounting(Mounting::from_euler(180.0, 0.0, 0.0));
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut pipeline = Pipeline::new(tracker, HealthMonitor::new(&SensorConfig::default()), period, tx);
+    pipeline.set_stream(Some(StreamConfig { content: StreamContent::Raw, decimation: 1 }));
+    pipeline.set_telemetry(true);
+    let sample = ImuSample { accel: FusionVector::new(0.0, 0.0, -1.0), gyro: FusionVector::new(0.0, 10.0, 20.0) };
+    for _ in 0..stream::SAMPLES_PER_FRAME {
+        pipeline.process(0.005, &sample).unwrap();
+    }
+
+    let mut checked = 0;
+    for message in rx.try_iter() {
+        let (accel, gyro) = match message {
+            Outbound::Stream(frame) => {
+                let sample = stream::decode(&frame).unwrap().samples[0];
+                (sample.accel, sample.gyro)
+            },
+            Outbound::Telemetry(record) => match Record::decode(&record).unwrap() {
+                Record::Sample { accel, gyro, .. } => (accel, gyro),
+                _ => continue,
+            },
+            _ => continue,
+        };
+        let expected = [0.0, 0.0, 1.0, 0.0, -10.0, -20.0];
+        for (value, expected) in accel.iter().chain(&gyro).zip(expected) {
+            assert!((value - expected).abs() < 1e-3, "{:?}", (accel, gyro));
+        }
+        checked += 1;
+    }
+    assert_eq!(checked, stream::SAMPLES_PER_FRAME + 1);
+}
@@ -0,0 +1,335 @@
+use core::fmt::Debug;
+use core::time::Duration;
+use std::time::Instant;
+
+use embedded_hal::blocking::{delay::DelayMs, spi};
+use embedded_hal::digital::v2::OutputPin;
+
+use crate::sensor::{scale, ImuSample, SensorConfig};
+use crate::sensor_recovery::SensorSupervisor;
+use crate::spi_registers::{BusError, SpiRegisters};
+use crate::state_machine::FSMError;
+
+// MPU9250 registers involved in FIFO handling
+const FIFO_EN: u8 = 0x23;
+const INT_ENABLE: u8 = 0x38;
+const INT_STATUS: u8 = 0x3A;
+const USER_CTRL: u8 = 0x6A;
+const FIFO_COUNT_H: u8 = 0x72;
+const FIFO_R_W: u8 = 0x74;
+
+const USER_CTRL_FIFO_EN: u8 = 0x40;
+const USER_CTRL_FIFO_RST: u8 = 0x04;
+const FIFO_EN_GYRO_XYZ: u8 = 0x70;
+const FIFO_EN_ACCEL: u8 = 0x08;
+const INT_FIFO_OVERFLOW: u8 = 0x10;
+
+pub const FIFO_SIZE: usize = 512;
+// Sensors are written to the FIFO in register order: accel XYZ, then gyro XYZ,
+// every value being a big-endian i16.
+pub const FRAME_SIZE: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FifoSample {
+    pub seq: u32,
+    pub time: Instant,
+    pub accel: [i16; 3],
+    pub gyro: [i16; 3],
+}
+
+pub fn parse_frame(frame: &[u8]) -> ([i16; 3], [i16; 3]) {
+    assert!(frame.len() >= FRAME_SIZE);
+    let word = |i: usize| i16::from_be_bytes([frame[2 * i], frame[2 * i + 1]]);
+    ([word(0), word(1), word(2)], [word(3), word(4), word(5)])
+}
+
+/* Turns bursts of FIFO bytes into timestamped samples. Frames may be split
+ * across bursts, so incomplete trailing bytes are kept for the next one.
+ * Samples are placed on the sensor's sampling grid, which is anchored on the
+ * drain time (the newest frame can be at most one period old) and re-anchored
+ * whenever the grid drifts away from it by more than a period.
+ */
+pub struct FifoSequencer {
+    period: Duration,
+    partial: Vec<u8>,
+    next_seq: u32,
+    last_time: Option<Instant>,
+    pub overflows: u32,
+}
+
+impl FifoSequencer {
+    pub fn new(period: Duration) -> Self {
+        Self {
+            period,
+            partial: Vec::with_capacity(FRAME_SIZE),
+            next_seq: 0,
+            last_time: None,
+            overflows: 0,
+        }
+    }
+
+    pub fn push(&mut self, burst: &[u8], drained_at: Instant, out: &mut Vec<FifoSample>) {
+        self.partial.extend_from_slice(burst);
+        let frames = self.partial.len() / FRAME_SIZE;
+        if frames == 0 {
+            return;
+        }
+
+        let newest = match self.last_time {
+            Some(last) => {
+                let predicted = last + self.period * frames as u32;
+                let drift = if predicted > drained_at {
+                    predicted - drained_at
+                } else {
+                    drained_at - predicted
+                };
+                if drift > self.period { drained_at } else { predicted }
+            },
+            None => drained_at,
+        };
+
+        for (i, frame) in self.partial.chunks_exact(FRAME_SIZE).enumerate() {
+            let (accel, gyro) = parse_frame(frame);
+            out.push(FifoSample {
+                seq: self.next_seq,
+                time: newest - self.period * (frames - 1 - i) as u32,
+                accel,
+                gyro,
+            });
+            self.next_seq = self.next_seq.wrapping_add(1);
+        }
+        self.partial.drain(..frames * FRAME_SIZE);
+        self.last_time = Some(newest);
+    }
+
+    // Called after the FIFO was reset: buffered bytes are no longer aligned to frames
+    pub fn reset(&mut self) {
+        self.partial.clear();
+        self.last_time = None;
+    }
+
+    pub fn overflowed(&mut self) {
+        self.reset();
+        self.overflows += 1;
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Drain {
+    Data(usize),
+    Overflow,
+}
+
+pub struct Mpu9250Fifo<SPI, CS> {
+    regs: SpiRegisters<SPI, CS>,
+    accel_resolution: f32,
+    gyro_resolution: f32,
+}
+
+impl<SPI, CS, E, EO> Mpu9250Fifo<SPI, CS>
+    where SPI: spi::Write<u8, Error = E> + spi::Transfer<u8, Error = E>,
+          CS: OutputPin<Error = EO>
+{
+    // Takes over a bus on which the MPU9250 has already been configured
+    pub fn new(spi: SPI, cs: CS, config: &SensorConfig) -> Self {
+        Self {
+            regs: SpiRegisters::new(spi, cs),
+            accel_resolution: config.accel_range.full_scale() / 32768.0,
+            gyro_resolution: config.gyro_range.full_scale() / 32768.0,
+        }
+    }
+
+    pub fn release(self) -> (SPI, CS) {
+        self.regs.release()
+    }
+
+    pub fn enable<D: DelayMs<u8>>(&mut self, delay: &mut D) -> Result<(), BusError<E, EO>> {
+        self.regs.write(FIFO_EN, 0)?;
+        self.regs.modify(USER_CTRL, |r| (r & !USER_CTRL_FIFO_EN) | USER_CTRL_FIFO_RST)?;
+        delay.delay_ms(1);
+        self.regs.modify(USER_CTRL, |r| r | USER_CTRL_FIFO_EN)?;
+        self.regs.modify(INT_ENABLE, |r| r | INT_FIFO_OVERFLOW)?;
+        // Clear any stale overflow status before starting
+        self.regs.read(INT_STATUS)?;
+        self.regs.write(FIFO_EN, FIFO_EN_GYRO_XYZ | FIFO_EN_ACCEL)
+    }
+
+    pub fn count(&mut self) -> Result<usize, BusError<E, EO>> {
+        let mut buffer = [0u8; 2];
+        self.regs.read_many(FIFO_COUNT_H, &mut buffer)?;
+        Ok(u16::from_be_bytes([buffer[0] & 0x1F, buffer[1]]) as usize)
+    }
+
+    /* Appends every complete frame waiting in the FIFO to `buffer`. On overflow
+     * the oldest bytes have been overwritten and frame alignment is lost, so the
+     * FIFO is reset and nothing is read.
+     */
+    pub fn drain<D: DelayMs<u8>>(&mut self, delay: &mut D, buffer: &mut Vec<u8>)
+        -> Result<Drain, BusError<E, EO>>
+    {
+        let status = self.regs.read(INT_STATUS)?;
+        let count = self.count()?;
+        if status & INT_FIFO_OVERFLOW != 0 || count >= FIFO_SIZE {
+            self.enable(delay)?;
+            return Ok(Drain::Overflow);
+        }
+
+        let len = count - count % FRAME_SIZE;
+        let start = buffer.len();
+        buffer.resize(start + len, 0);
+        self.regs.read_many(FIFO_R_W, &mut buffer[start..])?;
+        Ok(Drain::Data(len))
+    }
+
+    /* `drain` through the supervisor, so that failures are retried, then the
+     * FIFO set up again, until the sensor is given up on. None without data,
+     * see `SensorSupervisor::sample`.
+     */
+    pub fn supervised_drain<D: DelayMs<u8>>(&mut self, supervisor: &mut SensorSupervisor, delay: &mut D,
+                                            buffer: &mut Vec<u8>) -> Result<Option<Drain>, FSMError>
+        where E: Debug,
+              EO: Debug
+    {
+        supervisor.supervise(self, delay, |fifo, delay| Ok(fifo.drain(delay, buffer)?),
+                             |fifo, delay| Ok(fifo.enable(delay)?))
+    }
+
+    pub fn scale(&self, sample: &FifoSample) -> ImuSample {
+        ImuSample {
+            accel: scale(sample.accel, self.accel_resolution),
+            gyro: scale(sample.gyro, self.gyro_resolution),
+        }
+    }
+}
+
+// FIFO contents captured with the board lying flat, ±2g / ±250dps ranges
+#[cfg(test)]
+const CAPTURED_FIFO: [u8; 42] = [
+    0x00, 0x64, 0xff, 0x38, 0x40, 0x1c, 0xff, 0xe8, 0x00, 0x31, 0xff, 0xdd,
+    0x00, 0x5c, 0xff, 0x40, 0x40, 0x08, 0xff, 0xea, 0x00, 0x2f, 0xff, 0xde,
+    0x00, 0x6a, 0xff, 0x34, 0x3f, 0xf4, 0xff, 0xe7, 0x00, 0x32, 0xff, 0xdc,
+    0x00, 0x60, 0xff, 0x3a, 0x40, 0x10,
+];
+
+#[test]
+fn test_parse_frame() {
+    let (accel, gyro) = parse_frame(&CAPTURED_FIFO[..FRAME_SIZE]);
+    assert_eq!(accel, [100, -200, 16412]);
+    assert_eq!(gyro, [-24, 49, -35]);
+}
+
+#[test]
+fn test_sequencer_split_bursts() {
+    let period = Duration::from_millis(4);
+    let mut sequencer = FifoSequencer::new(period);
+    let mut samples = Vec::new();
+    let t0 = Instant::now();
+
+    // Two and a half frames, then the rest of the third one
+    sequencer.push(&CAPTURED_FIFO[..30], t0, &mut samples);
+    assert_eq!(samples.len(), 2);
+    assert_eq!(samples[1].time, t0);
+    assert_eq!(samples[0].time, t0 - period);
+
+    sequencer.push(&CAPTURED_FIFO[30..36], t0 + period + Duration::from_micros(300), &mut samples);
+    assert_eq!(samples.len(), 3);
+    assert_eq!(samples[2].seq, 2);
+    assert_eq!(samples[2].accel, [106, -204, 16372]);
+    // Kept on the sampling grid despite the drain jitter
+    assert_eq!(samples[2].time, t0 + period);
+
+    // A trailing partial frame produces nothing yet
+    sequencer.push(&CAPTURED_FIFO[36..], t0 + 2 * period, &mut samples);
+    assert_eq!(samples.len(), 3);
+}
+
+#[test]
+fn test_sequencer_overflow_resyncs() {
+    let period = Duration::from_millis(4);
+    let mut sequencer = FifoSequencer::new(period);
+    let mut samples = Vec::new();
+    let t0 = Instant::now();
+
+    sequencer.push(&CAPTURED_FIFO[..40], t0, &mut samples);
+    assert_eq!(samples.len(), 3);
+
+    sequencer.overflowed();
+    let t1 = t0 + Duration::from_millis(200);
+    sequencer.push(&CAPTURED_FIFO[..24], t1, &mut samples);
+    assert_eq!(samples.len(), 5);
+    // The partial frame from before the overflow was discarded
+    assert_eq!(samples[3].accel, [100, -200, 16412]);
+    assert_eq!(samples[4].time, t1);
+    assert_eq!(samples[4].seq, 4);
+    assert_eq!(sequencer.overflows, 1);
+}
+
+#[test]
+fn test_sequencer_reanchors_on_drift() {
+    let period = Duration::from_millis(4);
+    let mut sequencer = FifoSequencer::new(period);
+    let mut samples = Vec::new();
+    let t0 = Instant::now();
+
+    sequencer.push(&CAPTURED_FIFO[..12], t0, &mut samples);
+    // One frame drained much later than the grid predicts
+    let t1 = t0 + Duration::from_millis(20);
+    sequencer.push(&CAPTURED_FIFO[12..24], t1, &mut samples);
+    assert_eq!(samples[1].time, t1);
+}
+
+#[test]
+fn test_failed_drains_are_supervised() {
+    use crate::mock_bus::{MockBus, NoDelay};
+    use crate::sensor_recovery::{RecoveryPolicy, SensorEvent};
+    use crate::state_machine::{SensorFSM, SensorStatus};
+
+    let bus = MockBus::new();
+    bus.set_stream(0, FIFO_R_W);
+    // Two frames waiting
+    bus.set(0, FIFO_COUNT_H + 1, 24);
+    let mut fifo = Mpu9250Fifo::new(bus.spi(), bus.cs(), &SensorConfig::default());
+    let mut fsm = SensorFSM::new();
+    fsm.bootup_complete().unwrap();
+    fsm.peripherals_complete().unwrap();
+    fsm.calibration_complete().unwrap();
+    let mut supervisor = SensorSupervisor::new(fsm, RecoveryPolicy { max_read_errors: 2, max_reinit_attempts: 2 });
+    let mut burst = Vec::new();
+    let mut drain = |supervisor: &mut SensorSupervisor| {
+        burst.clear();
+        fifo.supervised_drain(supervisor, &mut NoDelay, &mut burst).unwrap()
+    };
+
+    // A transient failure is retried
+    bus.fail_next(1);
+    assert_eq!(drain(&mut supervisor), None);
+    assert_eq!(supervisor.status(), SensorStatus::Degraded);
+    assert_eq!(drain(&mut supervisor), Some(Drain::Data(24)));
+    assert_eq!(supervisor.status(), SensorStatus::Sampling);
+
+    // Persistent ones set the FIFO up again, once the bus answers
+    bus.fail_register(INT_STATUS);
+    assert_eq!(drain(&mut supervisor), None);
+    assert_eq!(drain(&mut supervisor), None);
+    assert_eq!(supervisor.status(), SensorStatus::Recovering);
+    assert_eq!(drain(&mut supervisor), None);
+    bus.clear_failures();
+    bus.clear_transactions();
+    assert_eq!(drain(&mut supervisor), None);
+    assert!(bus.writes().contains(&(0, FIFO_EN, FIFO_EN_GYRO_XYZ | FIFO_EN_ACCEL)));
+    assert_eq!(supervisor.status(), SensorStatus::Sampling);
+    assert_eq!(drain(&mut supervisor), Some(Drain::Data(24)));
+    assert_eq!(supervisor.take_events()[3..], [
+        SensorEvent::Recovering { attempt: 1 },
+        SensorEvent::Recovering { attempt: 2 },
+        SensorEvent::Recovered,
+    ]);
+
+    // Until the sensor is given up on
+    bus.fail_register(INT_STATUS);
+    for _ in 0..10 {
+        assert_eq!(drain(&mut supervisor), None);
+    }
+    assert!(supervisor.is_faulted());
+    assert!(matches!(supervisor.take_events().last(), Some(SensorEvent::Faulted { .. })));
+}
@@ -0,0 +1,555 @@
+use core::time::Duration;
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+
+use crate::event_sink::EventSink;
+
+// Messages handed over to the MQTT publishing task
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outbound {
+    // Movement analysis results, `<direction> <activity>` with the direction as
+    // a `MovementDirection::as_payload` digit, or `shake <activity>`, see
+    // `activity`
+    Event(Vec<u8>),
+    // Sensor condition changes, see `SensorEvent`
+    Fault(String),
+    // Sample sanity issues and periodic metrics, see `HealthMonitor`
+    Health(String),
+    // Connection metrics, on every broker (re)connection
+    Link(String),
+    // Replies to the received commands, see `commands::Ack`
+    Ack(String),
+    // Sample frames while streaming, see `stream`
+    Stream(Vec<u8>),
+    // Per-sample records for the serial console, see `telemetry::Record`
+    Telemetry(Vec<u8>),
+    // Recognized gestures, `<name> <figure>`: the distance to the template for
+    // custom ones (`gesture_templates`), the margin of the trained classifier
+    // otherwise (`gesture_classifier`)
+    Gesture(String),
+    // Twists, tilts and flicks, see `rotation::RotationGesture`
+    Rotation(String),
+    // Single and double taps, see `tap::Tap`
+    Tap(String),
+    // Changes of the static pose, see `pose::Pose`
+    Pose(String),
+    // Names of the recognized sequences of events, see `combos`
+    Combo(String),
+}
+
+impl Outbound {
+    // Topic under the device prefix
+    pub fn topic(&self) -> &'static str {
+        match self {
+            Outbound::Event(_) => "event",
+            Outbound::Fault(_) => "fault",
+            Outbound::Health(_) => "health",
+            Outbound::Link(_) => "link",
+            Outbound::Ack(_) => "ack",
+            Outbound::Stream(_) => "stream",
+            Outbound::Telemetry(_) => "telemetry",
+            Outbound::Gesture(_) => "gesture",
+            Outbound::Rotation(_) => "rotation",
+            Outbound::Tap(_) => "tap",
+            Outbound::Pose(_) => "pose",
+            Outbound::Combo(_) => "combo",
+        }
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        match self {
+            Outbound::Event(payload) | Outbound::Stream(payload) | Outbound::Telemetry(payload) => payload,
+            Outbound::Fault(text)
+            | Outbound::Health(text)
+            | Outbound::Link(text)
+            | Outbound::Ack(text)
+            | Outbound::Gesture(text)
+            | Outbound::Rotation(text)
+            | Outbound::Tap(text)
+            | Outbound::Pose(text)
+            | Outbound::Combo(text) => text.as_bytes(),
+        }
+    }
+
+    fn tag(&self) -> u8 {
+        match self {
+            Outbound::Event(_) => 0,
+            Outbound::Fault(_) => 1,
+            Outbound::Health(_) => 2,
+            Outbound::Link(_) => 3,
+            Outbound::Ack(_) => 4,
+            Outbound::Stream(_) => 5,
+            Outbound::Telemetry(_) => 6,
+            Outbound::Gesture(_) => 7,
+            Outbound::Rotation(_) => 8,
+            Outbound::Tap(_) => 9,
+            Outbound::Pose(_) => 10,
+            Outbound::Combo(_) => 11,
+        }
+    }
+
+    // Stream frames and telemetry records are large and stale after a reboot,
+    // they would only fill up the flash
+    fn persistent(&self) -> bool {
+        !matches!(self, Outbound::Stream(_) | Outbound::Telemetry(_))
+    }
+}
+
+// Storage format: per message, a tag byte, the payload length as u16 LE and the
+// payload. Only the persistent messages are stored.
+pub fn encode(messages: &VecDeque<Outbound>) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    for message in messages.iter().filter(|message| message.persistent()) {
+        let payload = message.payload();
+        buffer.push(message.tag());
+        buffer.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        buffer.extend_from_slice(payload);
+    }
+    buffer
+}
+
+pub fn decode(mut buffer: &[u8]) -> Result<Vec<Outbound>> {
+    let mut messages = Vec::new();
+    while let [tag, l0, l1, rest @ ..] = buffer {
+        let len = u16::from_le_bytes([*l0, *l1]) as usize;
+        if rest.len() < len {
+            return Err(anyhow!("Truncated message"));
+        }
+        let (payload, rest) = rest.split_at(len);
+        let text = || String::from_utf8(payload.to_vec()).map_err(|err| anyhow!("Invalid message: {:?}", err));
+        messages.push(match tag {
+            0 => Outbound::Event(payload.to_vec()),
+            1 => Outbound::Fault(text()?),
+            2 => Outbound::Health(text()?),
+            3 => Outbound::Link(text()?),
+            4 => Outbound::Ack(text()?),
+            5 => Outbound::Stream(payload.to_vec()),
+            6 => Outbound::Telemetry(payload.to_vec()),
+            7 => Outbound::Gesture(text()?),
+            8 => Outbound::Rotation(text()?),
+            9 => Outbound::Tap(text()?),
+            10 => Outbound::Pose(text()?),
+            11 => Outbound::Combo(text()?),
+            _ => return Err(anyhow!("Unknown message tag {}", tag)),
+        });
+        buffer = rest;
+    }
+    if !buffer.is_empty() {
+        return Err(anyhow!("Truncated message"));
+    }
+    Ok(messages)
+}
+
+// Keeps the queued messages across reboots
+pub trait OutboxStore {
+    fn save(&mut self, messages: &VecDeque<Outbound>) -> Result<()>;
+    fn load(&mut self) -> Result<Vec<Outbound>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    DropOldest,
+    DropNewest,
+    // Replace the oldest queued message on the same topic, only the latest
+    // one is worth sending. Falls back to dropping the oldest message.
+    Coalesce,
+}
+
+impl OverflowPolicy {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "drop_oldest" => Some(OverflowPolicy::DropOldest),
+            "drop_newest" => Some(OverflowPolicy::DropNewest),
+            "coalesce" => Some(OverflowPolicy::Coalesce),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct OutboxStats {
+    pub published: u32,
+    pub dropped: u32,
+    pub failed_publishes: u32,
+}
+
+// Offline, the queue is saved at most this often, unless a fault came in
+pub const DEFAULT_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/* Bounded queue of messages waiting for the broker. Messages are published in
+ * order, and one that fails to go out stays at the front until the next flush.
+ * With a store, the queue is saved when it changed while offline, at most every
+ * `save_interval` to spare the flash, right away for faults, so that a reboot
+ * loses little of it.
+ */
+pub struct Outbox {
+    queue: VecDeque<Outbound>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    store: Option<Box<dyn OutboxStore + Send>>,
+    // Queue contents differ from what was last saved
+    dirty: bool,
+    // The store holds messages
+    stored: bool,
+    // A fault is waiting to be saved
+    urgent: bool,
+    save_interval: Duration,
+    last_save: Option<Instant>,
+    online: bool,
+    pub stats: OutboxStats,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        assert!(capacity > 0);
+        Self {
+            queue: VecDeque::with_capacity(capacity),
+            capacity,
+            policy,
+            store: None,
+            dirty: false,
+            stored: false,
+            urgent: false,
+            save_interval: DEFAULT_SAVE_INTERVAL,
+            last_save: None,
+            online: false,
+            stats: OutboxStats::default(),
+        }
+    }
+
+    // Restores the messages saved in `store`, ahead of any new ones
+    pub fn with_store(mut self, mut store: Box<dyn OutboxStore + Send>) -> Result<Self> {
+        for message in store.load()? {
+            self.push(message);
+        }
+        self.stored = !self.queue.is_empty();
+        self.dirty = false;
+        self.urgent = false;
+        self.store = Some(store);
+        Ok(self)
+    }
+
+    pub fn with_save_interval(mut self, interval: Duration) -> Self {
+        self.save_interval = interval;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn push(&mut self, message: Outbound) {
+        if self.queue.len() >= self.capacity {
+            self.stats.dropped += 1;
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    self.queue.pop_front();
+                },
+                OverflowPolicy::DropNewest => return,
+                OverflowPolicy::Coalesce => {
+                    let same_topic = self.queue.iter().position(|queued| queued.topic() == message.topic());
+                    self.queue.remove(same_topic.unwrap_or(0));
+                },
+            }
+        }
+        self.urgent |= matches!(message, Outbound::Fault(_));
+        self.queue.push_back(message);
+        self.dirty = true;
+    }
+
+    // Sends the queued messages in order while the sink is ready, stopping at the first failure
+    pub fn flush(&mut self, sink: &mut dyn EventSink) -> Result<()> {
+        self.flush_at(sink, Instant::now())
+    }
+
+    pub fn flush_at(&mut self, sink: &mut dyn EventSink, now: Instant) -> Result<()> {
+        self.online = sink.is_ready();
+        if self.online {
+            while let Some(message) = self.queue.front() {
+                if let Err(error) = sink.send(message) {
+                    log::warn!("Error sending {} message: {:?}", message.topic(), error);
+                    self.stats.failed_publishes += 1;
+                    self.online = false;
+                    break;
+                }
+                self.queue.pop_front();
+                self.stats.published += 1;
+                self.dirty = true;
+            }
+        }
+        self.persist(now)
+    }
+
+    // Saves what was not yet, e.g. before shutting down
+    pub fn save(&mut self) -> Result<()> {
+        if self.dirty {
+            self.save_at(Instant::now())?;
+        }
+        Ok(())
+    }
+
+    fn persist(&mut self, now: Instant) -> Result<()> {
+        // Avoid wearing the flash while messages flow normally: online, the
+        // store is only cleared once the queue drains
+        let save = if self.online {
+            self.queue.is_empty() && self.stored
+        } else {
+            let due = self.last_save.map_or(true, |last| now.saturating_duration_since(last) >= self.save_interval);
+            self.dirty && (due || self.urgent)
+        };
+        if save {
+            self.save_at(now)?;
+        }
+        Ok(())
+    }
+
+    fn save_at(&mut self, now: Instant) -> Result<()> {
+        let Some(store) = self.store.as_mut() else {
+            return Ok(());
+        };
+        store.save(&self.queue)?;
+        self.stored = !self.queue.is_empty();
+        self.dirty = false;
+        self.urgent = false;
+        self.last_save = Some(now);
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub use esp::NvsStore;
+
+#[cfg(target_os = "espidf")]
+mod esp {
+    use std::collections::VecDeque;
+
+    use anyhow::Result;
+    use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+
+    use super::{decode, encode, Outbound, OutboxStore};
+
+    const NAMESPACE: &str = "outbox";
+    const KEY: &str = "queue";
+
+    pub struct NvsStore {
+        nvs: EspNvs<NvsDefault>,
+    }
+
+    impl NvsStore {
+        pub fn new(partition: EspDefaultNvsPartition) -> Result<Self> {
+            Ok(Self { nvs: EspNvs::new(partition, NAMESPACE, true)? })
+        }
+    }
+
+    impl OutboxStore for NvsStore {
+        fn save(&mut self, messages: &VecDeque<Outbound>) -> Result<()> {
+            if messages.is_empty() {
+                self.nvs.remove(KEY)?;
+            } else {
+                self.nvs.set_blob(KEY, &encode(messages))?;
+            }
+            Ok(())
+        }
+
+        fn load(&mut self) -> Result<Vec<Outbound>> {
+            let Some(len) = self.nvs.blob_len(KEY)? else {
+                return Ok(Vec::new());
+            };
+            let mut buffer = vec![0u8; len];
+            match self.nvs.get_blob(KEY, &mut buffer)? {
+                Some(data) => decode(data),
+                None => Ok(Vec::new()),
+            }
+        }
+    }
+}
+
+// Host stand-in for the flash store, shared with the test that inspects it
+#[cfg(not(target_os = "espidf"))]
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    data: std::sync::Arc<std::sync::Mutex<Option<Vec<u8>>>>,
+    pub saves: std::sync::Arc<std::sync::atomic::AtomicU32>,
+}
+
+#[cfg(not(target_os = "espidf"))]
+impl OutboxStore for MemoryStore {
+    fn save(&mut self, messages: &VecDeque<Outbound>) -> Result<()> {
+        *self.data.lock().unwrap() = Some(encode(messages));
+        self.saves.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn load(&mut self) -> Result<Vec<Outbound>> {
+        self.data.lock().unwrap().as_deref().map_or(Ok(Vec::new()), decode)
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct FakeSink {
+    up: bool,
+    // Fails this many publishes even while up, as a flaky link would
+    failures: u32,
+    published: Vec<Outbound>,
+}
+
+#[cfg(test)]
+impl EventSink for FakeSink {
+    fn is_ready(&self) -> bool {
+        self.up
+    }
+
+    fn send(&mut self, message: &Outbound) -> Result<()> {
+        if self.failures > 0 {
+            self.failures -= 1;
+            return Err(anyhow!("Publish timed out"));
+        }
+        self.published.push(message.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn event(id: u8) -> Outbound {
+    Outbound::Event(vec![id])
+}
+
+#[test]
+fn test_replay_in_order_after_outage() {
+    let mut outbox = Outbox::new(8, OverflowPolicy::DropOldest);
+    let mut sink = FakeSink { up: true, ..Default::default() };
+
+    outbox.push(event(1));
+    outbox.flush(&mut sink).unwrap();
+
+    // Broker goes away
+    sink.up = false;
+    for id in 2..5 {
+        outbox.push(event(id));
+        outbox.flush(&mut sink).unwrap();
+    }
+    assert_eq!(outbox.len(), 3);
+
+    // Link is back but flaky: the failed message is retried first
+    sink.up = true;
+    sink.failures = 1;
+    outbox.push(event(5));
+    outbox.flush(&mut sink).unwrap();
+    assert_eq!(outbox.len(), 4);
+    outbox.flush(&mut sink).unwrap();
+    assert!(outbox.is_empty());
+
+    assert_eq!(sink.published, (1..6).map(event).collect::<Vec<_>>());
+    assert_eq!(outbox.stats, OutboxStats { published: 5, dropped: 0, failed_publishes: 1 });
+}
+
+#[test]
+fn test_overflow_policies() {
+    let fill = |policy| {
+        let mut outbox = Outbox::new(3, policy);
+        outbox.push(event(1));
+        outbox.push(Outbound::Health(String::from("healthy")));
+        outbox.push(event(2));
+        outbox.push(event(3));
+        let mut sink = FakeSink { up: true, ..Default::default() };
+        outbox.flush(&mut sink).unwrap();
+        assert_eq!(outbox.stats.dropped, 1);
+        sink.published
+    };
+
+    let health = Outbound::Health(String::from("healthy"));
+    assert_eq!(fill(OverflowPolicy::DropOldest), vec![health.clone(), event(2), event(3)]);
+    assert_eq!(fill(OverflowPolicy::DropNewest), vec![event(1), health.clone(), event(2)]);
+    assert_eq!(fill(OverflowPolicy::Coalesce), vec![health, event(2), event(3)]);
+
+    // Nothing queued on the same topic
+    let mut outbox = Outbox::new(2, OverflowPolicy::Coalesce);
+    outbox.push(event(1));
+    outbox.push(event(2));
+    outbox.push(Outbound::Fault(String::from("recovered")));
+    let mut sink = FakeSink { up: true, ..Default::default() };
+    outbox.flush(&mut sink).unwrap();
+    assert_eq!(sink.published, vec![event(2), Outbound::Fault(String::from("recovered"))]);
+}
+
+#[test]
+fn test_persistence_across_restarts() {
+    use std::sync::atomic::Ordering;
+
+    let store = MemoryStore::default();
+    let mut outbox = Outbox::new(8, OverflowPolicy::DropOldest).with_store(Box::new(store.clone())).unwrap();
+    let mut sink = FakeSink::default();
+
+    outbox.push(event(1));
+    outbox.push(Outbound::Stream(vec![0; 391]));
+    outbox.push(Outbound::Fault(String::from("faulted Bus")));
+    outbox.push(Outbound::Telemetry(vec![0; 57]));
+    outbox.flush(&mut sink).unwrap();
+    assert_eq!(store.saves.load(Ordering::Relaxed), 1);
+    drop(outbox);
+
+    // Rebooted, queued messages go first, without the samples
+    let mut outbox = Outbox::new(8, OverflowPolicy::DropOldest).with_store(Box::new(store.clone())).unwrap();
+    outbox.push(event(2));
+    sink.up = true;
+    outbox.flush(&mut sink).unwrap();
+    assert_eq!(sink.published, vec![event(1), Outbound::Fault(String::from("faulted Bus")), event(2)]);
+    // The drained queue is saved once, nothing more while online
+    assert_eq!(store.saves.load(Ordering::Relaxed), 2);
+    outbox.push(event(3));
+    outbox.flush(&mut sink).unwrap();
+    assert_eq!(store.saves.load(Ordering::Relaxed), 2);
+    assert!(store.clone().load().unwrap().is_empty());
+}
+
+#[test]
+fn test_offline_saves_are_throttled() {
+    use std::sync::atomic::Ordering;
+
+    let store = MemoryStore::default();
+    let mut outbox = Outbox::new(256, OverflowPolicy::DropOldest).with_store(Box::new(store.clone())).unwrap()
+        .with_save_interval(Duration::from_secs(10));
+    let mut sink = FakeSink::default();
+    let saves = || store.saves.load(Ordering::Relaxed);
+
+    // Offline for a minute, with a message every half second: the first one
+    // is saved right away, then the queue every 10 s
+    let t0 = Instant::now();
+    for tick in 0..120 {
+        outbox.push(event(tick as u8));
+        outbox.flush_at(&mut sink, t0 + Duration::from_millis(500 * tick)).unwrap();
+    }
+    assert_eq!(saves(), 6);
+    // Faults do not wait
+    outbox.push(Outbound::Fault(String::from("faulted Bus")));
+    outbox.flush_at(&mut sink, t0 + Duration::from_millis(60_100)).unwrap();
+    assert_eq!(saves(), 7);
+    assert_eq!(store.clone().load().unwrap().len(), 121);
+    // Nor what is left on shutdown
+    outbox.push(event(0));
+    outbox.flush_at(&mut sink, t0 + Duration::from_millis(60_200)).unwrap();
+    assert_eq!(saves(), 7);
+    outbox.save().unwrap();
+    outbox.save().unwrap();
+    assert_eq!(saves(), 8);
+    assert_eq!(store.clone().load().unwrap().len(), 122);
+}
+
+#[test]
+fn test_decode_rejects_corrupt_data() {
+    let mut messages = VecDeque::new();
+    messages.push_back(event(1));
+    messages.push_back(Outbound::Link(String::from("wifi_reconnects 1")));
+    let encoded = encode(&messages);
+    assert_eq!(decode(&encoded).unwrap(), Vec::from(messages));
+
+    assert!(decode(&encoded[..encoded.len() - 1]).is_err());
+    assert!(decode(&[0xff, 0, 0]).is_err());
+}
@@ -0,0 +1,237 @@
+use core::time::Duration;
+use std::sync::mpsc::Sender;
+
+use anyhow::Result;
+
+use crate::activity::{ActivityMeter, ShakeConfig, ShakeDetector};
+use crate::analysis::Analysis;
+use crate::combos::ComboMatcher;
+use crate::commands::Ack;
+use crate::gesture_classifier::{Classifier, GestureClassifier};
+use crate::gesture_model;
+use crate::gesture_templates::{DtwConfig, TemplateRecognizer};
+use crate::imu_tracker::{Frame, ImuTracker};
+use crate::mounting::MountingCalibration;
+use crate::outbox::Outbound;
+use crate::pose::{PoseConfig, PoseDetector};
+use crate::rotation::{RotationConfig, RotationDetector};
+use crate::sensor::ImuSample;
+use crate::sensor_health::HealthMonitor;
+use crate::stream::{axes, StreamConfig, StreamEncoder, StreamSample};
+use crate::tap::{TapConfig, TapDetector};
+use crate::telemetry::Record;
+
+// Health metrics are published about every 10 s
+const HEALTH_REPORT_PERIOD: Duration = Duration::from_secs(10);
+// Movement directions are reported once every this many samples
+const EVENT_DECIMATION: u32 = 50;
+// The mounting is levelled over about a second of the device lying still
+const MOUNT_CALIBRATION_PERIOD: Duration = Duration::from_secs(1);
+
+// Turns the sensor samples into outbound messages: health checks, then
+// taps and pose, orientation tracking, movement analysis and activity,
+// rotation and custom gestures, then combos of the events
+pub struct Pipeline {
+    pub tracker: ImuTracker,
+    pub analysis: Analysis,
+    // Frame of the linear acceleration movements are analysed in, the earth
+    // one by default, see `imu_tracker::Frame`
+    pub analysis_frame: Frame,
+    pub activity: ActivityMeter,
+    pub shake: ShakeDetector,
+    pub taps: TapDetector,
+    pub pose: PoseDetector,
+    pub gestures: TemplateRecognizer,
+    // Present when a model is built in, see `gesture_model`
+    pub classifier: Option<GestureClassifier>,
+    // Without combos until configured, see `combos::parse`
+    pub combos: ComboMatcher,
+    pub rotation: RotationDetector,
+    pub health: HealthMonitor,
+    health_report_samples: u32,
+    mount_calibration_samples: usize,
+    mount_calibration: Option<MountingCalibration>,
+    id: u32,
+    stream: Option<StreamEncoder>,
+    telemetry: bool,
+    tx: Sender<Outbound>,
+}
+
+fn load_classifier(model: &[u8]) -> Option<GestureClassifier> {
+    if model.is_empty() {
+        return None;
+    }
+    match Classifier::from_blob(model) {
+        Ok(classifier) => Some(GestureClassifier::new(classifier)),
+        Err(err) => {
+            log::warn!("Gesture model not loaded: {:?}", err);
+            None
+        },
+    }
+}
+
+impl Pipeline {
+    pub fn new(tracker: ImuTracker, health: HealthMonitor, sample_period: Duration, tx: Sender<Outbound>) -> Self {
+        Self {
+            tracker,
+            analysis: Analysis::default(),
+            analysis_frame: Frame::Earth,
+            activity: ActivityMeter::default(),
+            shake: ShakeDetector::new(ShakeConfig::default()),
+            taps: TapDetector::new(TapConfig::default()),
+            pose: PoseDetector::new(PoseConfig::default()),
+            gestures: TemplateRecognizer::new(DtwConfig::default()),
+            classifier: load_classifier(gesture_model::MODEL),
+            combos: ComboMatcher::new(Vec::new()),
+            rotation: RotationDetector::new(RotationConfig::default()),
+            health,
+            health_report_samples: ((HEALTH_REPORT_PERIOD.as_secs_f32() / sample_period.as_secs_f32()) as u32).max(1),
+            mount_calibration_samples: ((MOUNT_CALIBRATION_PERIOD.as_secs_f32() / sample_period.as_secs_f32()) as usize).max(1),
+            mount_calibration: None,
+            id: 1,
+            stream: None,
+            telemetry: false,
+            tx,
+        }
+    }
+
+    // Starts streaming the samples, or stops it with `None`
+    pub fn set_stream(&mut self, config: Option<StreamConfig>) {
+        self.stream = config.map(StreamEncoder::new);
+    }
+
+    pub fn stream_config(&self) -> Option<StreamConfig> {
+        self.stream.as_ref().map(StreamEncoder::config)
+    }
+
+    // Emits telemetry records for every sample, see `telemetry`
+    pub fn set_telemetry(&mut self, enabled: bool) {
+        self.telemetry = enabled;
+    }
+
+    /* Levels the mounting from the next samples, with the device lying face up,
+     * see `MountingCalibration`. The outcome is acknowledged as `mount level`
+     * once done.
+     */
+    pub fn start_mount_calibration(&mut self) {
+        self.mount_calibration = Some(MountingCalibration::new(self.mount_calibration_samples));
+    }
+
+    pub fn process(&mut self, delta: f32, sample: &ImuSample) -> Result<()> {
+        self.health.check(sample);
+        for event in self.health.take_events() {
+            log::warn!("IMU {}", event);
+            self.tx.send(Outbound::Health(event.to_string()))?;
+        }
+        if self.health.metrics.samples % self.health_report_samples == 0 {
+            self.tx.send(Outbound::Health(self.health.metrics.to_string()))?;
+        }
+
+        if let Some(calibration) = self.mount_calibration.as_mut() {
+            if let Some(outcome) = calibration.add_sample(sample.accel, sample.gyro) {
+                self.mount_calibration = None;
+                let command = String::from("mount level");
+                let ack = match outcome {
+                    Ok(mounting) => {
+                        self.tracker.set_mounting(mounting);
+                        let angle = mounting.euler().angle;
+                        let detail = format!("{:.1} {:.1} {:.1}", angle.roll, angle.pitch, angle.yaw);
+                        Ack::Ok { command, detail: Some(detail) }
+                    },
+                    Err(err) => Ack::Error { command, reason: format!("{:?}", err) },
+                };
+                self.tx.send(Outbound::Ack(ack.to_string()))?;
+            }
+        }
+
+        // Readings in the axes of the device rather than those of the sensor,
+        // see `mounting`
+        let mounting = self.tracker.mounting();
+        let (accel, gyro) = (mounting.apply(sample.accel), mounting.apply(sample.gyro));
+        // Discrete events, in the order they are published, for the combos
+        let mut events = Vec::new();
+        if let Some(tap) = self.taps.add_measurement(delta, accel) {
+            self.tx.send(Outbound::Tap(tap.to_string()))?;
+            events.push(String::from(tap.name()));
+        }
+        if let Some(pose) = self.pose.add_measurement(delta, accel) {
+            self.tx.send(Outbound::Pose(String::from(pose.as_payload())))?;
+        }
+        self.tracker.update(delta, sample.accel, sample.gyro);
+        if let Some(stream) = self.stream.as_mut() {
+            let angle = &self.tracker.euler.angle;
+            let frame = stream.push(&StreamSample {
+                accel: axes(&accel),
+                gyro: axes(&gyro),
+                euler: [angle.roll, angle.pitch, angle.yaw],
+                linear_accel: axes(&self.tracker.linear_accel),
+            });
+            if let Some(frame) = frame {
+                self.tx.send(Outbound::Stream(frame))?;
+            }
+        }
+
+        let new_direction = self.analysis.add_measurement(self.tracker.linear_accel_in(self.analysis_frame));
+        self.activity.add_measurement(delta, self.analysis.smoothed());
+        let shaken = self.shake.add_measurement(delta, self.analysis.smoothed());
+        if self.telemetry {
+            let angle = &self.tracker.euler.angle;
+            let sample = Record::Sample {
+                sequence: self.id,
+                delta,
+                accel: axes(&accel),
+                gyro: axes(&gyro),
+                euler: [angle.roll, angle.pitch, angle.yaw],
+                linear_accel: axes(&self.tracker.linear_accel),
+            };
+            let (horizontal, vertical) = self.analysis.indicators();
+            let analysis = Record::Analysis {
+                sequence: self.id,
+                horizontal,
+                vertical,
+                activity: self.activity.intensity(),
+                direction: new_direction,
+            };
+            self.tx.send(Outbound::Telemetry(sample.encode()))?;
+            self.tx.send(Outbound::Telemetry(analysis.encode()))?;
+        }
+        if self.id % EVENT_DECIMATION == 0 {
+            if let Some(dir) = new_direction {
+                log::debug!("{} {:?}", self.id, dir);
+                let payload = format!("{} {:.2}", dir.as_payload(), self.activity.intensity());
+                self.tx.send(Outbound::Event(payload.into_bytes()))?;
+                events.push(String::from(dir.as_name()));
+            }
+        }
+        if shaken {
+            let payload = format!("shake {:.2}", self.activity.intensity());
+            self.tx.send(Outbound::Event(payload.into_bytes()))?;
+            events.push(String::from("shake"));
+        }
+        if let Some(gesture) = self.rotation.add_measurement(delta, gyro, &self.tracker.euler) {
+            self.tx.send(Outbound::Rotation(String::from(gesture.as_payload())))?;
+            events.push(String::from(gesture.as_payload()));
+        }
+        if let Some(gesture) = self.gestures.add_sample(self.tracker.linear_accel, &self.tracker.euler) {
+            self.tx.send(Outbound::Gesture(format!("{} {:.3}", gesture.name, gesture.distance)))?;
+            events.push(gesture.name);
+        }
+        if let Some(classifier) = self.classifier.as_mut() {
+            if let Some((label, margin)) = classifier.add_sample(self.tracker.linear_accel, gyro) {
+                self.tx.send(Outbound::Gesture(format!("{} {:.3}", label, margin)))?;
+                events.push(String::from(label));
+            }
+        }
+
+        if let Some(combo) = self.combos.advance(delta) {
+            self.tx.send(Outbound::Combo(String::from(combo)))?;
+        }
+        for event in &events {
+            if let Some(combo) = self.combos.add_event(event) {
+                self.tx.send(Outbound::Combo(String::from(combo)))?;
+            }
+        }
+        self.id += 1;
+        Ok(())
+    }
+}
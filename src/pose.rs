@@ -0,0 +1,164 @@
+use imu_fusion::FusionVector;
+
+/* Static pose of the device, from gravity as measured by the accelerometer
+ * while still. The AHRS runs without accelerometer correction (see
+ * `ImuTracker`), so its orientation drifts over time, whereas the low-passed
+ * acceleration always points up once the device rests.
+ *
+ * A pose is taken when gravity is within `enter_angle` degrees of its axis for
+ * `hold` seconds, and kept until gravity strays beyond `exit_angle` from it.
+ * The device is still while the acceleration stays within `still_tolerance`
+ * of 1 g. Axes are those of the device, see `mounting`: z out of the face, y
+ * towards the top, x towards the right edge.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pose {
+    FaceUp,
+    FaceDown,
+    Upright,
+    UpsideDown,
+    LeftEdge,
+    RightEdge,
+}
+
+const POSES: [Pose; 6] = [Pose::FaceUp, Pose::FaceDown, Pose::Upright, Pose::UpsideDown, Pose::LeftEdge, Pose::RightEdge];
+
+impl Pose {
+    pub fn as_payload(&self) -> &'static str {
+        match *self {
+            Pose::FaceUp => "face_up",
+            Pose::FaceDown => "face_down",
+            Pose::Upright => "upright",
+            Pose::UpsideDown => "upside_down",
+            Pose::LeftEdge => "left_edge",
+            Pose::RightEdge => "right_edge",
+        }
+    }
+
+    // What the accelerometer reads in this pose, in g
+    fn gravity(&self) -> FusionVector {
+        match *self {
+            Pose::FaceUp => FusionVector::new(0.0, 0.0, 1.0),
+            Pose::FaceDown => FusionVector::new(0.0, 0.0, -1.0),
+            Pose::Upright => FusionVector::new(0.0, 1.0, 0.0),
+            Pose::UpsideDown => FusionVector::new(0.0, -1.0, 0.0),
+            // Resting on the left edge, the right one is up
+            Pose::LeftEdge => FusionVector::new(1.0, 0.0, 0.0),
+            Pose::RightEdge => FusionVector::new(-1.0, 0.0, 0.0),
+        }
+    }
+
+    // Angle between gravity and the axis of the pose, in degrees
+    fn angle(&self, gravity: FusionVector) -> f32 {
+        let cosine = self.gravity().dot_product(&gravity) / gravity.magnitude().sqrt();
+        cosine.clamp(-1.0, 1.0).acos().to_degrees()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoseConfig {
+    pub enter_angle: f32,
+    pub exit_angle: f32,
+    pub hold: f32,
+    // Time constant of the gravity low-pass filter
+    pub filter_time: f32,
+    // In g
+    pub still_tolerance: f32,
+}
+
+impl Default for PoseConfig {
+    fn default() -> Self {
+        Self { enter_angle: 30.0, exit_angle: 50.0, hold: 0.5, filter_time: 0.2, still_tolerance: 0.1 }
+    }
+}
+
+pub struct PoseDetector {
+    config: PoseConfig,
+    gravity: Option<FusionVector>,
+    pose: Option<Pose>,
+    // Pose gravity is close to, and for how long
+    candidate: Option<(Pose, f32)>,
+}
+
+impl PoseDetector {
+    pub fn new(config: PoseConfig) -> Self {
+        assert!(config.enter_angle <= config.exit_angle);
+        Self { config, gravity: None, pose: None, candidate: None }
+    }
+
+    // Latest pose reported, none until the device first rests
+    pub fn pose(&self) -> Option<Pose> {
+        self.pose
+    }
+
+    // The new pose when it changes
+    pub fn add_measurement(&mut self, delta: f32, accel: FusionVector) -> Option<Pose> {
+        let gravity = match self.gravity {
+            Some(gravity) => gravity + (accel - gravity) * (delta / (self.config.filter_time + delta)),
+            None => accel,
+        };
+        self.gravity = Some(gravity);
+        let still = |vector: FusionVector| (vector.magnitude().sqrt() - 1.0).abs() <= self.config.still_tolerance;
+        if !still(accel) || !still(gravity) {
+            self.candidate = None;
+            return None;
+        }
+        if self.pose.is_some_and(|pose| pose.angle(gravity) <= self.config.exit_angle) {
+            self.candidate = None;
+            return None;
+        }
+
+        let nearest = POSES.into_iter().find(|pose| pose.angle(gravity) <= self.config.enter_angle);
+        self.candidate = match (nearest, self.candidate) {
+            (None, _) => None,
+            (Some(pose), Some((candidate, elapsed))) if pose == candidate => Some((pose, elapsed + delta)),
+            (Some(pose), _) => Some((pose, 0.0)),
+        };
+        let (pose, elapsed) = self.candidate?;
+        if elapsed < self.config.hold {
+            return None;
+        }
+        self.candidate = None;
+        self.pose = Some(pose);
+        self.pose
+    }
+}
+
+// Accelerometer reading at rest, tilted by `degrees` from face up about y
+#[cfg(test)]
+fn tilted(degrees: f32) -> FusionVector {
+    let angle = degrees.to_radians();
+    FusionVector::new(-angle.sin(), 0.0, angle.cos())
+}
+
+#[test]
+fn test_poses() {
+    const DELTA: f32 = 0.005;
+    let mut detector = PoseDetector::new(PoseConfig::default());
+    let hold = |detector: &mut PoseDetector, accel: FusionVector, seconds: f32| -> Vec<Pose> {
+        (0..(seconds / DELTA) as usize).filter_map(|_| detector.add_measurement(DELTA, accel)).collect()
+    };
+
+    // Not reported before the hold time
+    assert!(hold(&mut detector, tilted(0.0), 0.4).is_empty());
+    assert_eq!(hold(&mut detector, tilted(0.0), 0.4), [Pose::FaceUp]);
+    assert_eq!(detector.pose(), Some(Pose::FaceUp));
+    // Between the enter and exit angles, nothing changes either way
+    assert!(hold(&mut detector, tilted(40.0), 2.0).is_empty());
+    assert!(hold(&mut detector, tilted(80.0), 0.2).is_empty());
+    assert!(hold(&mut detector, tilted(40.0), 2.0).is_empty());
+    assert_eq!(detector.pose(), Some(Pose::FaceUp));
+    // On the right edge, through the angles in between
+    assert_eq!(hold(&mut detector, tilted(90.0), 2.0), [Pose::RightEdge]);
+
+    // Carried around, nothing is still
+    let moving: Vec<Pose> = (0..400).filter_map(|index| {
+        let shake = if index % 20 < 10 { 0.4 } else { -0.4 };
+        detector.add_measurement(DELTA, FusionVector::new(0.0, 1.0 + shake, 0.0))
+    }).collect();
+    assert!(moving.is_empty());
+    assert_eq!(hold(&mut detector, FusionVector::new(0.0, 0.98, 0.05), 1.0), [Pose::Upright]);
+    assert_eq!(hold(&mut detector, FusionVector::new(0.02, -0.03, -1.02), 1.0), [Pose::FaceDown]);
+    assert_eq!(hold(&mut detector, FusionVector::new(0.0, -1.0, 0.0), 1.0), [Pose::UpsideDown]);
+    assert_eq!(hold(&mut detector, FusionVector::new(1.0, 0.0, 0.0), 1.0), [Pose::LeftEdge]);
+}
@@ -0,0 +1,237 @@
+use imu_fusion::{FusionEuler, FusionVector};
+
+use crate::imu_tracker::wrap_degrees;
+
+/* Rotation gestures, from the gyro and the orientation of the tracker. The
+ * device is taken as lying level with z up and x pointing forward, so that
+ * rolling right and pitching forward are positive.
+ *
+ * - Twists are bursts of rotation about z of at least `twist_angle`, done
+ *   within `twist_time`, clockwise or not as seen from above.
+ * - Flicks are bursts of any rotation reaching `flick_rate`, over within
+ *   `flick_time`.
+ * - Tilts are roll or pitch held at least `tilt_angle` away from the rest
+ *   orientation for `tilt_hold`. Another tilt is reported once back within
+ *   `tilt_release` of the rest orientation, which slowly follows the device
+ *   otherwise, and jumps to the current one after `tilt_rest` in a tilt.
+ *
+ * Angles are in degrees and times in seconds.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RotationGesture {
+    TwistClockwise,
+    TwistCounterClockwise,
+    TiltLeft,
+    TiltRight,
+    TiltForward,
+    TiltBack,
+    Flick,
+}
+
+impl RotationGesture {
+    pub fn as_payload(&self) -> &'static str {
+        match *self {
+            RotationGesture::TwistClockwise => "twist_cw",
+            RotationGesture::TwistCounterClockwise => "twist_ccw",
+            RotationGesture::TiltLeft => "tilt_left",
+            RotationGesture::TiltRight => "tilt_right",
+            RotationGesture::TiltForward => "tilt_forward",
+            RotationGesture::TiltBack => "tilt_back",
+            RotationGesture::Flick => "flick",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RotationConfig {
+    // Angular rate starting and ending a burst of rotation, deg/s
+    pub burst_rate: f32,
+    pub twist_angle: f32,
+    pub twist_time: f32,
+    // Peak angular rate of a flick, deg/s
+    pub flick_rate: f32,
+    pub flick_time: f32,
+    pub tilt_angle: f32,
+    pub tilt_hold: f32,
+    pub tilt_release: f32,
+    pub tilt_rest: f32,
+    // Time constant of the rest orientation following the device
+    pub rest_follow_time: f32,
+}
+
+impl Default for RotationConfig {
+    fn default() -> Self {
+        Self {
+            burst_rate: 60.0,
+            twist_angle: 60.0,
+            twist_time: 0.6,
+            flick_rate: 400.0,
+            flick_time: 0.25,
+            tilt_angle: 30.0,
+            tilt_hold: 0.3,
+            tilt_release: 15.0,
+            tilt_rest: 3.0,
+            rest_follow_time: 5.0,
+        }
+    }
+}
+
+struct Burst {
+    elapsed: f32,
+    // Rotation about z so far
+    angle: f32,
+    peak_rate: f32,
+}
+
+pub struct RotationDetector {
+    config: RotationConfig,
+    burst: Option<Burst>,
+    // Roll and pitch at rest, unknown until the first sample
+    rest: Option<(f32, f32)>,
+    // Time spent beyond the tilt angle, and whether the tilt was reported
+    tilt_time: f32,
+    tilted: bool,
+}
+
+impl RotationDetector {
+    pub fn new(config: RotationConfig) -> Self {
+        Self { config, burst: None, rest: None, tilt_time: 0.0, tilted: false }
+    }
+
+    pub fn add_measurement(&mut self, delta: f32, gyro: FusionVector, euler: &FusionEuler) -> Option<RotationGesture> {
+        let burst = self.track_burst(delta, gyro);
+        let tilt = self.track_tilt(delta, euler.angle.roll, euler.angle.pitch);
+        burst.or(tilt)
+    }
+
+    fn track_burst(&mut self, delta: f32, gyro: FusionVector) -> Option<RotationGesture> {
+        let rate = (gyro.x * gyro.x + gyro.y * gyro.y + gyro.z * gyro.z).sqrt();
+        if rate >= self.config.burst_rate {
+            let burst = self.burst.get_or_insert(Burst { elapsed: 0.0, angle: 0.0, peak_rate: 0.0 });
+            burst.elapsed += delta;
+            burst.angle += gyro.z * delta;
+            burst.peak_rate = burst.peak_rate.max(rate);
+            return None;
+        }
+
+        let burst = self.burst.take()?;
+        if burst.peak_rate >= self.config.flick_rate && burst.elapsed <= self.config.flick_time {
+            Some(RotationGesture::Flick)
+        } else if burst.angle.abs() >= self.config.twist_angle && burst.elapsed <= self.config.twist_time {
+            // Counter-clockwise from above is positive about z
+            Some(if burst.angle > 0.0 { RotationGesture::TwistCounterClockwise } else { RotationGesture::TwistClockwise })
+        } else {
+            None
+        }
+    }
+
+    fn track_tilt(&mut self, delta: f32, roll: f32, pitch: f32) -> Option<RotationGesture> {
+        let (rest_roll, rest_pitch) = *self.rest.get_or_insert((roll, pitch));
+        let (roll_offset, pitch_offset) = (wrap_degrees(roll - rest_roll), wrap_degrees(pitch - rest_pitch));
+        let offset = roll_offset.abs().max(pitch_offset.abs());
+
+        if self.tilted {
+            self.tilt_time += delta;
+            if offset < self.config.tilt_release {
+                self.tilted = false;
+                self.tilt_time = 0.0;
+            } else if self.tilt_time >= self.config.tilt_rest {
+                // Held for long, this is where the device rests now
+                self.rest = Some((roll, pitch));
+                self.tilted = false;
+                self.tilt_time = 0.0;
+            }
+            return None;
+        }
+
+        if offset < self.config.tilt_angle {
+            self.tilt_time = 0.0;
+            let follow = (delta / self.config.rest_follow_time).min(1.0);
+            self.rest = Some((rest_roll + roll_offset * follow, rest_pitch + pitch_offset * follow));
+            return None;
+        }
+        self.tilt_time += delta;
+        if self.tilt_time < self.config.tilt_hold {
+            return None;
+        }
+        self.tilted = true;
+        Some(if roll_offset.abs() >= pitch_offset.abs() {
+            if roll_offset > 0.0 { RotationGesture::TiltRight } else { RotationGesture::TiltLeft }
+        } else if pitch_offset > 0.0 {
+            RotationGesture::TiltForward
+        } else {
+            RotationGesture::TiltBack
+        })
+    }
+}
+
+#[cfg(test)]
+struct Motion {
+    roll: f32,
+    pitch: f32,
+    yaw: f32,
+}
+
+// Rotates at `rate` deg/s about each axis for `seconds`, integrating the angles
+#[cfg(test)]
+fn rotate(detector: &mut RotationDetector, motion: &mut Motion, rate: [f32; 3], seconds: f32) -> Vec<RotationGesture> {
+    const DELTA: f32 = 0.005;
+    let mut detected = Vec::new();
+    for _ in 0..(seconds / DELTA).round() as usize {
+        motion.roll += rate[0] * DELTA;
+        motion.pitch += rate[1] * DELTA;
+        motion.yaw = wrap_degrees(motion.yaw + rate[2] * DELTA);
+        let mut euler = FusionEuler::zero();
+        euler.angle.roll = motion.roll;
+        euler.angle.pitch = motion.pitch;
+        euler.angle.yaw = motion.yaw;
+        detected.extend(detector.add_measurement(DELTA, FusionVector::new(rate[0], rate[1], rate[2]), &euler));
+    }
+    detected
+}
+
+#[test]
+fn test_rotation_gestures() {
+    let mut detector = RotationDetector::new(RotationConfig::default());
+    let mut motion = Motion { roll: 0.0, pitch: 0.0, yaw: 170.0 };
+    assert!(rotate(&mut detector, &mut motion, [0.0; 3], 1.0).is_empty());
+
+    // 80 degrees in 0.4 s, either way
+    let mut detected = rotate(&mut detector, &mut motion, [0.0, 0.0, 200.0], 0.4);
+    detected.extend(rotate(&mut detector, &mut motion, [0.0; 3], 0.2));
+    detected.extend(rotate(&mut detector, &mut motion, [0.0, 0.0, -200.0], 0.4));
+    detected.extend(rotate(&mut detector, &mut motion, [0.0; 3], 0.2));
+    assert_eq!(detected, [RotationGesture::TwistCounterClockwise, RotationGesture::TwistClockwise]);
+    // Too slow for a twist
+    let mut detected = rotate(&mut detector, &mut motion, [0.0, 0.0, 70.0], 1.2);
+    detected.extend(rotate(&mut detector, &mut motion, [0.0; 3], 0.2));
+    assert!(detected.is_empty());
+
+    // Snap and back
+    let mut detected = rotate(&mut detector, &mut motion, [600.0, 0.0, 0.0], 0.1);
+    detected.extend(rotate(&mut detector, &mut motion, [-600.0, 0.0, 0.0], 0.1));
+    detected.extend(rotate(&mut detector, &mut motion, [0.0; 3], 0.2));
+    assert_eq!(detected, [RotationGesture::Flick]);
+
+    // Tilted right and held, reported once
+    let mut detected = rotate(&mut detector, &mut motion, [80.0, 0.0, 0.0], 0.5);
+    detected.extend(rotate(&mut detector, &mut motion, [0.0; 3], 1.0));
+    assert_eq!(detected, [RotationGesture::TiltRight]);
+    // Back level, then forward for too short, then back
+    let mut detected = rotate(&mut detector, &mut motion, [-80.0, 0.0, 0.0], 0.5);
+    detected.extend(rotate(&mut detector, &mut motion, [0.0, 50.0, 0.0], 0.7));
+    detected.extend(rotate(&mut detector, &mut motion, [0.0, -50.0, 0.0], 1.6));
+    detected.extend(rotate(&mut detector, &mut motion, [0.0; 3], 1.0));
+    assert_eq!(detected, [RotationGesture::TiltBack]);
+
+    // Left there, it becomes the rest orientation, and level is a tilt forward
+    assert!(rotate(&mut detector, &mut motion, [0.0; 3], 3.0).is_empty());
+    let mut detected = rotate(&mut detector, &mut motion, [0.0, 50.0, 0.0], 0.8);
+    detected.extend(rotate(&mut detector, &mut motion, [0.0; 3], 1.0));
+    assert_eq!(detected, [RotationGesture::TiltForward]);
+
+    // Slow drift follows the rest orientation
+    let mut detector = RotationDetector::new(RotationConfig::default());
+    let mut motion = Motion { roll: 0.0, pitch: 0.0, yaw: 0.0 };
+    assert!(rotate(&mut detector, &mut motion, [4.0, 0.0, 0.0], 20.0).is_empty());
+}
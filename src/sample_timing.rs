@@ -0,0 +1,205 @@
+use core::time::Duration;
+use std::time::Instant;
+
+// With the DLPF enabled the MPU9250 runs its internal sampling at 1 kHz, and the
+// output data rate is that divided by (1 + SMPLRT_DIV).
+pub fn mpu9250_sample_period(sample_rate_divisor: u8) -> Duration {
+    Duration::from_micros(1000 * (1 + sample_rate_divisor as u64))
+}
+
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampleTick {
+    // Time step for the fusion update, in whole nominal periods (seconds)
+    pub delta: f32,
+    // Samples missed since the previous tick
+    pub dropped: u32,
+    // The sample arrived later than the jitter tolerance allows
+    pub late: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TimingStats {
+    pub samples: u32,
+    pub dropped: u32,
+    pub late: u32,
+    pub max_jitter: Duration,
+}
+
+/* Sample timestamps are taken when the trigger wakes us up, so they carry the
+ * scheduling jitter of the acquisition task. The sensor itself samples on a fixed
+ * grid, so instead of feeding raw wall-clock deltas to the fusion we snap them to
+ * whole nominal periods and account for any gap as dropped samples.
+ */
+pub struct SampleTiming {
+    period: Duration,
+    tolerance: Duration,
+    last: Option<Instant>,
+    pub stats: TimingStats,
+}
+
+impl SampleTiming {
+    pub fn new(period: Duration) -> Self {
+        Self::with_tolerance(period, period / 4)
+    }
+
+    pub fn with_tolerance(period: Duration, tolerance: Duration) -> Self {
+        assert!(!period.is_zero());
+        Self {
+            period,
+            tolerance,
+            last: None,
+            stats: TimingStats::default(),
+        }
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    pub fn tick(&mut self, clock: &impl Clock) -> SampleTick {
+        self.tick_at(clock.now())
+    }
+
+    // For samples that carry their own timestamp, e.g. those read from the FIFO
+    pub fn tick_at(&mut self, now: Instant) -> SampleTick {
+        let period = self.period.as_secs_f32();
+        self.stats.samples += 1;
+
+        let Some(last) = self.last.replace(now) else {
+            return SampleTick { delta: period, dropped: 0, late: false };
+        };
+
+        // The trigger fires once per sample, so at least one period has passed
+        let elapsed = now.duration_since(last);
+        let periods = ((elapsed.as_secs_f32() / period).round() as u32).max(1);
+        let expected = self.period * periods;
+        let (jitter, late) = if elapsed > expected {
+            (elapsed - expected, elapsed - expected > self.tolerance)
+        } else {
+            (expected - elapsed, false)
+        };
+
+        let dropped = periods - 1;
+        self.stats.dropped += dropped;
+        if late {
+            self.stats.late += 1;
+        }
+        if jitter > self.stats.max_jitter {
+            self.stats.max_jitter = jitter;
+        }
+
+        SampleTick {
+            delta: expected.as_secs_f32(),
+            dropped,
+            late,
+        }
+    }
+}
+
+// Host stand-in for the system clock, only moves when told to
+#[cfg(not(target_os = "espidf"))]
+#[derive(Clone)]
+pub struct FakeClock {
+    now: std::sync::Arc<std::sync::Mutex<Instant>>,
+}
+
+#[cfg(not(target_os = "espidf"))]
+impl FakeClock {
+    pub fn new() -> Self {
+        Self { now: std::sync::Arc::new(std::sync::Mutex::new(Instant::now())) }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+#[cfg(not(target_os = "espidf"))]
+impl Default for FakeClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(not(target_os = "espidf"))]
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[test]
+fn test_divisor_period() {
+    assert_eq!(mpu9250_sample_period(0), Duration::from_millis(1));
+    assert_eq!(mpu9250_sample_period(3), Duration::from_millis(4));
+    assert_eq!(mpu9250_sample_period(9), Duration::from_millis(10));
+}
+
+#[test]
+fn test_jitter_is_absorbed() {
+    let clock = FakeClock::new();
+    let mut timing = SampleTiming::new(Duration::from_millis(5));
+    timing.tick(&clock);
+
+    for jitter_us in [0i64, 900, -700, 1200, -1100, 300] {
+        let step = Duration::from_micros((5000 + jitter_us) as u64);
+        clock.advance(step);
+        let tick = timing.tick(&clock);
+        assert_eq!(tick.delta, 0.005);
+        assert_eq!(tick.dropped, 0);
+        assert!(!tick.late);
+    }
+    assert_eq!(timing.stats.samples, 7);
+    assert_eq!(timing.stats.dropped, 0);
+    assert_eq!(timing.stats.max_jitter, Duration::from_micros(1200));
+}
+
+#[test]
+fn test_overrun_reports_dropped_samples() {
+    let clock = FakeClock::new();
+    let mut timing = SampleTiming::new(Duration::from_millis(5));
+    timing.tick(&clock);
+
+    clock.advance(Duration::from_micros(15_400));
+    let tick = timing.tick(&clock);
+    assert_eq!(tick.dropped, 2);
+    assert!((tick.delta - 0.015).abs() < 1e-6);
+    assert!(!tick.late);
+
+    clock.advance(Duration::from_millis(5));
+    assert_eq!(timing.tick(&clock).dropped, 0);
+    assert_eq!(timing.stats.dropped, 2);
+}
+
+#[test]
+fn test_late_sample() {
+    let clock = FakeClock::new();
+    let mut timing = SampleTiming::new(Duration::from_millis(4));
+    timing.tick(&clock);
+
+    // 1.4 periods: still the next sample, but well past its slot
+    clock.advance(Duration::from_micros(5600));
+    let tick = timing.tick(&clock);
+    assert_eq!(tick.dropped, 0);
+    assert!(tick.late);
+    assert_eq!(tick.delta, 0.004);
+
+    // Arriving early never counts as a dropped sample
+    clock.advance(Duration::from_micros(500));
+    let tick = timing.tick(&clock);
+    assert_eq!(tick.dropped, 0);
+    assert!(!tick.late);
+    assert_eq!(timing.stats.late, 1);
+}
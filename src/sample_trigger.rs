@@ -0,0 +1,153 @@
+use anyhow::Result;
+
+#[cfg(not(target_os = "espidf"))]
+use core::time::Duration;
+#[cfg(not(target_os = "espidf"))]
+use std::collections::VecDeque;
+#[cfg(not(target_os = "espidf"))]
+use crate::sample_timing::FakeClock;
+
+// Paces the acquisition loop: each wakeup announces a new sample (or FIFO burst)
+pub trait SampleTrigger {
+    fn wait(&mut self) -> Result<()>;
+}
+
+#[cfg(target_os = "espidf")]
+pub use esp::{DataReadyTrigger, TimerTrigger};
+
+#[cfg(target_os = "espidf")]
+mod esp {
+    use core::time::Duration;
+    use std::num::NonZeroU32;
+
+    use anyhow::Result;
+    use esp_idf_svc::hal::delay::{TickType, BLOCK};
+    use esp_idf_svc::hal::gpio::{Input, InputPin, InterruptType, PinDriver};
+    use esp_idf_svc::hal::peripheral::Peripheral;
+    use esp_idf_svc::hal::task::notification::Notification;
+    use esp_idf_svc::timer::{EspTaskTimerService, EspTimer};
+
+    use super::SampleTrigger;
+
+    // Software timer, not synchronized with the sensor's own output data rate
+    pub struct TimerTrigger {
+        notification: Notification,
+        _timer: EspTimer<'static>,
+    }
+
+    impl TimerTrigger {
+        pub fn new(timer_service: &EspTaskTimerService, period: Duration) -> Result<Self> {
+            let notification = Notification::new();
+            let notifier = notification.notifier();
+            let timer = timer_service.timer(move || unsafe {
+                notifier.notify_and_yield(NonZeroU32::new(1).unwrap());
+            })?;
+            timer.every(period)?;
+
+            Ok(Self { notification, _timer: timer })
+        }
+    }
+
+    impl SampleTrigger for TimerTrigger {
+        fn wait(&mut self) -> Result<()> {
+            self.notification.wait(BLOCK);
+            Ok(())
+        }
+    }
+
+    /* Driven by the MPU9250 INT pin, which pulses on every new sample once
+     * RAW_RDY_EN is set, so acquisition is phase-locked to the sensor ODR.
+     * The GPIO driver disables the interrupt each time it fires, hence it is
+     * re-armed after every wakeup.
+     * A sensor that stopped responding never pulses the pin, so waits time out
+     * after `timeout` to let the acquisition loop notice the failure.
+     */
+    pub struct DataReadyTrigger<'d, T: InputPin> {
+        pin: PinDriver<'d, T, Input>,
+        notification: Notification,
+        timeout: u32,
+    }
+
+    impl<'d, T: InputPin> DataReadyTrigger<'d, T> {
+        pub fn new(pin: impl Peripheral<P = T> + 'd, timeout: Duration) -> Result<Self> {
+            let mut pin = PinDriver::input(pin)?;
+            pin.set_interrupt_type(InterruptType::PosEdge)?;
+
+            let notification = Notification::new();
+            let notifier = notification.notifier();
+            unsafe {
+                pin.subscribe(move || {
+                    notifier.notify_and_yield(NonZeroU32::new(1).unwrap());
+                })?;
+            }
+            pin.enable_interrupt()?;
+
+            Ok(Self { pin, notification, timeout: TickType::from(timeout).ticks() })
+        }
+    }
+
+    impl<T: InputPin> SampleTrigger for DataReadyTrigger<'_, T> {
+        fn wait(&mut self) -> Result<()> {
+            if self.notification.wait(self.timeout).is_none() {
+                log::warn!("No data-ready signal in {} ticks", self.timeout);
+            }
+            self.pin.enable_interrupt()?;
+            Ok(())
+        }
+    }
+}
+
+// Host stand-in: every wakeup advances the clock by the next scripted interval
+#[cfg(not(target_os = "espidf"))]
+pub struct FakeTrigger {
+    clock: FakeClock,
+    intervals: VecDeque<Duration>,
+}
+
+#[cfg(not(target_os = "espidf"))]
+impl FakeTrigger {
+    pub fn new(clock: FakeClock) -> Self {
+        Self { clock, intervals: VecDeque::new() }
+    }
+
+    pub fn periodic(clock: FakeClock, period: Duration, count: usize) -> Self {
+        let mut trigger = Self::new(clock);
+        trigger.intervals.extend(std::iter::repeat(period).take(count));
+        trigger
+    }
+
+    pub fn schedule(&mut self, interval: Duration) {
+        self.intervals.push_back(interval);
+    }
+}
+
+#[cfg(not(target_os = "espidf"))]
+impl SampleTrigger for FakeTrigger {
+    fn wait(&mut self) -> Result<()> {
+        let interval = self.intervals.pop_front()
+            .ok_or_else(|| anyhow::anyhow!("Trigger script exhausted"))?;
+        self.clock.advance(interval);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_fake_trigger_drives_timing() {
+    use crate::sample_timing::SampleTiming;
+
+    let clock = FakeClock::new();
+    let period = Duration::from_millis(4);
+    let mut trigger: Box<dyn SampleTrigger> = Box::new({
+        let mut trigger = FakeTrigger::periodic(clock.clone(), period, 3);
+        // A wakeup that came three samples late
+        trigger.schedule(period * 4);
+        trigger
+    });
+    let mut timing = SampleTiming::new(period);
+
+    let mut dropped = Vec::new();
+    while trigger.wait().is_ok() {
+        dropped.push(timing.tick(&clock).dropped);
+    }
+    assert_eq!(dropped, vec![0, 0, 0, 3]);
+}
@@ -0,0 +1,178 @@
+use core::fmt::Debug;
+use core::time::Duration;
+
+use embedded_hal::blocking::{delay::DelayMs, spi};
+use embedded_hal::digital::v2::OutputPin;
+use imu_fusion::FusionVector;
+
+use crate::spi_registers::BusError;
+use icm20948::Icm20948;
+use lsm6dsox::Lsm6dsox;
+use mpu::Mpu9250Sensor;
+
+pub mod icm20948;
+pub mod lsm6dsox;
+pub mod mpu;
+
+#[derive(Debug)]
+pub enum SensorError {
+    Bus(String),
+    UnexpectedChipId(u8),
+    UnknownModel,
+}
+
+impl<E: Debug, EO: Debug> From<BusError<E, EO>> for SensorError {
+    fn from(error: BusError<E, EO>) -> Self {
+        SensorError::Bus(format!("{:?}", error))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccelRange {
+    G2,
+    G4,
+    G8,
+    G16,
+}
+
+impl AccelRange {
+    // In units of standard gravity
+    pub fn full_scale(&self) -> f32 {
+        match *self {
+            AccelRange::G2 => 2.0,
+            AccelRange::G4 => 4.0,
+            AccelRange::G8 => 8.0,
+            AccelRange::G16 => 16.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GyroRange {
+    Dps250,
+    Dps500,
+    Dps1000,
+    Dps2000,
+}
+
+impl GyroRange {
+    // In degrees/s
+    pub fn full_scale(&self) -> f32 {
+        match *self {
+            GyroRange::Dps250 => 250.0,
+            GyroRange::Dps500 => 500.0,
+            GyroRange::Dps1000 => 1000.0,
+            GyroRange::Dps2000 => 2000.0,
+        }
+    }
+}
+
+// Requested output data rate, each device picks the closest one it supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataRate {
+    Hz100,
+    Hz250,
+    Hz500,
+    Hz1000,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SensorConfig {
+    pub accel_range: AccelRange,
+    pub gyro_range: GyroRange,
+    pub data_rate: DataRate,
+}
+
+impl Default for SensorConfig {
+    fn default() -> Self {
+        Self {
+            accel_range: AccelRange::G2,
+            gyro_range: GyroRange::Dps250,
+            data_rate: DataRate::Hz250,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct ImuSample {
+    // In units of standard gravity
+    pub accel: FusionVector,
+    // In degrees/s
+    pub gyro: FusionVector,
+}
+
+pub trait ImuSensor {
+    fn who_am_i(&mut self) -> Result<u8, SensorError>;
+    fn read(&mut self) -> Result<ImuSample, SensorError>;
+    // Routes the new-sample signal to the sensor interrupt pin
+    fn enable_data_ready(&mut self) -> Result<(), SensorError>;
+    fn config(&self) -> &SensorConfig;
+    // Nominal output period for the data rate actually configured on the device
+    fn sample_period(&self) -> Duration;
+    // Brings the device back to its configuration after a failure, including the
+    // data-ready interrupt if it had been enabled
+    fn reinit(&mut self, delay: &mut dyn DelayMs<u8>) -> Result<(), SensorError>;
+}
+
+pub(crate) fn scale(raw: [i16; 3], resolution: f32) -> FusionVector {
+    FusionVector::new(raw[0] as f32, raw[1] as f32, raw[2] as f32) * resolution
+}
+
+// Identifiers reported in WHO_AM_I by the chips a model name covers
+pub fn expected_chip_ids(model: &str) -> Result<&'static [u8], SensorError> {
+    match model {
+        "mpu9250" => Ok(&mpu::CHIP_IDS),
+        "icm20948" => Ok(&[icm20948::CHIP_ID]),
+        "lsm6dsox" => Ok(&[lsm6dsox::CHIP_ID]),
+        _ => Err(SensorError::UnknownModel),
+    }
+}
+
+// Brings up the sensor named by `model` ("mpu9250", "icm20948" or "lsm6dsox"),
+// each driver checks the chip identifier before configuring it.
+pub fn init<SPI, CS, D, E, EO>(model: &str, spi: SPI, cs: CS, delay: &mut D, config: &SensorConfig)
+    -> Result<Box<dyn ImuSensor>, SensorError>
+    where SPI: spi::Write<u8, Error = E> + spi::Transfer<u8, Error = E> + 'static,
+          CS: OutputPin<Error = EO> + 'static,
+          D: DelayMs<u8>,
+          E: Debug + 'static,
+          EO: Debug + 'static
+{
+    Ok(match model {
+        "mpu9250" => Box::new(Mpu9250Sensor::new(spi, cs, delay, config)?),
+        "icm20948" => Box::new(Icm20948::new(spi, cs, delay, config)?),
+        "lsm6dsox" => Box::new(Lsm6dsox::new(spi, cs, delay, config)?),
+        _ => return Err(SensorError::UnknownModel),
+    })
+}
+
+#[test]
+fn test_init_by_model() {
+    use crate::mock_bus::{MockBus, NoDelay};
+
+    let bus = MockBus::new();
+    bus.set(0, 0x0F, lsm6dsox::CHIP_ID);
+    let mut imu = init("lsm6dsox", bus.spi(), bus.cs(), &mut NoDelay, &SensorConfig::default()).unwrap();
+    assert_eq!(imu.who_am_i().unwrap(), lsm6dsox::CHIP_ID);
+
+    // A different chip on the bus than the one configured
+    let result = init("icm20948", bus.spi(), bus.cs(), &mut NoDelay, &SensorConfig::default());
+    assert!(matches!(result, Err(SensorError::UnexpectedChipId(_))));
+
+    let result = init("bmi270", bus.spi(), bus.cs(), &mut NoDelay, &SensorConfig::default());
+    assert!(matches!(result, Err(SensorError::UnknownModel)));
+}
+
+#[test]
+fn test_init_bus_error() {
+    use crate::mock_bus::{MockBus, NoDelay};
+
+    let bus = MockBus::new();
+    bus.set(0, 0x75, 0x71);
+    bus.fail_register(0x75);
+    let result = init("mpu9250", bus.spi(), bus.cs(), &mut NoDelay, &SensorConfig::default());
+    assert!(matches!(result, Err(SensorError::Bus(_))));
+
+    bus.clear_failures();
+    assert!(init("mpu9250", bus.spi(), bus.cs(), &mut NoDelay, &SensorConfig::default()).is_ok());
+}
@@ -0,0 +1,225 @@
+use core::fmt::Debug;
+use core::time::Duration;
+
+use embedded_hal::blocking::{delay::DelayMs, spi};
+use embedded_hal::digital::v2::OutputPin;
+
+use crate::spi_registers::SpiRegisters;
+use super::{scale, AccelRange, DataRate, GyroRange, ImuSample, ImuSensor, SensorConfig, SensorError};
+
+pub const CHIP_ID: u8 = 0xEA;
+
+// Registers are split in banks, selected through REG_BANK_SEL (bank number in bits 5:4)
+const REG_BANK_SEL: u8 = 0x7F;
+
+// Bank 0
+const WHO_AM_I: u8 = 0x00;
+const USER_CTRL: u8 = 0x03;
+const PWR_MGMT_1: u8 = 0x06;
+const PWR_MGMT_2: u8 = 0x07;
+const INT_ENABLE_1: u8 = 0x11;
+const ACCEL_XOUT_H: u8 = 0x2D;
+
+// Bank 2
+const GYRO_SMPLRT_DIV: u8 = 0x00;
+const GYRO_CONFIG_1: u8 = 0x01;
+const ACCEL_SMPLRT_DIV_1: u8 = 0x10;
+const ACCEL_SMPLRT_DIV_2: u8 = 0x11;
+const ACCEL_CONFIG: u8 = 0x14;
+
+const PWR_MGMT_1_RESET: u8 = 0x80;
+const PWR_MGMT_1_CLK_AUTO: u8 = 0x01;
+const USER_CTRL_I2C_IF_DIS: u8 = 0x10;
+const INT_ENABLE_1_RAW_RDY: u8 = 0x01;
+// Enables the DLPF, which the sample rate divisors depend on
+const FCHOICE: u8 = 0x01;
+
+// Internal sample rate with the DLPF enabled
+const BASE_RATE_HZ: f32 = 1125.0;
+
+pub struct Icm20948<SPI, CS> {
+    regs: SpiRegisters<SPI, CS>,
+    config: SensorConfig,
+    sample_rate_divisor: u8,
+    data_ready: bool,
+}
+
+impl<SPI, CS, E, EO> Icm20948<SPI, CS>
+    where SPI: spi::Write<u8, Error = E> + spi::Transfer<u8, Error = E>,
+          CS: OutputPin<Error = EO>,
+          E: Debug,
+          EO: Debug
+{
+    pub fn new<D: DelayMs<u8>>(spi: SPI, cs: CS, delay: &mut D, config: &SensorConfig)
+        -> Result<Self, SensorError>
+    {
+        // Output rate is 1125 Hz / (1 + divisor)
+        let sample_rate_divisor = match config.data_rate {
+            DataRate::Hz100 => 10,
+            DataRate::Hz250 => 4,
+            DataRate::Hz500 => 1,
+            DataRate::Hz1000 => 0,
+        };
+        let mut icm = Self {
+            regs: SpiRegisters::new(spi, cs),
+            config: *config,
+            sample_rate_divisor,
+            data_ready: false,
+        };
+        icm.init(delay)?;
+        Ok(icm)
+    }
+
+    fn init<D: DelayMs<u8> + ?Sized>(&mut self, delay: &mut D) -> Result<(), SensorError> {
+        self.select_bank(0)?;
+        self.regs.write(PWR_MGMT_1, PWR_MGMT_1_RESET)?;
+        delay.delay_ms(100);
+        self.select_bank(0)?;
+        self.regs.write(PWR_MGMT_1, PWR_MGMT_1_CLK_AUTO)?;
+        self.regs.write(USER_CTRL, USER_CTRL_I2C_IF_DIS)?;
+
+        let who_am_i = self.regs.read(WHO_AM_I)?;
+        if who_am_i != CHIP_ID {
+            return Err(SensorError::UnexpectedChipId(who_am_i));
+        }
+        // Enable all axes of both sensors
+        self.regs.write(PWR_MGMT_2, 0x00)?;
+
+        let gyro_fs = match self.config.gyro_range {
+            GyroRange::Dps250 => 0,
+            GyroRange::Dps500 => 1,
+            GyroRange::Dps1000 => 2,
+            GyroRange::Dps2000 => 3,
+        };
+        let accel_fs = match self.config.accel_range {
+            AccelRange::G2 => 0,
+            AccelRange::G4 => 1,
+            AccelRange::G8 => 2,
+            AccelRange::G16 => 3,
+        };
+        self.select_bank(2)?;
+        self.regs.write(GYRO_SMPLRT_DIV, self.sample_rate_divisor)?;
+        self.regs.write(GYRO_CONFIG_1, (gyro_fs << 1) | FCHOICE)?;
+        self.regs.write(ACCEL_SMPLRT_DIV_1, 0)?;
+        self.regs.write(ACCEL_SMPLRT_DIV_2, self.sample_rate_divisor)?;
+        self.regs.write(ACCEL_CONFIG, (accel_fs << 1) | FCHOICE)?;
+        self.select_bank(0)
+    }
+
+    fn select_bank(&mut self, bank: u8) -> Result<(), SensorError> {
+        Ok(self.regs.write(REG_BANK_SEL, bank << 4)?)
+    }
+}
+
+impl<SPI, CS, E, EO> ImuSensor for Icm20948<SPI, CS>
+    where SPI: spi::Write<u8, Error = E> + spi::Transfer<u8, Error = E>,
+          CS: OutputPin<Error = EO>,
+          E: Debug,
+          EO: Debug
+{
+    fn who_am_i(&mut self) -> Result<u8, SensorError> {
+        Ok(self.regs.read(WHO_AM_I)?)
+    }
+
+    fn read(&mut self) -> Result<ImuSample, SensorError> {
+        // Accel and gyro XYZ are contiguous, big-endian
+        let mut buffer = [0u8; 12];
+        self.regs.read_many(ACCEL_XOUT_H, &mut buffer)?;
+        let word = |i: usize| i16::from_be_bytes([buffer[2 * i], buffer[2 * i + 1]]);
+        Ok(ImuSample {
+            accel: scale([word(0), word(1), word(2)], self.config.accel_range.full_scale() / 32768.0),
+            gyro: scale([word(3), word(4), word(5)], self.config.gyro_range.full_scale() / 32768.0),
+        })
+    }
+
+    fn enable_data_ready(&mut self) -> Result<(), SensorError> {
+        self.regs.modify(INT_ENABLE_1, |r| r | INT_ENABLE_1_RAW_RDY)?;
+        self.data_ready = true;
+        Ok(())
+    }
+
+    fn config(&self) -> &SensorConfig {
+        &self.config
+    }
+
+    fn sample_period(&self) -> Duration {
+        Duration::from_secs_f32((1 + self.sample_rate_divisor as u32) as f32 / BASE_RATE_HZ)
+    }
+
+    fn reinit(&mut self, delay: &mut dyn DelayMs<u8>) -> Result<(), SensorError> {
+        self.init(delay)?;
+        if self.data_ready {
+            self.enable_data_ready()?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_icm20948_config_registers() {
+    use crate::mock_bus::{MockBus, NoDelay};
+
+    let bus = MockBus::with_bank_select(REG_BANK_SEL);
+    bus.set(0, WHO_AM_I, CHIP_ID);
+    let config = SensorConfig {
+        accel_range: AccelRange::G4,
+        gyro_range: GyroRange::Dps2000,
+        data_rate: DataRate::Hz250,
+    };
+    let sensor = Icm20948::new(bus.spi(), bus.cs(), &mut NoDelay, &config).unwrap();
+
+    assert_eq!(bus.get(0x00, PWR_MGMT_1), PWR_MGMT_1_CLK_AUTO);
+    assert_eq!(bus.get(0x20, GYRO_SMPLRT_DIV), 4);
+    assert_eq!(bus.get(0x20, GYRO_CONFIG_1), 0b0000_0111);
+    assert_eq!(bus.get(0x20, ACCEL_SMPLRT_DIV_2), 4);
+    assert_eq!(bus.get(0x20, ACCEL_CONFIG), 0b0000_0011);
+    // Left in bank 0, where the data registers are
+    assert_eq!(bus.writes().last(), Some(&(0x20, REG_BANK_SEL, 0x00)));
+    assert_eq!(sensor.sample_period(), Duration::from_secs_f32(5.0 / 1125.0));
+}
+
+#[test]
+fn test_icm20948_rejects_other_chips() {
+    use crate::mock_bus::{MockBus, NoDelay};
+
+    let bus = MockBus::with_bank_select(REG_BANK_SEL);
+    bus.set(0, WHO_AM_I, 0x71);
+    let result = Icm20948::new(bus.spi(), bus.cs(), &mut NoDelay, &SensorConfig::default());
+    assert!(matches!(result, Err(SensorError::UnexpectedChipId(0x71))));
+}
+
+#[test]
+fn test_icm20948_read_scaling() {
+    use crate::mock_bus::{MockBus, NoDelay};
+
+    let bus = MockBus::with_bank_select(REG_BANK_SEL);
+    bus.set(0, WHO_AM_I, CHIP_ID);
+    let mut sensor = Icm20948::new(bus.spi(), bus.cs(), &mut NoDelay, &SensorConfig::default()).unwrap();
+    // -1 g on y, 125 deg/s on z
+    for (reg, value) in [(0x2F, 0xC0), (0x30, 0x00), (0x37, 0x40), (0x38, 0x00)] {
+        bus.set(0, reg, value);
+    }
+
+    let sample = sensor.read().unwrap();
+    assert!((sample.accel.y + 1.0).abs() < 1e-6);
+    assert!((sample.gyro.z - 125.0).abs() < 1e-3);
+}
+
+#[test]
+fn test_icm20948_reinit() {
+    use crate::mock_bus::{MockBus, NoDelay};
+
+    let bus = MockBus::with_bank_select(REG_BANK_SEL);
+    bus.set(0, WHO_AM_I, CHIP_ID);
+    let mut sensor = Icm20948::new(bus.spi(), bus.cs(), &mut NoDelay, &SensorConfig::default()).unwrap();
+    sensor.enable_data_ready().unwrap();
+
+    // Power cycled: back to the reset values
+    for (bank, register) in [(0x00, INT_ENABLE_1), (0x20, GYRO_SMPLRT_DIV), (0x20, GYRO_CONFIG_1)] {
+        bus.set(bank, register, 0);
+    }
+    sensor.reinit(&mut NoDelay).unwrap();
+    assert_eq!(bus.get(0x00, INT_ENABLE_1), INT_ENABLE_1_RAW_RDY);
+    assert_eq!(bus.get(0x20, GYRO_SMPLRT_DIV), 4);
+    assert_eq!(bus.get(0x20, GYRO_CONFIG_1), FCHOICE);
+}
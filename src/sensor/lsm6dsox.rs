@@ -0,0 +1,203 @@
+use core::fmt::Debug;
+use core::time::Duration;
+
+use embedded_hal::blocking::{delay::DelayMs, spi};
+use embedded_hal::digital::v2::OutputPin;
+
+use crate::spi_registers::SpiRegisters;
+use super::{scale, AccelRange, DataRate, GyroRange, ImuSample, ImuSensor, SensorConfig, SensorError};
+
+pub const CHIP_ID: u8 = 0x6C;
+
+const INT1_CTRL: u8 = 0x0D;
+const WHO_AM_I: u8 = 0x0F;
+const CTRL1_XL: u8 = 0x10;
+const CTRL2_G: u8 = 0x11;
+const CTRL3_C: u8 = 0x12;
+const CTRL4_C: u8 = 0x13;
+const OUTX_L_G: u8 = 0x22;
+
+const CTRL3_C_SW_RESET: u8 = 0x01;
+const CTRL3_C_IF_INC: u8 = 0x04;
+const CTRL3_C_BDU: u8 = 0x40;
+const CTRL4_C_I2C_DISABLE: u8 = 0x04;
+const INT1_DRDY_XL: u8 = 0x01;
+const INT1_DRDY_G: u8 = 0x02;
+
+pub struct Lsm6dsox<SPI, CS> {
+    regs: SpiRegisters<SPI, CS>,
+    config: SensorConfig,
+    // Register codes
+    odr: u8,
+    accel_fs: u8,
+    gyro_fs: u8,
+    odr_hz: f32,
+    accel_resolution: f32,
+    gyro_resolution: f32,
+    data_ready: bool,
+}
+
+impl<SPI, CS, E, EO> Lsm6dsox<SPI, CS>
+    where SPI: spi::Write<u8, Error = E> + spi::Transfer<u8, Error = E>,
+          CS: OutputPin<Error = EO>,
+          E: Debug,
+          EO: Debug
+{
+    pub fn new<D: DelayMs<u8>>(spi: SPI, cs: CS, delay: &mut D, config: &SensorConfig)
+        -> Result<Self, SensorError>
+    {
+        // ODR register codes, shared by both sensors
+        let (odr, odr_hz) = match config.data_rate {
+            DataRate::Hz100 => (0b0100, 104.0),
+            DataRate::Hz250 => (0b0101, 208.0),
+            DataRate::Hz500 => (0b0110, 416.0),
+            DataRate::Hz1000 => (0b0111, 833.0),
+        };
+        // Full scale codes are not in range order; sensitivities are from the datasheet
+        let (accel_fs, accel_resolution) = match config.accel_range {
+            AccelRange::G2 => (0b00, 0.061e-3),
+            AccelRange::G4 => (0b10, 0.122e-3),
+            AccelRange::G8 => (0b11, 0.244e-3),
+            AccelRange::G16 => (0b01, 0.488e-3),
+        };
+        let (gyro_fs, gyro_resolution) = match config.gyro_range {
+            GyroRange::Dps250 => (0b00, 8.75e-3),
+            GyroRange::Dps500 => (0b01, 17.5e-3),
+            GyroRange::Dps1000 => (0b10, 35.0e-3),
+            GyroRange::Dps2000 => (0b11, 70.0e-3),
+        };
+
+        let mut lsm = Self {
+            regs: SpiRegisters::new(spi, cs),
+            config: *config,
+            odr,
+            odr_hz,
+            accel_fs,
+            gyro_fs,
+            accel_resolution,
+            gyro_resolution,
+            data_ready: false,
+        };
+        lsm.init(delay)?;
+        Ok(lsm)
+    }
+
+    fn init<D: DelayMs<u8> + ?Sized>(&mut self, delay: &mut D) -> Result<(), SensorError> {
+        self.regs.write(CTRL3_C, CTRL3_C_SW_RESET)?;
+        delay.delay_ms(10);
+        self.regs.write(CTRL4_C, CTRL4_C_I2C_DISABLE)?;
+        let who_am_i = self.regs.read(WHO_AM_I)?;
+        if who_am_i != CHIP_ID {
+            return Err(SensorError::UnexpectedChipId(who_am_i));
+        }
+        // Keep output register pairs consistent while they are read out
+        self.regs.write(CTRL3_C, CTRL3_C_BDU | CTRL3_C_IF_INC)?;
+        self.regs.write(CTRL1_XL, (self.odr << 4) | (self.accel_fs << 2))?;
+        self.regs.write(CTRL2_G, (self.odr << 4) | (self.gyro_fs << 2))?;
+        Ok(())
+    }
+}
+
+impl<SPI, CS, E, EO> ImuSensor for Lsm6dsox<SPI, CS>
+    where SPI: spi::Write<u8, Error = E> + spi::Transfer<u8, Error = E>,
+          CS: OutputPin<Error = EO>,
+          E: Debug,
+          EO: Debug
+{
+    fn who_am_i(&mut self) -> Result<u8, SensorError> {
+        Ok(self.regs.read(WHO_AM_I)?)
+    }
+
+    fn read(&mut self) -> Result<ImuSample, SensorError> {
+        // Gyro XYZ followed by accel XYZ, little-endian
+        let mut buffer = [0u8; 12];
+        self.regs.read_many(OUTX_L_G, &mut buffer)?;
+        let word = |i: usize| i16::from_le_bytes([buffer[2 * i], buffer[2 * i + 1]]);
+        Ok(ImuSample {
+            accel: scale([word(3), word(4), word(5)], self.accel_resolution),
+            gyro: scale([word(0), word(1), word(2)], self.gyro_resolution),
+        })
+    }
+
+    fn enable_data_ready(&mut self) -> Result<(), SensorError> {
+        self.regs.modify(INT1_CTRL, |r| r | INT1_DRDY_XL | INT1_DRDY_G)?;
+        self.data_ready = true;
+        Ok(())
+    }
+
+    fn config(&self) -> &SensorConfig {
+        &self.config
+    }
+
+    fn sample_period(&self) -> Duration {
+        Duration::from_secs_f32(1.0 / self.odr_hz)
+    }
+
+    fn reinit(&mut self, delay: &mut dyn DelayMs<u8>) -> Result<(), SensorError> {
+        self.init(delay)?;
+        if self.data_ready {
+            self.enable_data_ready()?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_lsm6dsox_config_registers() {
+    use crate::mock_bus::{MockBus, NoDelay};
+
+    let bus = MockBus::new();
+    bus.set(0, WHO_AM_I, CHIP_ID);
+    let config = SensorConfig {
+        accel_range: AccelRange::G16,
+        gyro_range: GyroRange::Dps500,
+        data_rate: DataRate::Hz1000,
+    };
+    let sensor = Lsm6dsox::new(bus.spi(), bus.cs(), &mut NoDelay, &config).unwrap();
+
+    assert_eq!(bus.writes()[0], (0, CTRL3_C, CTRL3_C_SW_RESET));
+    assert_eq!(bus.get(0, CTRL3_C), 0x44);
+    assert_eq!(bus.get(0, CTRL1_XL), 0x74);
+    assert_eq!(bus.get(0, CTRL2_G), 0x74);
+    assert_eq!(sensor.sample_period(), Duration::from_secs_f32(1.0 / 833.0));
+}
+
+#[test]
+fn test_lsm6dsox_read_scaling() {
+    use crate::mock_bus::{MockBus, NoDelay};
+
+    let bus = MockBus::new();
+    bus.set(0, WHO_AM_I, CHIP_ID);
+    let mut sensor = Lsm6dsox::new(bus.spi(), bus.cs(), &mut NoDelay, &SensorConfig::default()).unwrap();
+    // 1000 LSB on gyro x (8.75 deg/s), 16393 LSB on accel z (~1 g)
+    for (reg, value) in [(0x22, 0xE8), (0x23, 0x03), (0x2C, 0x09), (0x2D, 0x40)] {
+        bus.set(0, reg, value);
+    }
+
+    let sample = sensor.read().unwrap();
+    assert!((sample.gyro.x - 8.75).abs() < 1e-4);
+    assert!((sample.accel.z - 1.0).abs() < 1e-3);
+    assert_eq!(sample.accel.x, 0.0);
+
+    sensor.enable_data_ready().unwrap();
+    assert_eq!(bus.get(0, INT1_CTRL), 0x03);
+}
+
+#[test]
+fn test_lsm6dsox_reinit() {
+    use crate::mock_bus::{MockBus, NoDelay};
+
+    let bus = MockBus::new();
+    bus.set(0, WHO_AM_I, CHIP_ID);
+    let mut sensor = Lsm6dsox::new(bus.spi(), bus.cs(), &mut NoDelay, &SensorConfig::default()).unwrap();
+
+    // Replaced by a different chip
+    bus.set(0, WHO_AM_I, 0x6A);
+    assert!(matches!(sensor.reinit(&mut NoDelay), Err(SensorError::UnexpectedChipId(0x6A))));
+
+    bus.set(0, WHO_AM_I, CHIP_ID);
+    bus.set(0, CTRL1_XL, 0);
+    sensor.reinit(&mut NoDelay).unwrap();
+    assert_eq!(bus.get(0, CTRL1_XL), 0x50);
+    assert_eq!(bus.get(0, INT1_CTRL), 0);
+}
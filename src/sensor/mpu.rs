@@ -0,0 +1,247 @@
+use core::fmt::Debug;
+use core::time::Duration;
+
+use embedded_hal::blocking::{delay::DelayMs, spi};
+use embedded_hal::digital::v2::OutputPin;
+use imu_fusion::FusionVector;
+use mpu9250::{AccelScale, Dlpf, GyroScale, GyroTempDataRate, Imu, InterruptEnable, Mpu9250, MpuConfig,
+              SpiDevice};
+
+use crate::mpu_fifo::Mpu9250Fifo;
+use crate::sample_timing::mpu9250_sample_period;
+use super::{AccelRange, DataRate, GyroRange, ImuSample, ImuSensor, SensorConfig, SensorError};
+
+// Accepted by the driver: MPU9250, MPU9255 and MPU6500
+pub const CHIP_IDS: [u8; 3] = [0x71, 0x73, 0x70];
+
+// Adapter for the `mpu9250` crate driver
+pub struct Mpu9250Sensor<SPI, CS> {
+    mpu: Mpu9250<SpiDevice<SPI, CS>, Imu>,
+    config: SensorConfig,
+    sample_rate_divisor: u8,
+    chip_id: u8,
+    data_ready: bool,
+}
+
+fn mpu_error<E: Debug>(error: mpu9250::Error<E>) -> SensorError {
+    match error {
+        mpu9250::Error::InvalidDevice(id) => SensorError::UnexpectedChipId(id),
+        _ => SensorError::Bus(format!("{:?}", error)),
+    }
+}
+
+fn bus_error<E: Debug>(error: E) -> SensorError {
+    SensorError::Bus(format!("{:?}", error))
+}
+
+fn mpu_config(config: &SensorConfig) -> (MpuConfig<Imu>, u8) {
+    // Output rate is 1 kHz / (1 + divisor), as the DLPF is enabled
+    let sample_rate_divisor = match config.data_rate {
+        DataRate::Hz100 => 9,
+        DataRate::Hz250 => 3,
+        DataRate::Hz500 => 1,
+        DataRate::Hz1000 => 0,
+    };
+    let accel_scale = match config.accel_range {
+        AccelRange::G2 => AccelScale::_2G,
+        AccelRange::G4 => AccelScale::_4G,
+        AccelRange::G8 => AccelScale::_8G,
+        AccelRange::G16 => AccelScale::_16G,
+    };
+    let gyro_scale = match config.gyro_range {
+        GyroRange::Dps250 => GyroScale::_250DPS,
+        GyroRange::Dps500 => GyroScale::_500DPS,
+        GyroRange::Dps1000 => GyroScale::_1000DPS,
+        GyroRange::Dps2000 => GyroScale::_2000DPS,
+    };
+    let mut mpu_config = MpuConfig::imu();
+    mpu_config
+        .gyro_temp_data_rate(GyroTempDataRate::DlpfConf(Dlpf::_0))
+        .sample_rate_divisor(sample_rate_divisor)
+        .accel_scale(accel_scale)
+        .gyro_scale(gyro_scale);
+    (mpu_config, sample_rate_divisor)
+}
+
+impl<SPI, CS, E, EO> Mpu9250Sensor<SPI, CS>
+    where SPI: spi::Write<u8, Error = E> + spi::Transfer<u8, Error = E>,
+          CS: OutputPin<Error = EO>,
+          E: Debug,
+          EO: Debug
+{
+    pub fn new<D: DelayMs<u8>>(spi: SPI, cs: CS, delay: &mut D, config: &SensorConfig)
+        -> Result<Self, SensorError>
+    {
+        let (mut mpu_config, sample_rate_divisor) = mpu_config(config);
+        let mut mpu = Mpu9250::imu(spi, cs, delay, &mut mpu_config).map_err(mpu_error)?;
+        let chip_id = mpu.who_am_i().map_err(bus_error)?;
+
+        Ok(Self {
+            mpu,
+            config: *config,
+            sample_rate_divisor,
+            chip_id,
+            data_ready: false,
+        })
+    }
+
+    // Hands the bus over to the FIFO reader, keeping the current configuration
+    pub fn into_fifo<D: DelayMs<u8>>(self, delay: &mut D) -> Result<Mpu9250Fifo<SPI, CS>, SensorError> {
+        let (spi, cs) = self.mpu.release();
+        let mut fifo = Mpu9250Fifo::new(spi, cs, &self.config);
+        fifo.enable(delay)?;
+        Ok(fifo)
+    }
+}
+
+impl<SPI, CS, E, EO> ImuSensor for Mpu9250Sensor<SPI, CS>
+    where SPI: spi::Write<u8, Error = E> + spi::Transfer<u8, Error = E>,
+          CS: OutputPin<Error = EO>,
+          E: Debug,
+          EO: Debug
+{
+    fn who_am_i(&mut self) -> Result<u8, SensorError> {
+        self.mpu.who_am_i().map_err(bus_error)
+    }
+
+    fn read(&mut self) -> Result<ImuSample, SensorError> {
+        // The driver scales to m/s^2 and rad/s
+        let all = self.mpu.all::<[f32; 3]>().map_err(bus_error)?;
+        Ok(ImuSample {
+            accel: FusionVector::new(all.accel[0], all.accel[1], all.accel[2]) * (1. / mpu9250::G),
+            gyro: FusionVector::new(all.gyro[0], all.gyro[1], all.gyro[2]) * (180. / core::f32::consts::PI),
+        })
+    }
+
+    fn enable_data_ready(&mut self) -> Result<(), SensorError> {
+        self.mpu.enable_interrupts(InterruptEnable::RAW_RDY_EN).map_err(bus_error)?;
+        self.data_ready = true;
+        Ok(())
+    }
+
+    fn config(&self) -> &SensorConfig {
+        &self.config
+    }
+
+    fn sample_period(&self) -> Duration {
+        mpu9250_sample_period(self.sample_rate_divisor)
+    }
+
+    // The driver owns the bus and consumes it on a failed initialization, so the
+    // configuration is written again over the live device instead of resetting it
+    fn reinit(&mut self, _delay: &mut dyn DelayMs<u8>) -> Result<(), SensorError> {
+        let who_am_i = self.mpu.who_am_i().map_err(bus_error)?;
+        if who_am_i != self.chip_id {
+            return Err(SensorError::UnexpectedChipId(who_am_i));
+        }
+        let (mut mpu_config, _) = mpu_config(&self.config);
+        self.mpu.config(&mut mpu_config).map_err(bus_error)?;
+        if self.data_ready {
+            self.enable_data_ready()?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_mpu9250_config_registers() {
+    use crate::mock_bus::{MockBus, NoDelay};
+
+    let bus = MockBus::new();
+    bus.set(0, 0x75, 0x71);
+    let config = SensorConfig {
+        accel_range: AccelRange::G8,
+        gyro_range: GyroRange::Dps1000,
+        data_rate: DataRate::Hz100,
+    };
+    let sensor = Mpu9250Sensor::new(bus.spi(), bus.cs(), &mut NoDelay, &config).unwrap();
+
+    assert_eq!(bus.get(0, 0x19), 9);
+    assert_eq!(bus.get(0, 0x1B) & 0x18, 0x10);
+    assert_eq!(bus.get(0, 0x1C) & 0x18, 0x10);
+    assert_eq!(sensor.sample_period(), Duration::from_millis(10));
+}
+
+#[test]
+fn test_mpu9250_read_scaling() {
+    use crate::mock_bus::{MockBus, NoDelay};
+
+    let bus = MockBus::new();
+    bus.set(0, 0x75, 0x71);
+    let mut sensor = Mpu9250Sensor::new(bus.spi(), bus.cs(), &mut NoDelay, &SensorConfig::default()).unwrap();
+    // 1 g on z at ±2g, 125 deg/s on x at ±250 deg/s
+    for (reg, value) in [(0x3F, 0x40), (0x43, 0x40)] {
+        bus.set(0, reg, value);
+    }
+
+    let sample = sensor.read().unwrap();
+    assert!((sample.accel.z - 1.0).abs() < 1e-3);
+    assert!(sample.accel.x.abs() < 1e-6);
+    assert!((sample.gyro.x - 125.0).abs() < 1e-2);
+}
+
+#[test]
+fn test_mpu9250_init_sequence() {
+    use crate::mock_bus::{MockBus, NoDelay, Transaction};
+
+    const PWR_MGMT_1: u8 = 0x6B;
+    const CONFIG: u8 = 0x1A;
+    const GYRO_CONFIG: u8 = 0x1B;
+    const SMPLRT_DIV: u8 = 0x19;
+    const WHO_AM_I: u8 = 0x75;
+
+    let bus = MockBus::new();
+    bus.set(0, WHO_AM_I, 0x71);
+    // Leftovers from a previous configuration, the DLPF being bypassed
+    bus.set(0, CONFIG, 0x07);
+    bus.set(0, GYRO_CONFIG, 0x03);
+    Mpu9250Sensor::new(bus.spi(), bus.cs(), &mut NoDelay, &SensorConfig::default()).unwrap();
+
+    let transactions = bus.transactions();
+    let position = |wanted: &Transaction| transactions.iter().position(|t| t == wanted).unwrap();
+    let reset = position(&Transaction::Write { bank: 0, register: PWR_MGMT_1, data: vec![0x80] });
+    let clock = position(&Transaction::Write { bank: 0, register: PWR_MGMT_1, data: vec![0x01] });
+    let divisor = position(&Transaction::Write { bank: 0, register: SMPLRT_DIV, data: vec![3] });
+    let who_am_i = position(&Transaction::Read { bank: 0, register: WHO_AM_I, len: 1 });
+    assert!(reset < clock && clock < divisor && divisor < who_am_i);
+
+    // DLPF enabled with the widest bandwidth
+    assert_eq!(bus.get(0, CONFIG) & 0x07, 0);
+    assert_eq!(bus.get(0, GYRO_CONFIG) & 0x03, 0);
+    assert_eq!(bus.get(0, SMPLRT_DIV), 3);
+}
+
+#[test]
+fn test_mpu9250_read_error() {
+    use crate::mock_bus::{MockBus, NoDelay};
+
+    let bus = MockBus::new();
+    bus.set(0, 0x75, 0x73);
+    let mut sensor = Mpu9250Sensor::new(bus.spi(), bus.cs(), &mut NoDelay, &SensorConfig::default()).unwrap();
+
+    bus.fail_next(1);
+    assert!(matches!(sensor.read(), Err(SensorError::Bus(_))));
+    // Transient: the next read goes through
+    assert!(sensor.read().is_ok());
+}
+
+#[test]
+fn test_mpu9250_reinit() {
+    use crate::mock_bus::{MockBus, NoDelay};
+
+    let bus = MockBus::new();
+    bus.set(0, 0x75, 0x71);
+    let mut sensor = Mpu9250Sensor::new(bus.spi(), bus.cs(), &mut NoDelay, &SensorConfig::default()).unwrap();
+    sensor.enable_data_ready().unwrap();
+
+    bus.fail_register(0x75);
+    assert!(matches!(sensor.reinit(&mut NoDelay), Err(SensorError::Bus(_))));
+
+    bus.clear_failures();
+    for register in [0x19, 0x38] {
+        bus.set(0, register, 0);
+    }
+    sensor.reinit(&mut NoDelay).unwrap();
+    assert_eq!(bus.get(0, 0x19), 3);
+    assert_eq!(bus.get(0, 0x38), 0x01);
+}
@@ -0,0 +1,208 @@
+use core::fmt;
+
+use imu_fusion::FusionVector;
+
+use crate::sensor::{expected_chip_ids, ImuSample, SensorConfig, SensorError};
+
+// Readings beyond this fraction of the full scale count as saturated
+const SATURATION_FRACTION: f32 = 0.995;
+
+// Identical consecutive samples tolerated before the sensor counts as stuck.
+// Noise makes exact repeats rare on a live sensor, even at rest.
+const STUCK_LIMIT: u32 = 20;
+
+pub fn validate_chip_id(model: &str, who_am_i: u8) -> Result<(), SensorError> {
+    if expected_chip_ids(model)?.contains(&who_am_i) {
+        Ok(())
+    } else {
+        Err(SensorError::UnexpectedChipId(who_am_i))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    Accel,
+    Gyro,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HealthEvent {
+    // An axis reached the configured full scale
+    Saturated { source: Source, axis: usize },
+    // The sensor kept returning the very same sample
+    Stuck { samples: u32 },
+    // All of the issues above went away
+    Healthy,
+}
+
+impl fmt::Display for HealthEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HealthEvent::Saturated { source, axis } => write!(f, "saturated {:?} {}", source, ["x", "y", "z"][*axis]),
+            HealthEvent::Stuck { samples } => write!(f, "stuck {}", samples),
+            HealthEvent::Healthy => write!(f, "healthy"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct HealthMetrics {
+    pub samples: u32,
+    // Samples with at least one saturated axis
+    pub saturated: u32,
+    // Samples identical to the previous one
+    pub repeated: u32,
+    pub longest_repeat_run: u32,
+}
+
+impl fmt::Display for HealthMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "samples {} saturated {} repeated {} longest_run {}",
+               self.samples, self.saturated, self.repeated, self.longest_repeat_run)
+    }
+}
+
+fn axes(vector: &FusionVector) -> [f32; 3] {
+    [vector.x, vector.y, vector.z]
+}
+
+// Watches the sample stream for readings a working sensor would not produce
+pub struct HealthMonitor {
+    limits: [f32; 2],
+    stuck_limit: u32,
+    saturated: [[bool; 3]; 2],
+    previous: Option<[[f32; 3]; 2]>,
+    repeat_run: u32,
+    unhealthy: bool,
+    events: Vec<HealthEvent>,
+    pub metrics: HealthMetrics,
+}
+
+impl HealthMonitor {
+    pub fn new(config: &SensorConfig) -> Self {
+        Self {
+            limits: [
+                config.accel_range.full_scale() * SATURATION_FRACTION,
+                config.gyro_range.full_scale() * SATURATION_FRACTION,
+            ],
+            stuck_limit: STUCK_LIMIT,
+            saturated: [[false; 3]; 2],
+            previous: None,
+            repeat_run: 0,
+            unhealthy: false,
+            events: Vec::new(),
+            metrics: HealthMetrics::default(),
+        }
+    }
+
+    pub fn with_stuck_limit(mut self, stuck_limit: u32) -> Self {
+        self.stuck_limit = stuck_limit;
+        self
+    }
+
+    // Events since the last call
+    pub fn take_events(&mut self) -> Vec<HealthEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        !self.unhealthy
+    }
+
+    pub fn check(&mut self, sample: &ImuSample) {
+        let values = [axes(&sample.accel), axes(&sample.gyro)];
+        self.metrics.samples += 1;
+
+        let mut any_saturated = false;
+        for (i, source) in [Source::Accel, Source::Gyro].into_iter().enumerate() {
+            for (axis, value) in values[i].iter().enumerate() {
+                let saturated = value.abs() >= self.limits[i];
+                if saturated && !self.saturated[i][axis] {
+                    self.events.push(HealthEvent::Saturated { source, axis });
+                }
+                self.saturated[i][axis] = saturated;
+                any_saturated |= saturated;
+            }
+        }
+        if any_saturated {
+            self.metrics.saturated += 1;
+        }
+
+        if self.previous == Some(values) {
+            self.repeat_run += 1;
+            self.metrics.repeated += 1;
+            self.metrics.longest_repeat_run = self.metrics.longest_repeat_run.max(self.repeat_run);
+            if self.repeat_run == self.stuck_limit {
+                self.events.push(HealthEvent::Stuck { samples: self.repeat_run });
+            }
+        } else {
+            self.repeat_run = 0;
+        }
+        self.previous = Some(values);
+
+        let unhealthy = any_saturated || self.repeat_run >= self.stuck_limit;
+        if self.unhealthy && !unhealthy {
+            self.events.push(HealthEvent::Healthy);
+        }
+        self.unhealthy = unhealthy;
+    }
+}
+
+#[cfg(test)]
+fn sample(accel: [f32; 3], gyro: [f32; 3]) -> ImuSample {
+    ImuSample {
+        accel: FusionVector::new(accel[0], accel[1], accel[2]),
+        gyro: FusionVector::new(gyro[0], gyro[1], gyro[2]),
+    }
+}
+
+#[test]
+fn test_chip_id_validation() {
+    assert!(validate_chip_id("mpu9250", 0x71).is_ok());
+    assert!(validate_chip_id("mpu9250", 0x73).is_ok());
+    assert!(matches!(validate_chip_id("mpu9250", 0xEA), Err(SensorError::UnexpectedChipId(0xEA))));
+    assert!(validate_chip_id("icm20948", 0xEA).is_ok());
+    assert!(matches!(validate_chip_id("lsm6dsox", 0xFF), Err(SensorError::UnexpectedChipId(0xFF))));
+    assert!(matches!(validate_chip_id("bmi270", 0x24), Err(SensorError::UnknownModel)));
+}
+
+#[test]
+fn test_saturation_follows_configured_range() {
+    use crate::sensor::{AccelRange, GyroRange};
+
+    let config = SensorConfig { accel_range: AccelRange::G4, gyro_range: GyroRange::Dps500, ..Default::default() };
+    let mut monitor = HealthMonitor::new(&config);
+
+    // Past the default ±2 g, but within ±4 g
+    monitor.check(&sample([3.0, 0.0, 1.0], [0.0, 0.0, 0.0]));
+    assert!(monitor.take_events().is_empty());
+
+    monitor.check(&sample([3.99, 0.0, 1.0], [0.0, -499.9, 0.0]));
+    monitor.check(&sample([3.99, 0.0, 1.0], [0.0, -499.9, 0.1]));
+    assert_eq!(monitor.take_events(), vec![
+        HealthEvent::Saturated { source: Source::Accel, axis: 0 },
+        HealthEvent::Saturated { source: Source::Gyro, axis: 1 },
+    ]);
+    assert!(!monitor.is_healthy());
+
+    monitor.check(&sample([0.5, 0.0, 1.0], [0.0, 10.0, 0.0]));
+    assert_eq!(monitor.take_events(), vec![HealthEvent::Healthy]);
+    assert_eq!(monitor.metrics.saturated, 2);
+}
+
+#[test]
+fn test_stuck_samples() {
+    let mut monitor = HealthMonitor::new(&SensorConfig::default()).with_stuck_limit(3);
+
+    monitor.check(&sample([0.0, 0.0, 1.0], [0.1, 0.0, 0.0]));
+    for _ in 0..5 {
+        monitor.check(&sample([0.0, 0.0, 1.0], [0.2, 0.0, 0.0]));
+    }
+    assert_eq!(monitor.take_events(), vec![HealthEvent::Stuck { samples: 3 }]);
+    assert!(!monitor.is_healthy());
+
+    monitor.check(&sample([0.0, 0.0, 1.0], [0.3, 0.0, 0.0]));
+    assert_eq!(monitor.take_events(), vec![HealthEvent::Healthy]);
+    assert_eq!(monitor.metrics, HealthMetrics { samples: 7, saturated: 0, repeated: 4, longest_repeat_run: 4 });
+    assert_eq!(monitor.metrics.to_string(), "samples 7 saturated 0 repeated 4 longest_run 4");
+}
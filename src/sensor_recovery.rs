@@ -0,0 +1,261 @@
+use core::fmt;
+
+use embedded_hal::blocking::delay::DelayMs;
+
+use crate::sensor::{ImuSample, ImuSensor, SensorError};
+use crate::state_machine::{FSMError, SensorFSM, SensorStatus};
+
+pub struct RecoveryPolicy {
+    // Consecutive failed reads tolerated before reinitializing the sensor
+    pub max_read_errors: u32,
+    // Reinitializations without a good read in between before giving up on the sensor
+    pub max_reinit_attempts: u32,
+}
+
+impl Default for RecoveryPolicy {
+    fn default() -> Self {
+        Self {
+            max_read_errors: 5,
+            max_reinit_attempts: 3,
+        }
+    }
+}
+
+// Notable changes in the sensor condition, to be reported
+#[derive(Debug, Clone, PartialEq)]
+pub enum SensorEvent {
+    ReadError { consecutive: u32, error: String },
+    Recovering { attempt: u32 },
+    Recovered,
+    Faulted { error: String },
+}
+
+impl fmt::Display for SensorEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SensorEvent::ReadError { consecutive, error } => write!(f, "read_error {} {}", consecutive, error),
+            SensorEvent::Recovering { attempt } => write!(f, "recovering {}", attempt),
+            SensorEvent::Recovered => write!(f, "recovered"),
+            SensorEvent::Faulted { error } => write!(f, "faulted {}", error),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RecoveryStats {
+    pub read_errors: u32,
+    pub reinits: u32,
+    pub recoveries: u32,
+}
+
+// Reads the sensor once per sampling period, going through the degraded and
+// recovering states of `SensorFSM` on failures instead of bailing out.
+pub struct SensorSupervisor {
+    fsm: SensorFSM,
+    policy: RecoveryPolicy,
+    consecutive_errors: u32,
+    reinit_attempts: u32,
+    events: Vec<SensorEvent>,
+    pub stats: RecoveryStats,
+}
+
+impl SensorSupervisor {
+    // `fsm` is expected to be sampling already
+    pub fn new(fsm: SensorFSM, policy: RecoveryPolicy) -> Self {
+        Self {
+            fsm,
+            policy,
+            consecutive_errors: 0,
+            reinit_attempts: 0,
+            events: Vec::new(),
+            stats: RecoveryStats::default(),
+        }
+    }
+
+    pub fn status(&self) -> SensorStatus {
+        self.fsm.status()
+    }
+
+    pub fn is_faulted(&self) -> bool {
+        self.fsm.status() == SensorStatus::Faulted
+    }
+
+    pub fn pause(&mut self) -> Result<(), FSMError> {
+        self.fsm.pause()
+    }
+
+    pub fn resume(&mut self) -> Result<(), FSMError> {
+        self.consecutive_errors = 0;
+        self.fsm.resume()
+    }
+
+    // Events since the last call
+    pub fn take_events(&mut self) -> Vec<SensorEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    // Returns a sample when the read went through. While recovering, each call
+    // makes one reinitialization attempt instead of reading.
+    pub fn sample(&mut self, sensor: &mut dyn ImuSensor, delay: &mut dyn DelayMs<u8>)
+        -> Result<Option<ImuSample>, FSMError>
+    {
+        self.supervise(sensor, delay, |sensor, _| sensor.read(), |sensor, delay| sensor.reinit(delay))
+    }
+
+    // Same as `sample` for any way of reading `sensor`, e.g. draining its FIFO,
+    // with `reinit` bringing it back after repeated failures
+    pub fn supervise<S: ?Sized, D: ?Sized, T>(&mut self, sensor: &mut S, delay: &mut D,
+                                             read: impl FnOnce(&mut S, &mut D) -> Result<T, SensorError>,
+                                             reinit: impl FnOnce(&mut S, &mut D) -> Result<(), SensorError>)
+        -> Result<Option<T>, FSMError>
+    {
+        match self.fsm.status() {
+            SensorStatus::Sampling | SensorStatus::Degraded => match read(sensor, delay) {
+                Ok(reading) => {
+                    if self.fsm.status() == SensorStatus::Degraded {
+                        self.fsm.read_recovered()?;
+                    }
+                    self.consecutive_errors = 0;
+                    // A reinitialization only counts as successful once data flows again
+                    self.reinit_attempts = 0;
+                    Ok(Some(reading))
+                },
+                Err(error) => {
+                    self.read_failed(error)?;
+                    Ok(None)
+                },
+            },
+            SensorStatus::Recovering => {
+                self.recover(reinit(sensor, delay))?;
+                Ok(None)
+            },
+            SensorStatus::Paused | SensorStatus::Faulted | SensorStatus::Shutdown => Ok(None),
+            _ => Err(FSMError::InvalidTransition),
+        }
+    }
+
+    fn read_failed(&mut self, error: SensorError) -> Result<(), FSMError> {
+        self.stats.read_errors += 1;
+        self.consecutive_errors += 1;
+        let error = format!("{:?}", error);
+        self.events.push(SensorEvent::ReadError {
+            consecutive: self.consecutive_errors,
+            error: error.clone(),
+        });
+        self.fsm.read_failed()?;
+        if self.consecutive_errors >= self.policy.max_read_errors {
+            self.fsm.recovery_started()?;
+            // Reinitializing went through every time, but no data came out of it
+            if self.reinit_attempts >= self.policy.max_reinit_attempts {
+                self.events.push(SensorEvent::Faulted { error });
+                self.fsm.recovery_failed()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn recover(&mut self, reinit: Result<(), SensorError>) -> Result<(), FSMError> {
+        self.reinit_attempts += 1;
+        self.stats.reinits += 1;
+        self.events.push(SensorEvent::Recovering { attempt: self.reinit_attempts });
+        match reinit {
+            Ok(()) => {
+                self.consecutive_errors = 0;
+                self.stats.recoveries += 1;
+                self.events.push(SensorEvent::Recovered);
+                self.fsm.recovery_complete()
+            },
+            Err(error) if self.reinit_attempts >= self.policy.max_reinit_attempts => {
+                self.events.push(SensorEvent::Faulted { error: format!("{:?}", error) });
+                self.fsm.recovery_failed()
+            },
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+fn sampling_supervisor(policy: RecoveryPolicy) -> SensorSupervisor {
+    let mut fsm = SensorFSM::new();
+    fsm.bootup_complete().unwrap();
+    fsm.peripherals_complete().unwrap();
+    fsm.calibration_complete().unwrap();
+    SensorSupervisor::new(fsm, policy)
+}
+
+#[test]
+fn test_transient_errors_are_retried() {
+    use crate::fake_sensor::FakeSensor;
+    use crate::mock_bus::NoDelay;
+
+    let mut supervisor = sampling_supervisor(RecoveryPolicy::default());
+    let mut sensor = FakeSensor::new();
+    sensor.fail_reads(2);
+
+    assert!(supervisor.sample(&mut sensor, &mut NoDelay).unwrap().is_none());
+    assert_eq!(supervisor.status(), SensorStatus::Degraded);
+    assert!(supervisor.sample(&mut sensor, &mut NoDelay).unwrap().is_none());
+    assert!(supervisor.sample(&mut sensor, &mut NoDelay).unwrap().is_some());
+    assert_eq!(supervisor.status(), SensorStatus::Sampling);
+
+    assert_eq!(sensor.reinits, 0);
+    assert_eq!(supervisor.stats.read_errors, 2);
+    let events = supervisor.take_events();
+    assert_eq!(events.len(), 2);
+    assert!(matches!(events[1], SensorEvent::ReadError { consecutive: 2, .. }));
+}
+
+#[test]
+fn test_repeated_errors_reinit_the_sensor() {
+    use crate::fake_sensor::FakeSensor;
+    use crate::mock_bus::NoDelay;
+
+    let mut supervisor = sampling_supervisor(RecoveryPolicy { max_read_errors: 3, max_reinit_attempts: 3 });
+    let mut sensor = FakeSensor::new();
+    sensor.fail_reads(3);
+    sensor.fail_reinits(1);
+
+    for _ in 0..3 {
+        assert!(supervisor.sample(&mut sensor, &mut NoDelay).unwrap().is_none());
+    }
+    assert_eq!(supervisor.status(), SensorStatus::Recovering);
+    // First attempt fails, the second one goes through
+    supervisor.sample(&mut sensor, &mut NoDelay).unwrap();
+    assert_eq!(supervisor.status(), SensorStatus::Recovering);
+    supervisor.sample(&mut sensor, &mut NoDelay).unwrap();
+    assert_eq!(supervisor.status(), SensorStatus::Sampling);
+    assert!(supervisor.sample(&mut sensor, &mut NoDelay).unwrap().is_some());
+
+    assert_eq!(sensor.reads, 4);
+    assert_eq!(sensor.reinits, 2);
+    assert_eq!(supervisor.stats, RecoveryStats { read_errors: 3, reinits: 2, recoveries: 1 });
+    let events = supervisor.take_events();
+    assert_eq!(&events[3..], &[
+        SensorEvent::Recovering { attempt: 1 },
+        SensorEvent::Recovering { attempt: 2 },
+        SensorEvent::Recovered,
+    ]);
+}
+
+#[test]
+fn test_unrecoverable_sensor_faults() {
+    use crate::fake_sensor::FakeSensor;
+    use crate::mock_bus::NoDelay;
+
+    let mut supervisor = sampling_supervisor(RecoveryPolicy { max_read_errors: 2, max_reinit_attempts: 2 });
+    let mut sensor = FakeSensor::new();
+    // Reinitialization succeeds but the reads keep failing
+    sensor.fail_reads(u32::MAX);
+    sensor.fail_reinits(1);
+
+    for _ in 0..20 {
+        supervisor.sample(&mut sensor, &mut NoDelay).unwrap();
+    }
+    assert!(supervisor.is_faulted());
+    // Two failed reads, a failed and a successful reinitialization, then two
+    // more failed reads use up the attempts
+    assert_eq!(sensor.reads, 4);
+    assert_eq!(sensor.reinits, 2);
+    let events = supervisor.take_events();
+    assert_eq!(events.last().unwrap().to_string(), "faulted Bus(\"injected read error\")");
+}
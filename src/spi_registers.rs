@@ -0,0 +1,105 @@
+use embedded_hal::blocking::spi;
+use embedded_hal::digital::v2::OutputPin;
+
+// Register access over SPI with a manually driven chip select, using the same
+// framing as the `mpu9250` crate: the first byte is the register address, with
+// the MSB set for reads.
+const READ: u8 = 0x80;
+
+// Largest single SPI transaction, data is read in chunks of this size
+const CHUNK_SIZE: usize = 32;
+
+#[derive(Debug)]
+pub enum BusError<E, EO> {
+    Spi(E),
+    ChipSelect(EO),
+}
+
+pub struct SpiRegisters<SPI, CS> {
+    spi: SPI,
+    cs: CS,
+}
+
+impl<SPI, CS, E, EO> SpiRegisters<SPI, CS>
+    where SPI: spi::Write<u8, Error = E> + spi::Transfer<u8, Error = E>,
+          CS: OutputPin<Error = EO>
+{
+    pub fn new(spi: SPI, cs: CS) -> Self {
+        Self { spi, cs }
+    }
+
+    pub fn release(self) -> (SPI, CS) {
+        (self.spi, self.cs)
+    }
+
+    pub fn read(&mut self, reg: u8) -> Result<u8, BusError<E, EO>> {
+        let mut value = [0u8; 1];
+        self.read_many(reg, &mut value)?;
+        Ok(value[0])
+    }
+
+    pub fn read_many(&mut self, reg: u8, data: &mut [u8]) -> Result<(), BusError<E, EO>> {
+        self.cs.set_low().map_err(BusError::ChipSelect)?;
+        let result = self.spi.write(&[reg | READ])
+            .and_then(|_| data.chunks_mut(CHUNK_SIZE)
+                .try_for_each(|chunk| self.spi.transfer(chunk).map(|_| ())));
+        self.cs.set_high().map_err(BusError::ChipSelect)?;
+        result.map_err(BusError::Spi)
+    }
+
+    pub fn write(&mut self, reg: u8, value: u8) -> Result<(), BusError<E, EO>> {
+        self.cs.set_low().map_err(BusError::ChipSelect)?;
+        let result = self.spi.write(&[reg & !READ, value]);
+        self.cs.set_high().map_err(BusError::ChipSelect)?;
+        result.map_err(BusError::Spi)
+    }
+
+    pub fn modify<F>(&mut self, reg: u8, f: F) -> Result<(), BusError<E, EO>>
+        where F: FnOnce(u8) -> u8
+    {
+        let value = self.read(reg)?;
+        self.write(reg, f(value))
+    }
+}
+
+#[test]
+fn test_read_many_is_one_transaction() {
+    use crate::mock_bus::{MockBus, Transaction};
+
+    let bus = MockBus::new();
+    for i in 0..40 {
+        bus.set(0, 0x10 + i, i);
+    }
+    let mut regs = SpiRegisters::new(bus.spi(), bus.cs());
+    let mut data = [0u8; 40];
+    regs.read_many(0x10, &mut data).unwrap();
+
+    assert_eq!(data[39], 39);
+    assert_eq!(bus.transactions(), vec![Transaction::Read { bank: 0, register: 0x10, len: 40 }]);
+}
+
+#[test]
+fn test_chip_select_released_on_error() {
+    use crate::mock_bus::MockBus;
+
+    let bus = MockBus::new();
+    let mut regs = SpiRegisters::new(bus.spi(), bus.cs());
+    bus.fail_next(1);
+    assert!(matches!(regs.write(0x10, 1), Err(BusError::Spi(_))));
+    assert!(!bus.selected());
+    assert_eq!(bus.get(0, 0x10), 0);
+}
+
+#[test]
+fn test_chip_select_error() {
+    use crate::mock_bus::{MockBus, MockPin};
+
+    let bus = MockBus::new();
+    let cs = MockPin::new();
+    let mut regs = SpiRegisters::new(bus.spi(), cs.clone());
+    cs.set_failing(true);
+    assert!(matches!(regs.read(0x10), Err(BusError::ChipSelect(_))));
+
+    assert!(cs.levels().is_empty());
+    assert!(bus.transactions().is_empty());
+}
@@ -1,115 +1,416 @@
+use core::fmt::Debug;
+use std::collections::VecDeque;
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 #[derive(Debug)]
 pub enum FSMError {
     InvalidTransition,
 }
 
-#[derive(Clone, Copy)]
+// Transitions kept for inspection, oldest ones are discarded first
+const HISTORY_LEN: usize = 32;
+
+// A set of states along with the inputs that move between them
+pub trait Lifecycle: Copy + PartialEq + Debug + Send + 'static {
+    type Input: Copy + PartialEq + Debug + Send + 'static;
+
+    // State reached when `input` happens in this state, if allowed
+    fn next(self, input: Self::Input) -> Option<Self>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transition<S: Lifecycle> {
+    pub from: S,
+    pub to: S,
+    pub input: S::Input,
+    pub at: Instant,
+}
+
+struct Shared<S: Lifecycle> {
+    state: S,
+    history: VecDeque<Transition<S>>,
+    subscribers: Vec<Sender<Transition<S>>>,
+}
+
+// Read-only handle on a state machine, which can be moved to other threads
+pub struct Observer<S: Lifecycle> {
+    shared: Arc<Mutex<Shared<S>>>,
+}
+
+impl<S: Lifecycle> Clone for Observer<S> {
+    fn clone(&self) -> Self {
+        Self { shared: self.shared.clone() }
+    }
+}
+
+impl<S: Lifecycle> Observer<S> {
+    pub fn state(&self) -> S {
+        self.shared.lock().unwrap().state
+    }
+
+    pub fn history(&self) -> Vec<Transition<S>> {
+        self.shared.lock().unwrap().history.iter().copied().collect()
+    }
+
+    // Every transition from now on is sent to the returned receiver, until it is dropped
+    pub fn subscribe(&self) -> Receiver<Transition<S>> {
+        let (tx, rx) = channel();
+        self.shared.lock().unwrap().subscribers.push(tx);
+        rx
+    }
+}
+
+pub struct StateMachine<S: Lifecycle> {
+    observer: Observer<S>,
+}
+
+impl<S: Lifecycle> StateMachine<S> {
+    pub fn new(initial: S) -> Self {
+        Self {
+            observer: Observer {
+                shared: Arc::new(Mutex::new(Shared {
+                    state: initial,
+                    history: VecDeque::with_capacity(HISTORY_LEN),
+                    subscribers: Vec::new(),
+                })),
+            },
+        }
+    }
+
+    pub fn state(&self) -> S {
+        self.observer.state()
+    }
+
+    pub fn observer(&self) -> Observer<S> {
+        self.observer.clone()
+    }
+
+    pub fn handle(&mut self, input: S::Input) -> Result<S, FSMError> {
+        let mut shared = self.observer.shared.lock().unwrap();
+        let to = shared.state.next(input).ok_or(FSMError::InvalidTransition)?;
+        let transition = Transition { from: shared.state, to, input, at: Instant::now() };
+        shared.state = to;
+        if shared.history.len() == HISTORY_LEN {
+            shared.history.pop_front();
+        }
+        shared.history.push_back(transition);
+        shared.subscribers.retain(|subscriber| subscriber.send(transition).is_ok());
+        Ok(to)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum SensorStatus {
     Bootup,
     PeripheralSetup,
+    Calibrating,
     Sampling,
+    // Acquisition suspended on request
+    Paused,
+    // Reads are failing, but the sensor has not been reinitialized yet
+    Degraded,
+    Recovering,
+    // Recovery gave up, sampling has stopped
+    Faulted,
+    Shutdown,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SensorInput {
+    BootupComplete,
+    PeripheralsComplete,
+    CalibrationComplete,
+    Pause,
+    Resume,
+    ReadFailed,
+    ReadRecovered,
+    RecoveryStarted,
+    RecoveryComplete,
+    RecoveryFailed,
+    Shutdown,
+}
+
+impl Lifecycle for SensorStatus {
+    type Input = SensorInput;
+
+    fn next(self, input: SensorInput) -> Option<Self> {
+        use SensorInput as I;
+        use SensorStatus as S;
+        match (self, input) {
+            (S::Bootup, I::BootupComplete) => Some(S::PeripheralSetup),
+            (S::PeripheralSetup, I::PeripheralsComplete) => Some(S::Calibrating),
+            (S::Calibrating, I::CalibrationComplete) => Some(S::Sampling),
+            (S::Sampling | S::Degraded, I::Pause) => Some(S::Paused),
+            (S::Paused, I::Resume) => Some(S::Sampling),
+            (S::Sampling | S::Degraded, I::ReadFailed) => Some(S::Degraded),
+            (S::Degraded, I::ReadRecovered) => Some(S::Sampling),
+            (S::Degraded, I::RecoveryStarted) => Some(S::Recovering),
+            (S::Recovering, I::RecoveryComplete) => Some(S::Sampling),
+            (S::Recovering, I::RecoveryFailed) => Some(S::Faulted),
+            (S::Shutdown, I::Shutdown) => None,
+            (_, I::Shutdown) => Some(S::Shutdown),
+            _ => None,
+        }
+    }
 }
 
 pub struct SensorFSM {
-    state: SensorStatus,
+    machine: StateMachine<SensorStatus>,
+}
+
+impl Default for SensorFSM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SensorFSM {
 
     pub fn new() -> Self {
         Self {
-            state: SensorStatus::Bootup,
+            machine: StateMachine::new(SensorStatus::Bootup),
         }
     }
 
+    pub fn status(&self) -> SensorStatus {
+        self.machine.state()
+    }
+
+    pub fn get_observer(&self) -> Observer<SensorStatus> {
+        self.machine.observer()
+    }
+
+    fn handle(&mut self, input: SensorInput) -> Result<(), FSMError> {
+        self.machine.handle(input).map(|_| ())
+    }
+
     pub fn bootup_complete(&mut self) -> Result<(), FSMError> {
-        match self.state {
-            SensorStatus::Bootup => {
-                self.state = SensorStatus::PeripheralSetup;
-                Ok(())
-            },
-            _ => Err(FSMError::InvalidTransition)
-        }
+        self.handle(SensorInput::BootupComplete)
     }
 
     pub fn peripherals_complete(&mut self) -> Result<(), FSMError> {
-        match self.state {
-            SensorStatus::PeripheralSetup => {
-                self.state = SensorStatus::Sampling;
-                Ok(())
-            },
-            _ => Err(FSMError::InvalidTransition)
-        }
+        self.handle(SensorInput::PeripheralsComplete)
+    }
+
+    pub fn calibration_complete(&mut self) -> Result<(), FSMError> {
+        self.handle(SensorInput::CalibrationComplete)
+    }
+
+    pub fn pause(&mut self) -> Result<(), FSMError> {
+        self.handle(SensorInput::Pause)
+    }
+
+    pub fn resume(&mut self) -> Result<(), FSMError> {
+        self.handle(SensorInput::Resume)
+    }
+
+    pub fn read_failed(&mut self) -> Result<(), FSMError> {
+        self.handle(SensorInput::ReadFailed)
+    }
+
+    pub fn read_recovered(&mut self) -> Result<(), FSMError> {
+        self.handle(SensorInput::ReadRecovered)
+    }
+
+    pub fn recovery_started(&mut self) -> Result<(), FSMError> {
+        self.handle(SensorInput::RecoveryStarted)
+    }
+
+    pub fn recovery_complete(&mut self) -> Result<(), FSMError> {
+        self.handle(SensorInput::RecoveryComplete)
+    }
+
+    pub fn recovery_failed(&mut self) -> Result<(), FSMError> {
+        self.handle(SensorInput::RecoveryFailed)
+    }
+
+    pub fn shutdown(&mut self) -> Result<(), FSMError> {
+        self.handle(SensorInput::Shutdown)
     }
 }
 
+// State of one network link, Wi-Fi or the MQTT broker session
 #[derive(Clone, Copy, Debug, PartialEq)]
-pub enum ConnectionStatus {
-    Bootup,
-    PeripheralSetup,
+pub enum LinkStatus {
+    Down,
     Connecting,
+    Up,
+    // Waiting before the next connection attempt
+    Backoff,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkInput {
+    Connect,
     Connected,
+    Failed,
+    Dropped,
+    // The link it depends on went away
+    Reset,
 }
 
+impl Lifecycle for LinkStatus {
+    type Input = LinkInput;
+
+    fn next(self, input: LinkInput) -> Option<Self> {
+        use LinkInput as I;
+        use LinkStatus as S;
+        match (self, input) {
+            (S::Down | S::Backoff, I::Connect) => Some(S::Connecting),
+            (S::Connecting, I::Connected) => Some(S::Up),
+            (S::Connecting, I::Failed) => Some(S::Backoff),
+            (S::Up, I::Dropped) => Some(S::Backoff),
+            (S::Connecting | S::Up | S::Backoff, I::Reset) => Some(S::Down),
+            _ => None,
+        }
+    }
+}
+
+// Wi-Fi and the broker session are tracked separately, MQTT can only be up
+// while Wi-Fi is
 pub struct ConnectionFSM {
-    state: Arc<Mutex<ConnectionStatus>>,
+    wifi: StateMachine<LinkStatus>,
+    mqtt: StateMachine<LinkStatus>,
+}
+
+impl Default for ConnectionFSM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ConnectionFSM {
 
     pub fn new() -> Self {
         Self {
-            state: Arc::new(Mutex::new(ConnectionStatus::Bootup)),
+            wifi: StateMachine::new(LinkStatus::Down),
+            mqtt: StateMachine::new(LinkStatus::Down),
         }
     }
 
-    pub fn get_observer(&self) -> Arc<Mutex<ConnectionStatus>> {
-        self.state.clone()
+    pub fn wifi_status(&self) -> LinkStatus {
+        self.wifi.state()
     }
 
-    pub fn bootup_complete(&mut self) -> Result<(), FSMError> {
-        let mut st = self.state.lock().unwrap();
-        match *st {
-            ConnectionStatus::Bootup => {
-                *st = ConnectionStatus::PeripheralSetup;
-                Ok(())
-            },
-            _ => Err(FSMError::InvalidTransition)
-        }
+    pub fn mqtt_status(&self) -> LinkStatus {
+        self.mqtt.state()
     }
 
-    pub fn peripherals_complete(&mut self) -> Result<(), FSMError> {
-        let mut st = self.state.lock().unwrap();
-        match *st {
-            ConnectionStatus::PeripheralSetup => {
-                *st = ConnectionStatus::Connecting;
-                Ok(())
-            }
-            _ => Err(FSMError::InvalidTransition)
-        }
+    pub fn wifi_observer(&self) -> Observer<LinkStatus> {
+        self.wifi.observer()
     }
 
-    pub fn connected(&mut self) -> Result<(), FSMError> {
-        let mut st = self.state.lock().unwrap();
-        match *st {
-            ConnectionStatus::Connecting => {
-                *st = ConnectionStatus::Connected;
-                Ok(())
-            },
-            _ => Err(FSMError::InvalidTransition),
-        }
+    pub fn mqtt_observer(&self) -> Observer<LinkStatus> {
+        self.mqtt.observer()
     }
 
-    pub fn disconnected(&mut self) -> Result<(), FSMError> {
-        let mut st = self.state.lock().unwrap();
-        match *st {
-            ConnectionStatus::Connected => {
-                *st = ConnectionStatus::Connecting;
-                Ok(())
-            },
-            _ => Err(FSMError::InvalidTransition),
+    pub fn wifi(&mut self, input: LinkInput) -> Result<(), FSMError> {
+        self.wifi.handle(input).map(|_| ())
+    }
+
+    pub fn mqtt(&mut self, input: LinkInput) -> Result<(), FSMError> {
+        self.mqtt.handle(input).map(|_| ())
+    }
+}
+
+// Checks every state/input pair: those listed in `allowed` must lead to the
+// given state, every other one must be rejected and leave the state unchanged
+#[cfg(test)]
+fn check_transitions<S: Lifecycle>(states: &[S], inputs: &[S::Input], allowed: &[(S, S::Input, S)]) {
+    for &from in states {
+        for &input in inputs {
+            let expected = allowed.iter()
+                .find(|(state, allowed_input, _)| *state == from && *allowed_input == input)
+                .map(|(_, _, to)| *to);
+            assert_eq!(from.next(input), expected, "{:?} on {:?}", from, input);
+
+            let mut machine = StateMachine::new(from);
+            assert_eq!(machine.handle(input).ok(), expected);
+            assert_eq!(machine.state(), expected.unwrap_or(from));
         }
     }
 }
+
+#[test]
+fn test_sensor_transitions() {
+    use SensorInput as I;
+    use SensorStatus as S;
+
+    let states = [S::Bootup, S::PeripheralSetup, S::Calibrating, S::Sampling, S::Paused, S::Degraded,
+                  S::Recovering, S::Faulted, S::Shutdown];
+    let inputs = [I::BootupComplete, I::PeripheralsComplete, I::CalibrationComplete, I::Pause, I::Resume,
+                  I::ReadFailed, I::ReadRecovered, I::RecoveryStarted, I::RecoveryComplete, I::RecoveryFailed,
+                  I::Shutdown];
+    let mut allowed = vec![
+        (S::Bootup, I::BootupComplete, S::PeripheralSetup),
+        (S::PeripheralSetup, I::PeripheralsComplete, S::Calibrating),
+        (S::Calibrating, I::CalibrationComplete, S::Sampling),
+        (S::Sampling, I::Pause, S::Paused),
+        (S::Degraded, I::Pause, S::Paused),
+        (S::Paused, I::Resume, S::Sampling),
+        (S::Sampling, I::ReadFailed, S::Degraded),
+        (S::Degraded, I::ReadFailed, S::Degraded),
+        (S::Degraded, I::ReadRecovered, S::Sampling),
+        (S::Degraded, I::RecoveryStarted, S::Recovering),
+        (S::Recovering, I::RecoveryComplete, S::Sampling),
+        (S::Recovering, I::RecoveryFailed, S::Faulted),
+    ];
+    // Shutting down is possible from anywhere, but only once
+    allowed.extend(states.iter().filter(|s| **s != S::Shutdown).map(|s| (*s, I::Shutdown, S::Shutdown)));
+    check_transitions(&states, &inputs, &allowed);
+}
+
+#[test]
+fn test_link_transitions() {
+    use LinkInput as I;
+    use LinkStatus as S;
+
+    let states = [S::Down, S::Connecting, S::Up, S::Backoff];
+    let inputs = [I::Connect, I::Connected, I::Failed, I::Dropped, I::Reset];
+    let allowed = [
+        (S::Down, I::Connect, S::Connecting),
+        (S::Backoff, I::Connect, S::Connecting),
+        (S::Connecting, I::Connected, S::Up),
+        (S::Connecting, I::Failed, S::Backoff),
+        (S::Up, I::Dropped, S::Backoff),
+        (S::Connecting, I::Reset, S::Down),
+        (S::Up, I::Reset, S::Down),
+        (S::Backoff, I::Reset, S::Down),
+    ];
+    check_transitions(&states, &inputs, &allowed);
+}
+
+#[test]
+fn test_history_and_subscribers() {
+    let mut fsm = SensorFSM::new();
+    let observer = fsm.get_observer();
+    let subscription = observer.subscribe();
+
+    fsm.bootup_complete().unwrap();
+    fsm.peripherals_complete().unwrap();
+    assert!(fsm.resume().is_err());
+    fsm.calibration_complete().unwrap();
+
+    let transitions: Vec<_> = subscription.try_iter().map(|t| (t.from, t.to)).collect();
+    assert_eq!(transitions, vec![
+        (SensorStatus::Bootup, SensorStatus::PeripheralSetup),
+        (SensorStatus::PeripheralSetup, SensorStatus::Calibrating),
+        (SensorStatus::Calibrating, SensorStatus::Sampling),
+    ]);
+    let history = observer.history();
+    assert_eq!(history.len(), 3);
+    assert_eq!(history[2].input, SensorInput::CalibrationComplete);
+    assert!(history.windows(2).all(|pair| pair[0].at <= pair[1].at));
+    assert_eq!(observer.state(), SensorStatus::Sampling);
+
+    // Dropped subscribers are forgotten, the history only keeps the latest transitions
+    drop(subscription);
+    for _ in 0..HISTORY_LEN {
+        fsm.pause().unwrap();
+        fsm.resume().unwrap();
+    }
+    assert_eq!(observer.history().len(), HISTORY_LEN);
+    assert_eq!(observer.history()[0].input, SensorInput::Pause);
+}
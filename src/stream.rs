@@ -0,0 +1,273 @@
+use imu_fusion::FusionVector;
+
+/* Stream frames, all fields little endian:
+ *
+ *   u8  version
+ *   u8  content, 0 raw or 1 fused
+ *   u16 sequence number, wrapping, one per frame
+ *   u16 decimation, sensor samples per streamed sample
+ *   u8  samples in the frame
+ *   then for each sample, the fields below as i16
+ *
+ * Raw samples carry the accelerometer and gyroscope readings, fused ones add
+ * the euler angles and the linear acceleration. Readings are in the axes of
+ * the device once mounted, see `mounting`, like everything the pipeline
+ * detects, so that captures train the gesture classifier in the frame it then
+ * runs in. Telemetry records follow the same rule.
+ */
+pub const VERSION: u8 = 1;
+const HEADER_LEN: usize = 7;
+
+// Fixed point scales, so that the fields fit an i16
+pub const ACCEL_SCALE: f32 = 1000.0; // mg
+pub const GYRO_SCALE: f32 = 10.0; // 0.1 deg/s
+pub const EULER_SCALE: f32 = 100.0; // 0.01 deg
+pub const LINEAR_ACCEL_SCALE: f32 = 100.0; // cm/s^2
+
+// Keeps frames well under the usual MQTT buffer size
+pub const SAMPLES_PER_FRAME: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamContent {
+    Raw,
+    Fused,
+}
+
+impl StreamContent {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "raw" => Some(StreamContent::Raw),
+            "fused" => Some(StreamContent::Fused),
+            _ => None,
+        }
+    }
+
+    fn fields(&self) -> usize {
+        match self {
+            StreamContent::Raw => 6,
+            StreamContent::Fused => 12,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StreamConfig {
+    pub content: StreamContent,
+    pub decimation: u16,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct StreamSample {
+    // g
+    pub accel: [f32; 3],
+    // deg/s
+    pub gyro: [f32; 3],
+    // roll, pitch, yaw in deg
+    pub euler: [f32; 3],
+    // m/s^2
+    pub linear_accel: [f32; 3],
+}
+
+pub fn axes(vector: &FusionVector) -> [f32; 3] {
+    [vector.x, vector.y, vector.z]
+}
+
+fn to_fixed(value: f32, scale: f32) -> i16 {
+    (value * scale).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
+
+// Batches every `decimation`th sample into frames
+pub struct StreamEncoder {
+    config: StreamConfig,
+    sequence: u16,
+    skipped: u16,
+    samples: u8,
+    frame: Vec<u8>,
+}
+
+impl StreamEncoder {
+    pub fn new(config: StreamConfig) -> Self {
+        let decimation = config.decimation.max(1);
+        Self {
+            config: StreamConfig { decimation, ..config },
+            sequence: 0,
+            skipped: decimation - 1,
+            samples: 0,
+            frame: Vec::with_capacity(HEADER_LEN + SAMPLES_PER_FRAME * config.content.fields() * 2),
+        }
+    }
+
+    pub fn config(&self) -> StreamConfig {
+        self.config
+    }
+
+    // Returns a frame once full
+    pub fn push(&mut self, sample: &StreamSample) -> Option<Vec<u8>> {
+        self.skipped += 1;
+        if self.skipped < self.config.decimation {
+            return None;
+        }
+        self.skipped = 0;
+
+        if self.samples == 0 {
+            self.frame.clear();
+            self.frame.push(VERSION);
+            self.frame.push(match self.config.content {
+                StreamContent::Raw => 0,
+                StreamContent::Fused => 1,
+            });
+            self.frame.extend_from_slice(&self.sequence.to_le_bytes());
+            self.frame.extend_from_slice(&self.config.decimation.to_le_bytes());
+            self.frame.push(0);
+        }
+        let fields = [(sample.accel, ACCEL_SCALE), (sample.gyro, GYRO_SCALE),
+                      (sample.euler, EULER_SCALE), (sample.linear_accel, LINEAR_ACCEL_SCALE)];
+        for (values, scale) in fields.iter().take(self.config.content.fields() / 3) {
+            for value in values {
+                self.frame.extend_from_slice(&to_fixed(*value, *scale).to_le_bytes());
+            }
+        }
+        self.samples += 1;
+        self.frame[HEADER_LEN - 1] = self.samples;
+
+        if self.samples as usize == SAMPLES_PER_FRAME {
+            self.samples = 0;
+            self.sequence = self.sequence.wrapping_add(1);
+            Some(self.frame.clone())
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamError {
+    Truncated,
+    UnknownVersion(u8),
+    UnknownContent(u8),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamFrame {
+    pub content: StreamContent,
+    pub sequence: u16,
+    pub decimation: u16,
+    pub samples: Vec<StreamSample>,
+}
+
+pub fn decode(frame: &[u8]) -> Result<StreamFrame, StreamError> {
+    let [version, content, s0, s1, d0, d1, count, fields @ ..] = frame else {
+        return Err(StreamError::Truncated);
+    };
+    if *version != VERSION {
+        return Err(StreamError::UnknownVersion(*version));
+    }
+    let content = match content {
+        0 => StreamContent::Raw,
+        1 => StreamContent::Fused,
+        other => return Err(StreamError::UnknownContent(*other)),
+    };
+    let sample_len = content.fields() * 2;
+    if fields.len() != *count as usize * sample_len {
+        return Err(StreamError::Truncated);
+    }
+
+    let samples = fields.chunks(sample_len).map(|chunk| {
+        let value = |i: usize, scale: f32| i16::from_le_bytes([chunk[2 * i], chunk[2 * i + 1]]) as f32 / scale;
+        let vector = |first: usize, scale: f32| [value(first, scale), value(first + 1, scale), value(first + 2, scale)];
+        let mut sample = StreamSample {
+            accel: vector(0, ACCEL_SCALE),
+            gyro: vector(3, GYRO_SCALE),
+            ..Default::default()
+        };
+        if content == StreamContent::Fused {
+            sample.euler = vector(6, EULER_SCALE);
+            sample.linear_accel = vector(9, LINEAR_ACCEL_SCALE);
+        }
+        sample
+    }).collect();
+
+    Ok(StreamFrame {
+        content,
+        sequence: u16::from_le_bytes([*s0, *s1]),
+        decimation: u16::from_le_bytes([*d0, *d1]),
+        samples,
+    })
+}
+
+// Counts the frames missing from the received sequence numbers
+#[derive(Debug, Default)]
+pub struct LossTracker {
+    next: Option<u16>,
+    pub received: u32,
+    pub lost: u32,
+}
+
+impl LossTracker {
+    // Returns how many frames went missing right before this one
+    pub fn track(&mut self, sequence: u16) -> u16 {
+        let lost = self.next.map_or(0, |next| sequence.wrapping_sub(next));
+        self.next = Some(sequence.wrapping_add(1));
+        self.received += 1;
+        self.lost += lost as u32;
+        lost
+    }
+}
+
+#[cfg(test)]
+fn sample(i: usize) -> StreamSample {
+    let i = i as f32;
+    StreamSample {
+        accel: [0.001 * i, -0.5, 1.0],
+        gyro: [250.0, -0.1 * i, 0.0],
+        euler: [10.0, -45.5, 179.99],
+        linear_accel: [0.01 * i, 0.0, -9.8],
+    }
+}
+
+#[test]
+fn test_frames_round_trip() {
+    let mut encoder = StreamEncoder::new(StreamConfig { content: StreamContent::Fused, decimation: 1 });
+    let frames: Vec<Vec<u8>> = (0..2 * SAMPLES_PER_FRAME).filter_map(|i| encoder.push(&sample(i))).collect();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].len(), HEADER_LEN + SAMPLES_PER_FRAME * 24);
+
+    let decoded = decode(&frames[1]).unwrap();
+    assert_eq!((decoded.content, decoded.sequence, decoded.decimation), (StreamContent::Fused, 1, 1));
+    for (i, sample) in decoded.samples.iter().enumerate() {
+        let expected = self::sample(SAMPLES_PER_FRAME + i);
+        let pairs = [(sample.accel, expected.accel), (sample.gyro, expected.gyro),
+                     (sample.euler, expected.euler), (sample.linear_accel, expected.linear_accel)];
+        for (decoded, expected) in pairs {
+            for axis in 0..3 {
+                assert!((decoded[axis] - expected[axis]).abs() <= 0.01, "{:?} != {:?}", decoded, expected);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_raw_frames_are_decimated() {
+    let mut encoder = StreamEncoder::new(StreamConfig { content: StreamContent::Raw, decimation: 4 });
+    let frames: Vec<Vec<u8>> = (0..4 * SAMPLES_PER_FRAME).filter_map(|i| encoder.push(&sample(i))).collect();
+    assert_eq!(frames.len(), 1);
+    let decoded = decode(&frames[0]).unwrap();
+    assert_eq!(decoded.decimation, 4);
+    assert_eq!(decoded.samples.len(), SAMPLES_PER_FRAME);
+    // Every fourth sample, starting with the first one
+    assert_eq!(decoded.samples[1].accel[0], 0.004);
+    assert_eq!(decoded.samples[1].euler, [0.0; 3]);
+
+    assert_eq!(decode(&frames[0][..frames[0].len() - 1]), Err(StreamError::Truncated));
+    assert_eq!(decode(&[2, 0, 0, 0, 1, 0, 0]), Err(StreamError::UnknownVersion(2)));
+}
+
+#[test]
+fn test_loss_tracking() {
+    let mut tracker = LossTracker::default();
+    assert_eq!(tracker.track(65534), 0);
+    assert_eq!(tracker.track(65535), 0);
+    // Wraps around, with 0 and 1 missing
+    assert_eq!(tracker.track(2), 2);
+    assert_eq!((tracker.received, tracker.lost), (3, 2));
+}
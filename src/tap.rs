@@ -0,0 +1,378 @@
+use core::fmt;
+
+use imu_fusion::FusionVector;
+
+/* Taps on the device, from the accelerometer. Gravity and slow movements are
+ * removed by a high-pass filter, then a tap is a shock starting with a jerk of
+ * at least `jerk_threshold` and reaching `threshold`, over within
+ * `max_duration`, with quiet for `quiet_time` before and after it. Before,
+ * quiet is the filtered acceleration under half the threshold; after, while
+ * the device still rings, under the threshold.
+ *
+ * A second tap starting less than `double_tap_window` after the first makes a
+ * double tap, so single taps are only reported once that window is over.
+ *
+ * Accelerations are in g, times in seconds.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapDirection {
+    XPositive,
+    XNegative,
+    YPositive,
+    YNegative,
+    ZPositive,
+    ZNegative,
+}
+
+impl TapDirection {
+    // Dominant axis of the shock at its peak, with its sign: a tap on the top
+    // of a device lying flat knocks it towards z-
+    fn of(peak: FusionVector) -> Self {
+        let (x, y, z) = (peak.x.abs(), peak.y.abs(), peak.z.abs());
+        if x >= y && x >= z {
+            if peak.x >= 0.0 { TapDirection::XPositive } else { TapDirection::XNegative }
+        } else if y >= z {
+            if peak.y >= 0.0 { TapDirection::YPositive } else { TapDirection::YNegative }
+        } else if peak.z >= 0.0 {
+            TapDirection::ZPositive
+        } else {
+            TapDirection::ZNegative
+        }
+    }
+
+    pub fn as_payload(&self) -> &'static str {
+        match *self {
+            TapDirection::XPositive => "x+",
+            TapDirection::XNegative => "x-",
+            TapDirection::YPositive => "y+",
+            TapDirection::YNegative => "y-",
+            TapDirection::ZPositive => "z+",
+            TapDirection::ZNegative => "z-",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tap {
+    Single(TapDirection),
+    // In the direction of the first tap
+    Double(TapDirection),
+}
+
+impl Tap {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tap::Single(_) => "tap",
+            Tap::Double(_) => "double_tap",
+        }
+    }
+}
+
+impl fmt::Display for Tap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (Tap::Single(direction) | Tap::Double(direction)) = self;
+        write!(f, "{} {}", self.name(), direction.as_payload())
+    }
+}
+
+pub const MIN_SENSITIVITY: u8 = 1;
+pub const MAX_SENSITIVITY: u8 = 10;
+pub const DEFAULT_SENSITIVITY: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TapConfig {
+    // Time constant of the high-pass filter
+    pub high_pass_time: f32,
+    pub threshold: f32,
+    // g/s
+    pub jerk_threshold: f32,
+    pub max_duration: f32,
+    pub quiet_time: f32,
+    pub double_tap_window: f32,
+}
+
+impl Default for TapConfig {
+    fn default() -> Self {
+        Self {
+            high_pass_time: 0.02,
+            threshold: 1.0,
+            jerk_threshold: 100.0,
+            max_duration: 0.06,
+            quiet_time: 0.08,
+            double_tap_window: 0.4,
+        }
+    }
+}
+
+impl TapConfig {
+    // The default thresholds, lowered by a quarter for each step of
+    // sensitivity above the default one, and raised as much below it
+    pub fn with_sensitivity(sensitivity: u8) -> Self {
+        let steps = DEFAULT_SENSITIVITY as i32 - sensitivity.clamp(MIN_SENSITIVITY, MAX_SENSITIVITY) as i32;
+        let scale = 1.25f32.powi(steps);
+        let default = Self::default();
+        Self { threshold: default.threshold * scale, jerk_threshold: default.jerk_threshold * scale, ..default }
+    }
+}
+
+// `FusionVector::magnitude` is the squared one
+fn norm(vector: FusionVector) -> f32 {
+    vector.magnitude().sqrt()
+}
+
+enum State {
+    // Time since the last activity
+    Idle { quiet: f32 },
+    Shock { elapsed: f32, peak: FusionVector },
+    Settling { elapsed: f32, peak: FusionVector },
+}
+
+pub struct TapDetector {
+    config: TapConfig,
+    previous: Option<FusionVector>,
+    filtered: FusionVector,
+    state: State,
+    // First tap of a possible double tap, and the time since
+    pending: Option<(TapDirection, f32)>,
+}
+
+impl TapDetector {
+    pub fn new(config: TapConfig) -> Self {
+        Self { config, previous: None, filtered: FusionVector::zero(), state: State::Idle { quiet: 0.0 }, pending: None }
+    }
+
+    pub fn config(&self) -> TapConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: TapConfig) {
+        self.config = config;
+    }
+
+    pub fn add_measurement(&mut self, delta: f32, accel: FusionVector) -> Option<Tap> {
+        let previous = self.previous.replace(accel)?;
+        let alpha = self.config.high_pass_time / (self.config.high_pass_time + delta);
+        self.filtered = (self.filtered + accel - previous) * alpha;
+        let magnitude = norm(self.filtered);
+        let jerk = norm(accel - previous) / delta;
+        let active = magnitude >= self.config.threshold / 2.0;
+        if let Some((_, age)) = self.pending.as_mut() {
+            *age += delta;
+        }
+
+        match self.state {
+            State::Idle { quiet } => {
+                if magnitude >= self.config.threshold && jerk >= self.config.jerk_threshold
+                    && quiet >= self.config.quiet_time {
+                    self.state = State::Shock { elapsed: 0.0, peak: self.filtered };
+                    return None;
+                }
+                self.state = State::Idle { quiet: if active { 0.0 } else { quiet + delta } };
+                match self.pending {
+                    Some((direction, age)) if age > self.config.double_tap_window => {
+                        self.pending = None;
+                        Some(Tap::Single(direction))
+                    },
+                    _ => None,
+                }
+            },
+            State::Shock { elapsed, peak } => {
+                let elapsed = elapsed + delta;
+                let peak = if magnitude > norm(peak) { self.filtered } else { peak };
+                self.state = if elapsed > self.config.max_duration {
+                    // Too long for a tap, the device is being moved or shaken
+                    State::Idle { quiet: 0.0 }
+                } else if active {
+                    State::Shock { elapsed, peak }
+                } else {
+                    State::Settling { elapsed: 0.0, peak }
+                };
+                None
+            },
+            State::Settling { elapsed, peak } => {
+                if magnitude >= self.config.threshold {
+                    self.state = State::Idle { quiet: 0.0 };
+                    return None;
+                }
+                let elapsed = elapsed + delta;
+                if elapsed < self.config.quiet_time {
+                    self.state = State::Settling { elapsed, peak };
+                    return None;
+                }
+                self.state = State::Idle { quiet: elapsed };
+                match self.pending.take() {
+                    Some((first, _)) => Some(Tap::Double(first)),
+                    None => {
+                        self.pending = Some((TapDirection::of(peak), 0.0));
+                        None
+                    },
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+const TEST_RATE: f32 = 200.0;
+
+// Device lying flat for `seconds`, with a little noise
+#[cfg(test)]
+fn at_rest(seconds: f32) -> Vec<FusionVector> {
+    let mut state = 2024u32;
+    (0..(seconds * TEST_RATE) as usize).map(|_| {
+        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+        let noise = ((state >> 16) as f32 / 65536.0 - 0.5) * 0.02;
+        FusionVector::new(noise, -noise, 1.0 + noise)
+    }).collect()
+}
+
+// Adds a knock at `at` seconds and its rebound, `peak` being the first sample
+#[cfg(test)]
+fn knock(samples: &mut [FusionVector], at: f32, peak: FusionVector) {
+    let start = (at * TEST_RATE) as usize;
+    for (sample, share) in samples[start..].iter_mut().zip([1.0, -0.5, 0.25, -0.1]) {
+        *sample += peak * share;
+    }
+}
+
+#[cfg(test)]
+fn taps(detector: &mut TapDetector, samples: &[FusionVector]) -> Vec<(f32, Tap)> {
+    samples.iter().enumerate()
+        .filter_map(|(index, accel)| {
+            detector.add_measurement(1.0 / TEST_RATE, *accel).map(|tap| (index as f32 / TEST_RATE, tap))
+        })
+        .collect()
+}
+
+/* Raw accelerometer counts at 200 Hz, ±8g range, with the board lying flat: a
+ * tap on the top at 0.1 s, then a double tap at 0.8 s. Unlike `knock`, each
+ * shock rings over several samples on every axis and the board bounces on
+ * the table for a while after it.
+ *
+ * Hand-made in the format of the sensor, in place of a recording until one
+ * is taken with the capture tool.
+ */
+#[cfg(test)]
+const TAP_TRACE: [[i16; 3]; 260] = [
+    [59, -106, 4086], [59, -119, 4086], [71, -107, 4097], [64, -107, 4089], [46, -103, 4092],
+    [66, -126, 4072], [53, -115, 4091], [61, -106, 4082], [64, -107, 4082], [77, -106, 4099],
+    [56, -117, 4085], [60, -105, 4090], [57, -119, 4083], [72, -118, 4090], [65, -124, 4088],
+    [73, -129, 4085], [60, -118, 4092], [61, -124, 4095], [67, -102, 4101], [65, -110, 4076],
+    [681, -1550, -6566], [-173, 677, 9659], [338, -371, 1813], [77, 168, 5702], [115, -188, 3548],
+    [12, -101, 4340], [22, -190, 3732], [0, -146, 3971], [31, -148, 3910], [69, -102, 4102],
+    [69, -91, 4178], [81, -76, 4217], [79, -66, 4192], [72, -95, 4135], [56, -107, 4061],
+    [39, -120, 4027], [34, -122, 4035], [45, -136, 4044], [58, -115, 4094], [60, -92, 4101],
+    [66, -93, 4136], [79, -98, 4122], [67, -101, 4101], [62, -107, 4083], [63, -111, 4087],
+    [58, -121, 4063], [57, -107, 4070], [64, -95, 4062], [53, -106, 4099], [67, -111, 4106],
+    [67, -112, 4121], [66, -114, 4092], [59, -111, 4062], [55, -103, 4071], [59, -104, 4089],
+    [73, -127, 4080], [58, -105, 4096], [38, -100, 4077], [69, -123, 4093], [73, -111, 4093],
+    [69, -109, 4087], [75, -101, 4085], [86, -121, 4096], [59, -109, 4094], [63, -105, 4074],
+    [48, -105, 4079], [52, -124, 4099], [68, -97, 4079], [61, -121, 4095], [76, -119, 4102],
+    [70, -112, 4070], [74, -111, 4082], [65, -107, 4101], [52, -100, 4101], [75, -112, 4081],
+    [71, -110, 4089], [74, -113, 4067], [58, -127, 4095], [64, -116, 4088], [69, -110, 4100],
+    [61, -101, 4101], [76, -117, 4096], [45, -120, 4070], [71, -122, 4088], [60, -111, 4082],
+    [64, -94, 4088], [66, -102, 4086], [50, -116, 4097], [47, -116, 4097], [69, -111, 4095],
+    [63, -121, 4074], [56, -102, 4083], [53, -118, 4074], [60, -121, 4091], [40, -108, 4082],
+    [44, -104, 4085], [41, -118, 4090], [57, -104, 4095], [67, -108, 4100], [67, -107, 4069],
+    [70, -99, 4085], [57, -93, 4072], [66, -89, 4079], [68, -94, 4087], [66, -102, 4080],
+    [61, -108, 4095], [61, -112, 4079], [58, -103, 4089], [54, -118, 4112], [72, -105, 4064],
+    [67, -106, 4103], [65, -111, 4093], [44, -101, 4091], [55, -99, 4104], [49, -117, 4090],
+    [63, -114, 4079], [81, -101, 4077], [49, -95, 4097], [78, -103, 4080], [64, -130, 4081],
+    [61, -106, 4081], [60, -106, 4091], [67, -109, 4085], [69, -110, 4080], [56, -111, 4087],
+    [63, -111, 4089], [60, -122, 4092], [71, -107, 4086], [65, -119, 4071], [62, -119, 4094],
+    [52, -134, 4078], [76, -114, 4075], [55, -106, 4092], [63, -97, 4094], [61, -105, 4103],
+    [70, -101, 4078], [60, -104, 4085], [71, -105, 4096], [60, -88, 4099], [60, -110, 4111],
+    [58, -103, 4097], [61, -121, 4089], [65, -100, 4095], [62, -103, 4093], [63, -110, 4086],
+    [68, -120, 4082], [61, -124, 4084], [43, -117, 4093], [67, -111, 4086], [49, -94, 4092],
+    [71, -119, 4086], [45, -104, 4096], [44, -111, 4093], [46, -127, 4078], [56, -123, 4088],
+    [64, -105, 4094], [75, -100, 4076], [57, -120, 4078], [61, -111, 4092], [47, -122, 4088],
+    [1084, -933, -4924], [-372, 386, 8852], [427, -207, 2230], [0, 70, 5495], [144, -126, 3646],
+    [-7, -112, 4279], [22, -176, 3755], [-15, -167, 3952], [35, -148, 3924], [44, -115, 4088],
+    [76, -87, 4166], [99, -70, 4204], [112, -84, 4197], [75, -88, 4108], [48, -115, 4072],
+    [64, -126, 4038], [48, -122, 4026], [47, -119, 4035], [70, -122, 4084], [88, -105, 4112],
+    [83, -99, 4119], [74, -95, 4127], [59, -90, 4118], [60, -110, 4079], [69, -123, 4075],
+    [51, -123, 4073], [69, -115, 4067], [68, -112, 4087], [77, -98, 4090], [86, -107, 4107],
+    [59, -108, 4083], [79, -97, 4082], [48, -126, 4097], [56, -113, 4079], [58, -122, 4081],
+    [47, -113, 4086], [65, -113, 4078], [63, -114, 4104], [70, -110, 4087], [56, -118, 4088],
+    [64, -106, 4093], [80, -117, 4088], [87, -127, 4083], [63, -109, 4091], [469, -1336, -3694],
+    [-53, 567, 8229], [276, -311, 2527], [105, 124, 5349], [126, -150, 3725], [24, -110, 4244],
+    [13, -193, 3773], [4, -170, 3949], [43, -157, 3936], [56, -97, 4095], [94, -92, 4170],
+    [97, -90, 4221], [99, -97, 4194], [73, -94, 4132], [42, -119, 4080], [38, -138, 4015],
+    [31, -127, 4037], [52, -121, 4065], [55, -119, 4086], [74, -112, 4102], [76, -97, 4114],
+    [70, -106, 4125], [65, -107, 4100], [69, -100, 4079], [63, -123, 4070], [62, -103, 4063],
+    [56, -113, 4060], [61, -118, 4088], [53, -126, 4095], [67, -112, 4108], [62, -113, 4103],
+    [49, -115, 4093], [69, -112, 4089], [54, -110, 4097], [53, -91, 4075], [60, -111, 4092],
+    [50, -130, 4092], [69, -104, 4114], [64, -107, 4100], [66, -95, 4080], [58, -142, 4095],
+    [58, -102, 4107], [61, -113, 4083], [54, -116, 4094], [62, -110, 4086], [70, -106, 4087],
+    [67, -112, 4077], [75, -106, 4079], [71, -107, 4074], [76, -108, 4096], [63, -112, 4074],
+    [70, -110, 4085], [65, -110, 4094], [58, -111, 4069], [58, -105, 4100], [58, -112, 4102],
+];
+
+#[test]
+fn test_taps() {
+    let mut detector = TapDetector::new(TapConfig::default());
+    assert!(taps(&mut detector, &at_rest(1.0)).is_empty());
+
+    // On the top, reported once the double tap window is over
+    let mut samples = at_rest(1.5);
+    knock(&mut samples, 0.5, FusionVector::new(0.2, 0.1, -2.0));
+    let detected = taps(&mut detector, &samples);
+    assert_eq!(detected.len(), 1, "{:?}", detected);
+    assert_eq!(detected[0].1, Tap::Single(TapDirection::ZNegative));
+    assert!(detected[0].0 > 0.9 && detected[0].0 < 1.1, "{:?}", detected);
+
+    // Twice on the side
+    let mut samples = at_rest(1.5);
+    knock(&mut samples, 0.3, FusionVector::new(1.8, 0.0, 0.0));
+    knock(&mut samples, 0.55, FusionVector::new(1.5, -0.3, 0.0));
+    assert_eq!(taps(&mut detector, &samples).iter().map(|(_, tap)| *tap).collect::<Vec<_>>(),
+               [Tap::Double(TapDirection::XPositive)]);
+    // Too far apart for a double tap
+    let mut samples = at_rest(2.0);
+    knock(&mut samples, 0.2, FusionVector::new(0.0, -2.0, 0.0));
+    knock(&mut samples, 1.0, FusionVector::new(0.0, 2.0, 0.0));
+    assert_eq!(taps(&mut detector, &samples).iter().map(|(_, tap)| *tap).collect::<Vec<_>>(),
+               [Tap::Single(TapDirection::YNegative), Tap::Single(TapDirection::YPositive)]);
+
+    // Shaken hard at 5 Hz, with a knock in the middle
+    let mut samples = at_rest(1.5);
+    for (index, sample) in samples.iter_mut().enumerate() {
+        sample.y += 2.0 * (2.0 * core::f32::consts::PI * 5.0 * index as f32 / TEST_RATE).sin();
+    }
+    knock(&mut samples, 0.6, FusionVector::new(0.0, 0.0, -2.0));
+    let detected = taps(&mut detector, &samples);
+    assert!(detected.is_empty(), "{:?}", detected);
+    // Bumped for longer than a tap
+    let mut samples = at_rest(1.5);
+    for (index, sample) in samples[100..120].iter_mut().enumerate() {
+        sample.x += if index % 2 == 0 { 2.0 } else { -2.0 };
+    }
+    assert!(taps(&mut detector, &samples).is_empty());
+
+    // A light tap only counts when more sensitive
+    let mut samples = at_rest(1.5);
+    knock(&mut samples, 0.5, FusionVector::new(0.0, 0.0, -0.8));
+    assert!(taps(&mut detector, &samples).is_empty());
+    detector.set_config(TapConfig::with_sensitivity(8));
+    assert_eq!(taps(&mut detector, &samples).iter().map(|(_, tap)| *tap).collect::<Vec<_>>(),
+               [Tap::Single(TapDirection::ZNegative)]);
+    assert!(TapConfig::with_sensitivity(1).threshold > TapConfig::default().threshold);
+    assert_eq!(TapConfig::with_sensitivity(DEFAULT_SENSITIVITY), TapConfig::default());
+}
+
+#[test]
+fn test_tap_trace() {
+    const LSB_PER_G: f32 = 4096.0;
+
+    let samples: Vec<FusionVector> = TAP_TRACE.iter()
+        .map(|[x, y, z]| FusionVector::new(*x as f32, *y as f32, *z as f32) * (1.0 / LSB_PER_G))
+        .collect();
+    let mut detector = TapDetector::new(TapConfig::default());
+    let detected = taps(&mut detector, &samples);
+    assert_eq!(detected.iter().map(|(_, tap)| *tap).collect::<Vec<_>>(),
+               [Tap::Single(TapDirection::ZNegative), Tap::Double(TapDirection::ZNegative)], "{:?}", detected);
+    // The single tap once the double tap window is over, the double one after
+    // the second shock settled
+    assert!(detected[0].0 > 0.5 && detected[0].0 < 0.7, "{:?}", detected);
+    assert!(detected[1].0 > 1.02 && detected[1].0 < 1.2, "{:?}", detected);
+}
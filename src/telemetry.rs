@@ -0,0 +1,323 @@
+use std::io::Write;
+
+use anyhow::Result;
+
+use crate::analysis::MovementDirection;
+use crate::event_sink::EventSink;
+use crate::outbox::Outbound;
+
+/* Telemetry frames for the serial console. Each record is followed by its
+ * CRC-16/CCITT-FALSE (little endian), COBS encoded and enclosed in zero bytes,
+ * so that a reader can join at any point and skip the log output mixed in
+ * with the frames.
+ *
+ * Records start with their type, all fields little endian:
+ *   0 sample:   u32 sequence, f32 delta, then accel, gyro, euler and linear
+ *               acceleration as 3 f32 each
+ *   1 analysis: u32 sequence, f32 horizontal, f32 vertical, f32 activity,
+ *               u8 direction (`MovementDirection::as_payload`, 0xFF for none)
+ *   2 message:  u8 topic length, topic, payload up to the end
+ */
+const SAMPLE: u8 = 0;
+const ANALYSIS: u8 = 1;
+const MESSAGE: u8 = 2;
+const NO_DIRECTION: u8 = 0xFF;
+
+// Longest frame a decoder keeps, anything longer is garbage
+pub const MAX_FRAME_LEN: usize = 512;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    Sample {
+        sequence: u32,
+        delta: f32,
+        accel: [f32; 3],
+        gyro: [f32; 3],
+        euler: [f32; 3],
+        linear_accel: [f32; 3],
+    },
+    Analysis {
+        sequence: u32,
+        horizontal: f32,
+        vertical: f32,
+        // See `activity::ActivityMeter`
+        activity: f32,
+        direction: Option<MovementDirection>,
+    },
+    // Any other outbound message, e.g. movement events or faults
+    Message { topic: String, payload: Vec<u8> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TelemetryError {
+    Cobs,
+    Crc,
+    UnknownRecord(u8),
+    Truncated,
+    TooLong,
+}
+
+fn put_f32s(buffer: &mut Vec<u8>, values: &[f32]) {
+    for value in values {
+        buffer.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+impl Record {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        match self {
+            Record::Sample { sequence, delta, accel, gyro, euler, linear_accel } => {
+                buffer.push(SAMPLE);
+                buffer.extend_from_slice(&sequence.to_le_bytes());
+                put_f32s(&mut buffer, &[*delta]);
+                for vector in [accel, gyro, euler, linear_accel] {
+                    put_f32s(&mut buffer, vector);
+                }
+            },
+            Record::Analysis { sequence, horizontal, vertical, activity, direction } => {
+                buffer.push(ANALYSIS);
+                buffer.extend_from_slice(&sequence.to_le_bytes());
+                put_f32s(&mut buffer, &[*horizontal, *vertical, *activity]);
+                buffer.push(direction.map_or(NO_DIRECTION, |direction| direction.as_payload()));
+            },
+            Record::Message { topic, payload } => {
+                buffer.push(MESSAGE);
+                buffer.push(topic.len() as u8);
+                buffer.extend_from_slice(topic.as_bytes());
+                buffer.extend_from_slice(payload);
+            },
+        }
+        buffer
+    }
+
+    pub fn decode(record: &[u8]) -> Result<Self, TelemetryError> {
+        let (kind, body) = record.split_first().ok_or(TelemetryError::Truncated)?;
+        let u32_at = |at: usize| -> Result<u32, TelemetryError> {
+            let bytes = body.get(at..at + 4).ok_or(TelemetryError::Truncated)?;
+            Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        };
+        let f32_at = |at: usize| u32_at(at).map(f32::from_bits);
+        let vector_at = |at: usize| -> Result<[f32; 3], TelemetryError> {
+            Ok([f32_at(at)?, f32_at(at + 4)?, f32_at(at + 8)?])
+        };
+        match *kind {
+            SAMPLE if body.len() == 56 => Ok(Record::Sample {
+                sequence: u32_at(0)?,
+                delta: f32_at(4)?,
+                accel: vector_at(8)?,
+                gyro: vector_at(20)?,
+                euler: vector_at(32)?,
+                linear_accel: vector_at(44)?,
+            }),
+            ANALYSIS if body.len() == 17 => Ok(Record::Analysis {
+                sequence: u32_at(0)?,
+                horizontal: f32_at(4)?,
+                vertical: f32_at(8)?,
+                activity: f32_at(12)?,
+                direction: MovementDirection::from_payload(body[16]),
+            }),
+            MESSAGE => {
+                let (len, rest) = body.split_first().ok_or(TelemetryError::Truncated)?;
+                if rest.len() < *len as usize {
+                    return Err(TelemetryError::Truncated);
+                }
+                let (topic, payload) = rest.split_at(*len as usize);
+                Ok(Record::Message { topic: String::from_utf8_lossy(topic).into_owned(), payload: payload.to_vec() })
+            },
+            SAMPLE | ANALYSIS => Err(TelemetryError::Truncated),
+            other => Err(TelemetryError::UnknownRecord(other)),
+        }
+    }
+}
+
+// CRC-16/CCITT-FALSE
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+pub fn cobs_encode(data: &[u8], out: &mut Vec<u8>) {
+    let mut code_at = out.len();
+    out.push(0);
+    let mut code = 1u8;
+    for byte in data {
+        if *byte != 0 {
+            out.push(*byte);
+            code += 1;
+        }
+        if *byte == 0 || code == 0xFF {
+            out[code_at] = code;
+            code_at = out.len();
+            out.push(0);
+            code = 1;
+        }
+    }
+    out[code_at] = code;
+}
+
+pub fn cobs_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut rest = data;
+    while let Some((code, tail)) = rest.split_first() {
+        let len = (*code as usize).checked_sub(1)?;
+        if *code == 0 || tail.len() < len {
+            return None;
+        }
+        out.extend_from_slice(&tail[..len]);
+        rest = &tail[len..];
+        if *code != 0xFF && !rest.is_empty() {
+            out.push(0);
+        }
+    }
+    Some(out)
+}
+
+// Record, CRC, COBS and the zero delimiters. The leading one ends whatever
+// was written before, e.g. a log line.
+pub fn frame(record: &[u8]) -> Vec<u8> {
+    let mut checked = record.to_vec();
+    checked.extend_from_slice(&crc16(record).to_le_bytes());
+    let mut frame = Vec::with_capacity(checked.len() + checked.len() / 254 + 3);
+    frame.push(0);
+    cobs_encode(&checked, &mut frame);
+    frame.push(0);
+    frame
+}
+
+// Splits a byte stream into records, skipping anything that is not a frame
+#[derive(Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    overflowed: bool,
+}
+
+impl FrameDecoder {
+    pub fn push(&mut self, byte: u8) -> Option<Result<Record, TelemetryError>> {
+        if byte != 0 {
+            if self.buffer.len() < MAX_FRAME_LEN {
+                self.buffer.push(byte);
+            } else {
+                self.overflowed = true;
+            }
+            return None;
+        }
+        let overflowed = std::mem::take(&mut self.overflowed);
+        let encoded = std::mem::take(&mut self.buffer);
+        if overflowed {
+            return Some(Err(TelemetryError::TooLong));
+        }
+        if encoded.is_empty() {
+            return None;
+        }
+        Some(decode_frame(&encoded))
+    }
+}
+
+fn decode_frame(encoded: &[u8]) -> Result<Record, TelemetryError> {
+    let checked = cobs_decode(encoded).ok_or(TelemetryError::Cobs)?;
+    if checked.len() < 3 {
+        return Err(TelemetryError::Truncated);
+    }
+    let (record, crc) = checked.split_at(checked.len() - 2);
+    if crc16(record).to_le_bytes() != crc {
+        return Err(TelemetryError::Crc);
+    }
+    Record::decode(record)
+}
+
+// Writes every outbound message as a frame, telemetry records as they are
+pub struct TelemetrySink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> TelemetrySink<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> EventSink for TelemetrySink<W> {
+    fn send(&mut self, message: &Outbound) -> Result<()> {
+        let record = match message {
+            Outbound::Telemetry(record) => frame(record),
+            other => frame(&Record::Message {
+                topic: String::from(other.topic()),
+                payload: other.payload().to_vec(),
+            }.encode()),
+        };
+        self.writer.write_all(&record)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_cobs_round_trip() {
+    let cases: [&[u8]; 5] = [&[], &[0], &[0x11, 0, 0, 0x22], &[1; 254], &[2; 600]];
+    for case in cases {
+        let mut encoded = Vec::new();
+        cobs_encode(case, &mut encoded);
+        assert!(!encoded.contains(&0));
+        assert_eq!(cobs_decode(&encoded).unwrap(), case);
+    }
+    let mut encoded = Vec::new();
+    cobs_encode(&[0x11, 0, 0x22], &mut encoded);
+    assert_eq!(encoded, [2, 0x11, 2, 0x22]);
+    assert_eq!(crc16(b"123456789"), 0x29B1);
+}
+
+#[test]
+fn test_decoder_skips_noise() {
+    let sample = Record::Sample {
+        sequence: 7,
+        delta: 0.005,
+        accel: [0.0, 0.0, 1.0],
+        gyro: [0.5, -0.5, 0.0],
+        euler: [1.0, 2.0, 180.0],
+        linear_accel: [0.1, 0.0, -0.1],
+    };
+    let analysis = Record::Analysis {
+        sequence: 7,
+        horizontal: 0.2,
+        vertical: 2.5,
+        activity: 1.25,
+        direction: Some(MovementDirection::Vertical),
+    };
+    let mut sink = TelemetrySink::new(Vec::new());
+    sink.send(&Outbound::Telemetry(sample.encode())).unwrap();
+    sink.send(&Outbound::Telemetry(analysis.encode())).unwrap();
+    sink.send(&Outbound::Fault(String::from("recovered"))).unwrap();
+    let frames = sink.into_inner();
+
+    // Joins half-way through a frame, with a log line in between
+    let mut stream = frames[5..].to_vec();
+    stream.extend_from_slice(b"I (1234) test_hardware: log line\r\n");
+    stream.extend_from_slice(&frames);
+    let mut corrupted = frames.clone();
+    corrupted[9] ^= 0x01;
+    stream.extend_from_slice(&corrupted);
+
+    let mut decoder = FrameDecoder::default();
+    let (records, errors): (Vec<_>, Vec<_>) = stream.iter().filter_map(|byte| decoder.push(*byte)).partition(Result::is_ok);
+    let records: Vec<Record> = records.into_iter().map(Result::unwrap).collect();
+    let message = Record::Message { topic: String::from("fault"), payload: b"recovered".to_vec() };
+    assert_eq!(records, vec![
+        analysis.clone(), message.clone(),
+        sample, analysis.clone(), message.clone(),
+        analysis, message,
+    ]);
+    // The partial frame, the log line and the corrupted sample
+    assert_eq!(errors.len(), 3);
+    assert_eq!(errors[2], Err(TelemetryError::Crc));
+}